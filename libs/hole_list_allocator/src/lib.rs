@@ -6,17 +6,20 @@
 #![deny(warnings)]
 
 extern crate alloc;
-extern crate spin;
+#[macro_use]
+extern crate irq_lock;
 extern crate linked_list_allocator;
 
 use alloc::heap::{Alloc, AllocErr, Layout};
-use spin::Mutex;
+use irq_lock::IrqMutex;
 use linked_list_allocator::Heap;
 
 pub const HEAP_START: usize = 0o_000_001_000_000_0000;
-pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+pub const HEAP_SIZE: usize = 1024 * 1024; // 1 MiB
 
-static HEAP: Mutex<Option<Heap>> = Mutex::new(None);
+// Interrupt handlers allocate too, so the heap can't be locked with interrupts enabled
+static HEAP: IrqMutex<Option<Heap>> =
+    IrqMutex::named(lock_class!("hole_list_allocator::HEAP"), None);
 
 //Set up the heap
 pub unsafe fn init(offset: usize, size: usize) {
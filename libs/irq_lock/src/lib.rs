@@ -0,0 +1,288 @@
+#![feature(asm)]
+#![feature(const_fn)]
+#![no_std]
+#![deny(warnings)]
+
+extern crate spin;
+
+use core::mem;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(feature = "lockdep")]
+pub mod lockdep;
+
+/// Address of the kernel's `fn() -> bool` that tells whether the CPU is in interrupt context.
+/// 0 until the kernel registers one.
+static IN_INTERRUPT_FN: AtomicUsize = AtomicUsize::new(0);
+
+/// Tell the lock types how to find out whether they're being taken in interrupt context
+pub fn set_in_interrupt_fn(f: fn() -> bool) {
+    IN_INTERRUPT_FN.store(f as usize, Ordering::SeqCst);
+}
+
+/// Address of the kernel's `fn()` that `IrqMutex::lock` calls while it spins. 0 until the kernel
+/// registers one.
+static SPIN_FN: AtomicUsize = AtomicUsize::new(0);
+
+/// Tell `IrqMutex::lock` what to do while it waits for the lock. As it spins with interrupts
+/// disabled, this is the only way the CPU can answer requests from other CPUs, which the holder of
+/// the lock may be waiting for.
+pub fn set_spin_fn(f: fn()) {
+    SPIN_FN.store(f as usize, Ordering::SeqCst);
+}
+
+fn spin() {
+    let f = SPIN_FN.load(Ordering::SeqCst);
+    if f != 0 {
+        let f: fn() = unsafe { mem::transmute(f) };
+        f();
+    }
+    unsafe { asm!("pause" : : : "memory" : "intel", "volatile"); }
+}
+
+fn in_interrupt() -> bool {
+    let f = IN_INTERRUPT_FN.load(Ordering::SeqCst);
+    if f != 0 {
+        let f: fn() -> bool = unsafe { mem::transmute(f) };
+        f()
+    } else {
+        false
+    }
+}
+
+/// Disable interrupts, returning whether they were enabled before
+fn save_and_disable_interrupts() -> bool {
+    let rflags: usize;
+    unsafe {
+        asm!("pushfq
+              pop $0
+              cli"
+             : "=r"(rflags) : : "memory" : "intel", "volatile");
+    }
+    rflags & 1 << 9 != 0
+}
+
+fn restore_interrupts(enabled: bool) {
+    if enabled {
+        unsafe { asm!("sti" : : : "memory" : "intel", "volatile"); }
+    }
+}
+
+/// A class of locks for lockdep, declared by `lock_class!` where the locks are created. Every
+/// lock created there, like each instance of a lock embedded in some structure, shares the class.
+/// Classes are told apart by the address of their static, not by name.
+pub struct LockClass {
+    #[cfg_attr(not(feature = "lockdep"), allow(dead_code))]
+    name: &'static str,
+}
+
+impl LockClass {
+    pub const fn new(name: &'static str) -> LockClass {
+        LockClass { name: name }
+    }
+}
+
+/// A `&'static LockClass` called `$name` for `IrqMutex::named` and `SpinMutex::named`, of its own
+/// even if another one has the same name
+#[macro_export]
+macro_rules! lock_class {
+    ($name:expr) => {{
+        static CLASS: $crate::LockClass = $crate::LockClass::new($name);
+        &CLASS
+    }};
+}
+
+/// Identifies the class of a lock for lockdep. Anonymous locks are their own class.
+#[cfg(feature = "lockdep")]
+fn lock_class(address: usize, class: Option<&'static LockClass>) -> (usize, &'static str) {
+    match class {
+        Some(class) => (class as *const LockClass as usize, class.name),
+        None => (address, "<anonymous>"),
+    }
+}
+
+#[cfg(feature = "lockdep")]
+fn lockdep_acquire(address: usize, class: Option<&'static LockClass>, check: bool) {
+    let (key, name) = lock_class(address, class);
+    lockdep::acquire(key, name, check);
+}
+
+#[cfg(feature = "lockdep")]
+fn lockdep_release(address: usize, class: Option<&'static LockClass>) {
+    lockdep::release(lock_class(address, class).0);
+}
+
+#[cfg(not(feature = "lockdep"))]
+fn lockdep_acquire(_address: usize, _class: Option<&'static LockClass>, _check: bool) {}
+
+#[cfg(not(feature = "lockdep"))]
+fn lockdep_release(_address: usize, _class: Option<&'static LockClass>) {}
+
+/// A spinlock that keeps interrupts disabled while it's held, so it can be shared between
+/// interrupt handlers and the code they interrupt.
+pub struct IrqMutex<T> {
+    inner: spin::Mutex<T>,
+    class: Option<&'static LockClass>,
+}
+
+pub struct IrqMutexGuard<'a, T: 'a> {
+    guard: Option<spin::MutexGuard<'a, T>>,
+    interrupts_enabled: bool,
+    address: usize,
+    class: Option<&'static LockClass>,
+}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(value: T) -> IrqMutex<T> {
+        IrqMutex {
+            inner: spin::Mutex::new(value),
+            class: None,
+        }
+    }
+
+    /// A mutex that lockdep tracks in `class`, from `lock_class!`
+    pub const fn named(class: &'static LockClass, value: T) -> IrqMutex<T> {
+        IrqMutex {
+            inner: spin::Mutex::new(value),
+            class: Some(class),
+        }
+    }
+
+    fn guard(&self, guard: spin::MutexGuard<T>, interrupts_enabled: bool) -> IrqMutexGuard<T> {
+        IrqMutexGuard {
+            guard: Some(guard),
+            interrupts_enabled: interrupts_enabled,
+            address: self as *const IrqMutex<T> as usize,
+            class: self.class,
+        }
+    }
+
+    pub fn lock(&self) -> IrqMutexGuard<T> {
+        let interrupts_enabled = save_and_disable_interrupts();
+        lockdep_acquire(self as *const IrqMutex<T> as usize, self.class, true);
+        loop {
+            if let Some(guard) = self.inner.try_lock() {
+                return self.guard(guard, interrupts_enabled);
+            }
+            spin();
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<T>> {
+        let interrupts_enabled = save_and_disable_interrupts();
+        match self.inner.try_lock() {
+            Some(guard) => {
+                lockdep_acquire(self as *const IrqMutex<T> as usize, self.class, false);
+                Some(self.guard(guard, interrupts_enabled))
+            }
+            None => {
+                restore_interrupts(interrupts_enabled);
+                None
+            }
+        }
+    }
+
+    /// Forcibly unlock the mutex, for use when the holder can never release it (e.g. printing
+    /// a panic message).
+    pub unsafe fn force_unlock(&self) {
+        self.inner.force_unlock();
+    }
+}
+
+impl<'a, T> Deref for IrqMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<'a, T> DerefMut for IrqMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<'a, T> Drop for IrqMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        // release the lock before an interrupt can come in and spin on it
+        self.guard = None;
+        lockdep_release(self.address, self.class);
+        restore_interrupts(self.interrupts_enabled);
+    }
+}
+
+/// A plain spinlock, for data that interrupt handlers never touch. Taking it in interrupt
+/// context is a bug, as the handler could spin forever on a lock held by the code it interrupted;
+/// debug builds check for this.
+pub struct SpinMutex<T> {
+    inner: spin::Mutex<T>,
+    class: Option<&'static LockClass>,
+}
+
+pub struct SpinMutexGuard<'a, T: 'a> {
+    guard: Option<spin::MutexGuard<'a, T>>,
+    address: usize,
+    class: Option<&'static LockClass>,
+}
+
+impl<T> SpinMutex<T> {
+    pub const fn new(value: T) -> SpinMutex<T> {
+        SpinMutex {
+            inner: spin::Mutex::new(value),
+            class: None,
+        }
+    }
+
+    /// A mutex that lockdep tracks in `class`, from `lock_class!`
+    pub const fn named(class: &'static LockClass, value: T) -> SpinMutex<T> {
+        SpinMutex {
+            inner: spin::Mutex::new(value),
+            class: Some(class),
+        }
+    }
+
+    fn guard(&self, guard: spin::MutexGuard<T>) -> SpinMutexGuard<T> {
+        SpinMutexGuard {
+            guard: Some(guard),
+            address: self as *const SpinMutex<T> as usize,
+            class: self.class,
+        }
+    }
+
+    pub fn lock(&self) -> SpinMutexGuard<T> {
+        debug_assert!(!in_interrupt(), "SpinMutex taken in interrupt context, use an IrqMutex");
+        lockdep_acquire(self as *const SpinMutex<T> as usize, self.class, true);
+        self.guard(self.inner.lock())
+    }
+
+    pub fn try_lock(&self) -> Option<SpinMutexGuard<T>> {
+        debug_assert!(!in_interrupt(), "SpinMutex taken in interrupt context, use an IrqMutex");
+        self.inner.try_lock().map(|guard| {
+            lockdep_acquire(self as *const SpinMutex<T> as usize, self.class, false);
+            self.guard(guard)
+        })
+    }
+}
+
+impl<'a, T> Deref for SpinMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<'a, T> DerefMut for SpinMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<'a, T> Drop for SpinMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.guard = None;
+        lockdep_release(self.address, self.class);
+    }
+}
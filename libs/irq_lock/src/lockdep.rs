@@ -0,0 +1,274 @@
+// Runtime lock dependency validator. Every lock belongs to a class; whenever a lock is taken
+// while others are held, the edge "held class -> new class" is recorded. A lock order that would
+// close a cycle in that graph can deadlock, even if it never has so far, and is reported along
+// with the backtraces of every edge involved.
+
+use core::fmt;
+use core::mem;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use spin;
+
+use super::{restore_interrupts, save_and_disable_interrupts};
+
+const MAX_CLASSES: usize = 32;
+const MAX_CPUS: usize = 16;
+const MAX_HELD: usize = 16;
+const BACKTRACE_DEPTH: usize = 8;
+
+type Backtrace = [usize; BACKTRACE_DEPTH];
+
+struct Class {
+    key: usize,
+    name: &'static str,
+}
+
+struct State {
+    classes: [Option<Class>; MAX_CLASSES],
+    /// Bit `b` of `deps[a]` is set if class `b` has been taken while holding class `a`
+    deps: [u64; MAX_CLASSES],
+    /// Where each dependency was first seen
+    traces: [[Backtrace; MAX_CLASSES]; MAX_CLASSES],
+    /// Classes held by each CPU, innermost last
+    held: [[usize; MAX_HELD]; MAX_CPUS],
+    held_len: [usize; MAX_CPUS],
+}
+
+static STATE: spin::Mutex<State> = spin::Mutex::new(State {
+    classes: [None, None, None, None, None, None, None, None,
+              None, None, None, None, None, None, None, None,
+              None, None, None, None, None, None, None, None,
+              None, None, None, None, None, None, None, None],
+    deps: [0; MAX_CLASSES],
+    traces: [[[0; BACKTRACE_DEPTH]; MAX_CLASSES]; MAX_CLASSES],
+    held: [[0; MAX_HELD]; MAX_CPUS],
+    held_len: [0; MAX_CPUS],
+});
+
+/// Cleared after the first report, as the recorded state can't be trusted after that
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+static CPU_ID_FN: AtomicUsize = AtomicUsize::new(0);
+static REPORT_FN: AtomicUsize = AtomicUsize::new(0);
+
+/// Tell lockdep how to find the current CPU, so held locks are tracked per CPU
+pub fn set_cpu_id_fn(f: fn() -> usize) {
+    CPU_ID_FN.store(f as usize, Ordering::SeqCst);
+}
+
+/// Set the function lockdep reports problems through. It must not take any locks.
+pub fn set_report_fn(f: fn(fmt::Arguments)) {
+    REPORT_FN.store(f as usize, Ordering::SeqCst);
+}
+
+fn cpu_id() -> usize {
+    let f = CPU_ID_FN.load(Ordering::SeqCst);
+    if f != 0 {
+        let f: fn() -> usize = unsafe { mem::transmute(f) };
+        f() % MAX_CPUS
+    } else {
+        0
+    }
+}
+
+fn report(args: fmt::Arguments) {
+    let f = REPORT_FN.load(Ordering::SeqCst);
+    if f != 0 {
+        let f: fn(fmt::Arguments) = unsafe { mem::transmute(f) };
+        f(args);
+    }
+}
+
+/// Walk the frame pointer chain, at most `BACKTRACE_DEPTH` frames up. Needs the kernel to be
+/// built with frame pointers. Stops at anything that doesn't look like the next frame up the
+/// stack, as the outermost frame's saved rbp may be garbage.
+#[inline(always)]
+fn backtrace() -> Backtrace {
+    let mut trace = [0; BACKTRACE_DEPTH];
+    let mut rbp: usize;
+    unsafe { asm!("mov $0, rbp" : "=r"(rbp) : : : "intel", "volatile"); }
+    for entry in trace.iter_mut() {
+        if rbp == 0 || rbp % mem::size_of::<usize>() != 0 || !is_canonical(rbp) {
+            break;
+        }
+        let next = unsafe {
+            *entry = *((rbp + mem::size_of::<usize>()) as *const usize);
+            *(rbp as *const usize)
+        };
+        // callers' frames are further up the stack
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+    trace
+}
+
+/// Whether bits 63 to 47 of `address` are all equal, as the CPU wants them
+fn is_canonical(address: usize) -> bool {
+    let upper = address >> 47;
+    upper == 0 || upper == (1 << 17) - 1
+}
+
+fn report_backtrace(trace: &Backtrace) {
+    for (i, &address) in trace.iter().enumerate().take_while(|&(_, &a)| a != 0) {
+        report(format_args!("        #{} {:#x}", i, address));
+    }
+}
+
+impl State {
+    fn class_index(&mut self, key: usize, name: &'static str) -> Option<usize> {
+        let mut free = None;
+        for (i, class) in self.classes.iter().enumerate() {
+            match *class {
+                Some(ref class) if class.key == key => return Some(i),
+                None if free.is_none() => free = Some(i),
+                _ => (),
+            }
+        }
+
+        if let Some(i) = free {
+            self.classes[i] = Some(Class { key: key, name: name });
+        }
+        free
+    }
+
+    fn name(&self, class: usize) -> &'static str {
+        self.classes[class].as_ref().map(|c| c.name).unwrap_or("?")
+    }
+
+    /// Find a path of dependencies from `from` to `to`, returned as the classes along it
+    /// (including both ends) and its length
+    fn find_path(&self, from: usize, to: usize) -> Option<([usize; MAX_CLASSES], usize)> {
+        // breadth-first search, remembering where each class was reached from
+        let mut parent = [MAX_CLASSES; MAX_CLASSES];
+        let mut queue = [0; MAX_CLASSES];
+        let (mut head, mut tail) = (0, 1);
+        queue[0] = from;
+        parent[from] = from;
+
+        while head < tail {
+            let class = queue[head];
+            head += 1;
+            if class == to {
+                let mut path = [0; MAX_CLASSES];
+                let mut len = 0;
+                let mut c = to;
+                loop {
+                    path[len] = c;
+                    len += 1;
+                    if c == from {
+                        break;
+                    }
+                    c = parent[c];
+                }
+                path[..len].reverse();
+                return Some((path, len));
+            }
+            for next in 0..MAX_CLASSES {
+                if self.deps[class] & 1 << next != 0 && parent[next] == MAX_CLASSES {
+                    parent[next] = class;
+                    queue[tail] = next;
+                    tail += 1;
+                }
+            }
+        }
+        None
+    }
+}
+
+fn disable(args: fmt::Arguments) {
+    ENABLED.store(false, Ordering::SeqCst);
+    report(args);
+}
+
+/// Record that the current CPU is about to take the lock `key`. With `check`, also validate the
+/// lock order (try-locks can't deadlock, so they skip that).
+pub fn acquire(key: usize, name: &'static str, check: bool) {
+    if !ENABLED.load(Ordering::SeqCst) {
+        return;
+    }
+
+    let interrupts_enabled = save_and_disable_interrupts();
+    acquire_locked(&mut STATE.lock(), cpu_id(), key, name, check);
+    restore_interrupts(interrupts_enabled);
+}
+
+fn acquire_locked(state: &mut State, cpu: usize, key: usize, name: &'static str, check: bool) {
+    let class = match state.class_index(key, name) {
+        Some(class) => class,
+        None => return disable(format_args!("lockdep: too many lock classes, turning off")),
+    };
+
+    let held_len = state.held_len[cpu];
+    if check {
+        let trace = backtrace();
+        for i in 0..held_len {
+            let held = state.held[cpu][i];
+
+            if held == class {
+                disable(format_args!("lockdep: recursive locking of \"{}\"", name));
+                report(format_args!("    acquired at:"));
+                report_backtrace(&trace);
+                return;
+            }
+
+            if state.deps[held] & 1 << class != 0 {
+                continue;
+            }
+
+            // the new edge held -> class closes a cycle if class already leads to held
+            if let Some((path, len)) = state.find_path(class, held) {
+                disable(format_args!("lockdep: possible deadlock taking \"{}\" while holding \"{}\"",
+                                     name, state.name(held)));
+                report(format_args!("    new dependency \"{}\" -> \"{}\" at:",
+                                    state.name(held), name));
+                report_backtrace(&trace);
+                report(format_args!("    existing dependency chain:"));
+                for w in path[..len].windows(2) {
+                    report(format_args!("    \"{}\" -> \"{}\" first seen at:",
+                                        state.name(w[0]), state.name(w[1])));
+                    report_backtrace(&state.traces[w[0]][w[1]]);
+                }
+                return;
+            }
+
+            state.deps[held] |= 1 << class;
+            state.traces[held][class] = trace;
+        }
+    }
+
+    if held_len < MAX_HELD {
+        state.held[cpu][held_len] = class;
+        state.held_len[cpu] = held_len + 1;
+    } else {
+        disable(format_args!("lockdep: more than {} locks held, turning off", MAX_HELD));
+    }
+}
+
+/// Record that the current CPU released the lock `key`
+pub fn release(key: usize) {
+    if !ENABLED.load(Ordering::SeqCst) {
+        return;
+    }
+
+    let interrupts_enabled = save_and_disable_interrupts();
+    release_locked(&mut STATE.lock(), cpu_id(), key);
+    restore_interrupts(interrupts_enabled);
+}
+
+fn release_locked(state: &mut State, cpu: usize, key: usize) {
+    let held_len = state.held_len[cpu];
+
+    // locks aren't necessarily released in the order they were taken
+    let position = (0..held_len).rev().find(|&i| {
+        let class = state.held[cpu][i];
+        state.classes[class].as_ref().map(|c| c.key) == Some(key)
+    });
+    if let Some(i) = position {
+        for j in i..held_len - 1 {
+            state.held[cpu][j] = state.held[cpu][j + 1];
+        }
+        state.held_len[cpu] = held_len - 1;
+    }
+}
@@ -0,0 +1,58 @@
+use collections::Vec;
+use core::mem;
+
+use super::{find_table, SdtHeader};
+
+const ENTRY_LOCAL_APIC: u8 = 0;
+
+const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+const LOCAL_APIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+/// Multiple APIC Description Table, which lists the interrupt controllers and processors
+#[repr(packed)]
+pub struct Madt {
+    header: SdtHeader,
+    pub local_apic_address: u32,
+    pub flags: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MadtLocalApic {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    /// Whether the processor is usable, either right away or once brought online
+    pub usable: bool,
+}
+
+impl Madt {
+    pub fn get() -> Option<&'static Madt> {
+        find_table(b"APIC").map(|header| unsafe { &*(header as *const SdtHeader as *const Madt) })
+    }
+
+    /// The local APIC of every processor in the system, the boot processor included
+    pub fn local_apics(&self) -> Vec<MadtLocalApic> {
+        let start = self as *const Madt as usize;
+        let end = start + self.header.length as usize;
+
+        let mut apics = Vec::new();
+        let mut entry = start + mem::size_of::<Madt>();
+        while entry + 2 <= end {
+            let (kind, length) = unsafe { (*(entry as *const u8), *((entry + 1) as *const u8)) };
+            if length < 2 {
+                break;
+            }
+
+            if kind == ENTRY_LOCAL_APIC && length >= 8 {
+                let flags = unsafe { *((entry + 4) as *const u32) };
+                apics.push(MadtLocalApic {
+                    processor_id: unsafe { *((entry + 2) as *const u8) },
+                    apic_id: unsafe { *((entry + 3) as *const u8) },
+                    usable: flags & (LOCAL_APIC_ENABLED | LOCAL_APIC_ONLINE_CAPABLE) != 0,
+                });
+            }
+
+            entry += length as usize;
+        }
+        apics
+    }
+}
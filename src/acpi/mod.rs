@@ -0,0 +1,124 @@
+// Just enough ACPI to find the processors: locate the RSDP, walk the RSDT or XSDT and parse the
+// MADT.
+
+use collections::Vec;
+use core::{mem, slice};
+
+use memory::{self, PhysicalAddress, PAGE_SIZE};
+
+pub use self::madt::{Madt, MadtLocalApic};
+
+mod madt;
+
+/// Root System Description Pointer, found by scanning low memory
+#[repr(packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // the rest only exists from revision 2 on
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// The header every System Description Table starts with
+#[derive(Clone, Copy)]
+#[repr(packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// Map `size` bytes of physical memory at `address`, read-only, returning the virtual address of
+/// `address`. The mapping is never undone, so this is only meant for tables read at boot.
+fn map_physical(address: PhysicalAddress, size: usize) -> usize {
+    let offset = address % PAGE_SIZE;
+    let page = memory::with_mem_ctrl(|m| m.map_pm(address - offset, size + offset,
+                                                 memory::EntryFlags::empty()))
+        .expect("could not map ACPI table");
+    page + offset
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+/// Look for the RSDP in the first KiB of the extended BIOS data area and in the BIOS ROM
+fn find_rsdp() -> Option<&'static Rsdp> {
+    let low_memory = map_physical(0, 0x10_0000);
+
+    let ebda = unsafe { *((low_memory + 0x40E) as *const u16) as usize } << 4;
+    let regions = [(ebda, ebda + 1024), (0xE_0000, 0x10_0000)];
+
+    for &(start, end) in regions.iter() {
+        // the RSDP is 16 byte aligned
+        let mut address = start;
+        while address + 20 <= end {
+            let candidate = unsafe { slice::from_raw_parts((low_memory + address) as *const u8, 20) };
+            if &candidate[..8] == b"RSD PTR " && checksum_ok(candidate) {
+                return Some(unsafe { &*((low_memory + address) as *const Rsdp) });
+            }
+            address += 16;
+        }
+    }
+    None
+}
+
+/// Map the table at `address` in full and check it
+fn map_sdt(address: PhysicalAddress) -> Option<&'static SdtHeader> {
+    let header = map_physical(address, mem::size_of::<SdtHeader>()) as *const SdtHeader;
+    let length = unsafe { (*header).length } as usize;
+
+    let table = map_physical(address, length);
+    let bytes = unsafe { slice::from_raw_parts(table as *const u8, length) };
+    if checksum_ok(bytes) {
+        Some(unsafe { &*(table as *const SdtHeader) })
+    } else {
+        None
+    }
+}
+
+/// Find the table with `signature` through the XSDT, or the RSDT on ACPI 1.0 machines
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static SdtHeader> {
+    let rsdp = match find_rsdp() {
+        Some(rsdp) => rsdp,
+        None => return None,
+    };
+
+    // entries are 64 bit addresses in the XSDT, 32 bit ones in the RSDT
+    let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (rsdp.xsdt_address as usize, 8)
+    } else {
+        (rsdp.rsdt_address as usize, 4)
+    };
+    let root = match map_sdt(root) {
+        Some(root) => root,
+        None => return None,
+    };
+
+    let entries_start = root as *const SdtHeader as usize + mem::size_of::<SdtHeader>();
+    let count = (root.length as usize - mem::size_of::<SdtHeader>()) / entry_size;
+    let addresses: Vec<usize> = (0..count).map(|i| unsafe {
+        let entry = entries_start + i * entry_size;
+        if entry_size == 8 {
+            *(entry as *const u64) as usize
+        } else {
+            *(entry as *const u32) as usize
+        }
+    }).collect();
+
+    addresses.into_iter()
+        .filter_map(map_sdt)
+        .find(|table| &table.signature == signature)
+}
@@ -0,0 +1,10 @@
+global init_image_start
+global init_image_end
+
+; The executable of init, the first user process, built from user/init.asm. `process::start_init`
+; loads it from here.
+section .rodata
+align 8
+init_image_start:
+    incbin "build/user/init"
+init_image_end:
@@ -0,0 +1,106 @@
+global divide_error_entry
+global debug_entry
+global invalid_opcode_entry
+global segment_not_present_entry
+global stack_segment_entry
+global general_protection_entry
+global page_fault_entry
+global x87_floating_point_entry
+global alignment_check_entry
+global simd_floating_point_entry
+global timer_entry
+extern divide_error_handler
+extern debug_handler
+extern invalid_opcode_handler
+extern segment_not_present_handler
+extern stack_segment_handler
+extern general_protection_handler
+extern page_fault_handler
+extern x87_floating_point_handler
+extern alignment_check_handler
+extern simd_floating_point_handler
+extern timer_handler
+
+section .text
+bits 64
+
+; Entry points for the exceptions user code can raise, and for the timer interrupt, which preempts
+; it. Unlike `extern "x86-interrupt"` handlers, they save every general purpose register in a
+; `SyscallFrame`, the way `syscall_interrupt_entry` does, so the handler can change any of the
+; registers the interrupted code resumes with, e.g. to run a signal handler.
+;
+; %1 is the entry point, %2 the Rust handler it calls with the frame and the error code, and %3 is
+; 1 if the CPU pushes an error code for the vector, 0 otherwise.
+%macro interrupt_entry 3
+%1:
+%if %3 == 0
+    push qword 0                    ; error code
+%endif
+    test qword [rsp + 16], 3        ; cs of the interrupted code
+    jz %%kernel_entry
+    swapgs
+%%kernel_entry:
+
+    push rdi
+    push rsi
+    push rdx
+    push rcx
+    push rax
+    push r8
+    push r9
+    push r10
+    push r11
+    push rbx
+    push rbp
+    push r12
+    push r13
+    push r14
+    push r15
+
+    ; take the error code out from between the registers and the interrupt frame, by moving the
+    ; registers up over it
+    mov rsi, [rsp + 15 * 8]
+    mov rcx, 15
+%%shift:
+    mov rax, [rsp + rcx * 8 - 8]
+    mov [rsp + rcx * 8], rax
+    loop %%shift
+    add rsp, 8
+
+    mov rdi, rsp
+    call %2
+
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbp
+    pop rbx
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rax
+    pop rcx
+    pop rdx
+    pop rsi
+    pop rdi
+
+    test qword [rsp + 8], 3
+    jz %%kernel_return
+    swapgs
+%%kernel_return:
+    iretq
+%endmacro
+
+interrupt_entry divide_error_entry, divide_error_handler, 0
+interrupt_entry debug_entry, debug_handler, 0
+interrupt_entry invalid_opcode_entry, invalid_opcode_handler, 0
+interrupt_entry segment_not_present_entry, segment_not_present_handler, 1
+interrupt_entry stack_segment_entry, stack_segment_handler, 1
+interrupt_entry general_protection_entry, general_protection_handler, 1
+interrupt_entry page_fault_entry, page_fault_handler, 1
+interrupt_entry x87_floating_point_entry, x87_floating_point_handler, 0
+interrupt_entry alignment_check_entry, alignment_check_handler, 1
+interrupt_entry simd_floating_point_entry, simd_floating_point_handler, 0
+interrupt_entry timer_entry, timer_handler, 0
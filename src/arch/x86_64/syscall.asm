@@ -0,0 +1,167 @@
+global syscall_entry
+global syscall_interrupt_entry
+extern syscall_dispatch
+
+; offsets into `PerCpu`, which GS points at while running in the kernel
+%define CPU_KERNEL_RSP 8
+%define CPU_USER_RSP 16
+
+; user selectors, see `GDT_USER_DATA` and `GDT_USER_CODE` in src/interrupts/gdt.rs
+%define USER_DATA_SELECTOR (3 << 3 | 3)
+%define USER_CODE_SELECTOR (4 << 3 | 3)
+
+; offsets into `SyscallFrame`
+%define FRAME_R11 (6 * 8)
+%define FRAME_RCX (11 * 8)
+%define FRAME_RIP (15 * 8)
+%define FRAME_RFLAGS (17 * 8)
+
+section .text
+bits 64
+; Entered through the `syscall` instruction. The CPU leaves the user RIP in rcx and RFLAGS in r11,
+; masks RFLAGS with SFMASK and loads the kernel CS/SS from STAR, but does not switch stacks.
+syscall_entry:
+    swapgs
+    mov [gs:CPU_USER_RSP], rsp
+    mov rsp, [gs:CPU_KERNEL_RSP]
+
+    ; build an interrupt frame so that `int 0x80` and `syscall` share one frame layout
+    push qword USER_DATA_SELECTOR   ; ss
+    push qword [gs:CPU_USER_RSP]    ; rsp
+    push r11                        ; rflags
+    push qword USER_CODE_SELECTOR   ; cs
+    push rcx                        ; rip
+
+    push rdi
+    push rsi
+    push rdx
+    push rcx
+    push rax
+    push r8
+    push r9
+    push r10
+    push r11
+    push rbx
+    push rbp
+    push r12
+    push r13
+    push r14
+    push r15
+
+    ; the user stack is no longer in use, so it's safe to take interrupts again
+    sti
+
+    mov rdi, rsp
+    call syscall_dispatch
+
+    cli
+
+    ; SYSRET with a non-canonical RIP faults in ring 0 on Intel CPUs, so let IRETQ raise the
+    ; fault in ring 3 instead
+    mov rcx, [rsp + FRAME_RIP]
+    shl rcx, 16
+    sar rcx, 16
+    cmp rcx, [rsp + FRAME_RIP]
+    jne .iret
+
+    ; SYSRET returns with RIP in rcx and RFLAGS in r11. A frame where those registers hold
+    ; anything else, like one rewritten to run a signal handler, needs IRETQ to restore them.
+    mov rcx, [rsp + FRAME_RCX]
+    cmp rcx, [rsp + FRAME_RIP]
+    jne .iret
+    mov rcx, [rsp + FRAME_R11]
+    cmp rcx, [rsp + FRAME_RFLAGS]
+    jne .iret
+
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbp
+    pop rbx
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rax
+    pop rcx
+    pop rdx
+    pop rsi
+    pop rdi
+
+    pop rcx                         ; rip
+    add rsp, 8                      ; cs
+    pop r11                         ; rflags
+    pop rsp                         ; rsp
+
+    swapgs
+    o64 sysret
+
+.iret:
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbp
+    pop rbx
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rax
+    pop rcx
+    pop rdx
+    pop rsi
+    pop rdi
+
+    swapgs
+    iretq
+
+; Entered through `int 0x80`. The CPU has already pushed an interrupt frame onto the kernel stack
+; (from TSS.RSP0 when coming from user mode), so only the general purpose registers are left.
+syscall_interrupt_entry:
+    test qword [rsp + 8], 3         ; cs of the caller
+    jz .kernel_caller
+    swapgs
+.kernel_caller:
+
+    push rdi
+    push rsi
+    push rdx
+    push rcx
+    push rax
+    push r8
+    push r9
+    push r10
+    push r11
+    push rbx
+    push rbp
+    push r12
+    push r13
+    push r14
+    push r15
+
+    mov rdi, rsp
+    call syscall_dispatch
+
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbp
+    pop rbx
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rax
+    pop rcx
+    pop rdx
+    pop rsi
+    pop rdi
+
+    test qword [rsp + 8], 3
+    jz .kernel_return
+    swapgs
+.kernel_return:
+    iretq
@@ -0,0 +1,88 @@
+global trampoline_start
+global trampoline_end
+
+; The trampoline is copied to this physical address, which the startup IPI points the application
+; processors at. It's assembled as part of the kernel, so every address inside it has to be
+; computed relative to where it's copied to.
+%define TRAMPOLINE 0x8000
+%define ADDR(label) (TRAMPOLINE + (label - trampoline_start))
+
+section .text
+bits 16
+; Application processors start here in real mode, and go straight to long mode with the page
+; table, stack and entry point the boot processor left in the data below.
+trampoline_start:
+    jmp short real_mode_entry
+
+; filled in by `smp::start_ap`, which hardcodes the offsets
+align 8
+ap_ready: dq 0          ; set once the rest has been read
+ap_cpu_id: dq 0
+ap_page_table: dq 0
+ap_stack_top: dq 0
+ap_entry: dq 0
+
+real_mode_entry:
+    cli
+    cld
+    xor ax, ax
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+
+    lgdt [ADDR(gdt.pointer)]
+
+    ; PAE, plus SSE like boot.asm enables on the boot processor
+    mov eax, cr4
+    or eax, 1 << 5 | 3 << 9
+    mov cr4, eax
+
+    mov eax, [ADDR(ap_page_table)]
+    mov cr3, eax
+
+    ; long mode and the no-execute bit
+    mov ecx, 0xC0000080
+    rdmsr
+    or eax, 1 << 8 | 1 << 11
+    wrmsr
+
+    ; paging, write protection, coprocessor monitoring and protected mode, no FPU emulation
+    mov eax, cr0
+    and eax, ~(1 << 2)
+    or eax, 1 << 31 | 1 << 16 | 1 << 1 | 1 << 0
+    mov cr0, eax
+
+    jmp gdt.code:ADDR(long_mode_entry)
+
+bits 64
+long_mode_entry:
+    mov ax, gdt.data
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    xor ax, ax
+    mov fs, ax
+    mov gs, ax
+
+    mov rsp, [ADDR(ap_stack_top)]
+    mov rdi, [ADDR(ap_cpu_id)]
+    mov rax, [ADDR(ap_entry)]
+    ; everything has been read, the boot processor may fill in the next AP's data
+    mov qword [ADDR(ap_ready)], 1
+
+    call rax
+.halt:
+    hlt
+    jmp .halt
+
+align 8
+gdt:
+    dq 0
+.code: equ $ - gdt
+    dq (1<<43) | (1<<44) | (1<<47) | (1<<53) ; code segment
+.data: equ $ - gdt
+    dq (1<<41) | (1<<44) | (1<<47) ; data segment
+.pointer:
+    dw $ - gdt - 1
+    dq ADDR(gdt)
+trampoline_end:
@@ -1,3 +1,6 @@
+use memory::pcid;
+
+/// Callee-saved state of a thread, saved and restored by `switch_to`
 pub struct Context {
     cr3: usize,
     rflags: usize,
@@ -11,22 +14,34 @@ pub struct Context {
 }
 
 impl Context {
+    /// A context that starts with interrupts disabled, executing the return address on top of
+    /// the stack at `rsp`
     pub fn new(cr3: usize, rsp: usize) -> Context {
         Context {
             cr3: cr3,
+            rflags: 0,
+            rbx: 0,
+            r12: 0,
+            r13: 0,
+            r14: 0,
+            r15: 0,
             rbp: 0,
             rsp: rsp,
         }
     }
 
+    /// Save the current state into `self` and continue from `next`. Returns once something
+    /// switches back to `self`.
     #[inline(never)]
     #[naked]
-    pub fn switch_to(&mut self, next: &Context) {
+    pub unsafe fn switch_to(&mut self, next: &Context) {
         // Save current page table
         asm!("mov $0, cr3" : "=r"(self.cr3) : : "memory" : "intel", "volatile");
         if next.cr3 != self.cr3 {
-            // Switch to next page table
-            asm!("mov cr3, $0" : : "r"(next.cr3) : "memory" : "intel", "volatile");
+            // Switch to next page table, keeping the TLB entries of its address space if they're
+            // tagged with its PCID
+            asm!("mov cr3, $0" : : "r"(next.cr3 | pcid::no_flush_bit(next.cr3))
+                 : "memory" : "intel", "volatile");
         }
 
         // Save current RFLAGS register
@@ -0,0 +1,281 @@
+// Lazy saving of the x87/SSE/AVX register state. The kernel is built without SSE, so only user
+// code touches these registers. A thread switch just sets CR0.TS; the first FPU or vector
+// instruction the new thread executes raises #NM, and only then is the state of the thread that
+// last used the FPU saved and the new thread's own state loaded.
+
+use collections::Vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use spin::Once;
+use x86_64::registers::control_regs::{cr0, cr0_write, Cr0};
+
+use super::{with_thread, current_id, ThreadId};
+
+const CPUID_1_ECX_XSAVE: u32 = 1 << 26;
+const CPUID_1_ECX_AVX: u32 = 1 << 28;
+
+const CR4_OSXSAVE: usize = 1 << 18;
+
+const XCR0_X87: u64 = 1 << 0;
+const XCR0_SSE: u64 = 1 << 1;
+const XCR0_AVX: u64 = 1 << 2;
+
+/// Size of the FXSAVE area
+const FXSAVE_SIZE: usize = 512;
+/// XSAVE needs 64 byte alignment, FXSAVE 16
+pub const AREA_ALIGN: usize = 64;
+
+// offsets into the saved area
+const MXCSR: usize = 24;
+const MXCSR_MASK: usize = 28;
+const XSAVE_HEADER: usize = 512;
+const XSAVE_HEADER_SIZE: usize = 64;
+
+/// The MXCSR bits that may be set when there's no mask saved
+const DEFAULT_MXCSR_MASK: u32 = 0xFFBF;
+
+static USE_XSAVE: AtomicBool = AtomicBool::new(false);
+static AREA_SIZE: AtomicUsize = AtomicUsize::new(FXSAVE_SIZE);
+/// The XCR0 state components to enable on every CPU
+static XSAVE_FEATURES: AtomicUsize = AtomicUsize::new(0);
+
+percpu! {
+    /// The thread whose state is in this CPU's registers, plus one. 0 if none.
+    static OWNER: AtomicUsize = AtomicUsize::new(0);
+}
+
+/// The state right after initialization, which every new thread starts from
+static INITIAL_STATE: Once<FpuState> = Once::new();
+
+/// Saved x87/SSE/AVX registers of a thread
+pub struct FpuState {
+    buffer: Vec<u8>,
+}
+
+impl FpuState {
+    pub fn new() -> FpuState {
+        let mut state = FpuState::zeroed();
+        if let Some(initial) = INITIAL_STATE.try() {
+            state.area_mut().copy_from_slice(initial.area());
+        }
+        state
+    }
+
+    /// A state holding the registers in `bytes`, as `bytes` returned them, e.g. after a round
+    /// trip through user memory. Returns `None` if they can't have come from there, and loading
+    /// them would fault.
+    pub fn from_bytes(bytes: &[u8]) -> Option<FpuState> {
+        let mut state = FpuState::zeroed();
+        if bytes.len() != state.area().len() {
+            return None;
+        }
+        state.area_mut().copy_from_slice(bytes);
+
+        // the mask in `bytes` could be anything, the one the kernel saved is the CPU's
+        let mask = INITIAL_STATE.try().map_or(0, |initial| read_u32(initial.area(), MXCSR_MASK));
+        let mask = if mask == 0 { DEFAULT_MXCSR_MASK } else { mask };
+        if read_u32(bytes, MXCSR) & !mask != 0 {
+            return None;
+        }
+
+        if USE_XSAVE.load(Ordering::SeqCst) {
+            // the header holds the saved components, and the rest of it, the compacted format
+            // included, must be zero
+            let header = &bytes[XSAVE_HEADER..XSAVE_HEADER + XSAVE_HEADER_SIZE];
+            let saved = read_u32(header, 0) as u64 | (read_u32(header, 4) as u64) << 32;
+            if saved & !(XSAVE_FEATURES.load(Ordering::SeqCst) as u64) != 0 ||
+               header[8..].iter().any(|&byte| byte != 0) {
+                return None;
+            }
+        }
+        Some(state)
+    }
+
+    /// The saved registers, in the format of FXSAVE or XSAVE
+    pub fn bytes(&self) -> &[u8] {
+        self.area()
+    }
+
+    fn zeroed() -> FpuState {
+        FpuState { buffer: vec![0; AREA_SIZE.load(Ordering::SeqCst) + AREA_ALIGN] }
+    }
+
+    fn offset(&self) -> usize {
+        let address = self.buffer.as_ptr() as usize;
+        (AREA_ALIGN - address % AREA_ALIGN) % AREA_ALIGN
+    }
+
+    fn area(&self) -> &[u8] {
+        let offset = self.offset();
+        &self.buffer[offset..offset + AREA_SIZE.load(Ordering::SeqCst)]
+    }
+
+    fn area_mut(&mut self) -> &mut [u8] {
+        let offset = self.offset();
+        &mut self.buffer[offset..offset + AREA_SIZE.load(Ordering::SeqCst)]
+    }
+
+    /// Save the registers into `self`. CR0.TS must be clear.
+    unsafe fn save(&mut self) {
+        let area = self.area_mut().as_mut_ptr();
+        if USE_XSAVE.load(Ordering::SeqCst) {
+            asm!("xsave64 [$0]" : : "r"(area), "{eax}"(!0u32), "{edx}"(!0u32) : "memory" : "intel", "volatile");
+        } else {
+            asm!("fxsave64 [$0]" : : "r"(area) : "memory" : "intel", "volatile");
+        }
+    }
+
+    /// Load the registers from `self`. CR0.TS must be clear.
+    unsafe fn restore(&self) {
+        let area = self.area().as_ptr();
+        if USE_XSAVE.load(Ordering::SeqCst) {
+            asm!("xrstor64 [$0]" : : "r"(area), "{eax}"(!0u32), "{edx}"(!0u32) : "memory" : "intel", "volatile");
+        } else {
+            asm!("fxrstor64 [$0]" : : "r"(area) : "memory" : "intel", "volatile");
+        }
+    }
+}
+
+/// Bytes the saved registers take up, the length of `FpuState::bytes`
+pub fn area_size() -> usize {
+    AREA_SIZE.load(Ordering::SeqCst)
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    bytes[offset..offset + 4].iter().rev().fold(0, |value, &byte| value << 8 | byte as u32)
+}
+
+/// Pick FXSAVE or XSAVE, enable AVX if there is any, and record the clean state new threads start
+/// with. boot.asm has already enabled SSE. Called on the boot processor, the others call
+/// `init_cpu`.
+pub fn init() {
+    let (_, _, ecx, _) = ::cpu::cpuid(1, 0);
+
+    if ecx & CPUID_1_ECX_XSAVE != 0 {
+        let mut features = XCR0_X87 | XCR0_SSE;
+        if ecx & CPUID_1_ECX_AVX != 0 {
+            features |= XCR0_AVX;
+        }
+        XSAVE_FEATURES.store(features as usize, Ordering::SeqCst);
+        USE_XSAVE.store(true, Ordering::SeqCst);
+        enable_xsave();
+
+        // EBX of leaf 0xD is the size needed for the features enabled in XCR0
+        let (_, size, _, _) = ::cpu::cpuid(0xD, 0);
+        AREA_SIZE.store(size as usize, Ordering::SeqCst);
+    }
+
+    INITIAL_STATE.call_once(|| {
+        let mut state = FpuState::zeroed();
+        unsafe {
+            asm!("clts; fninit" : : : "memory" : "intel", "volatile");
+            // all SSE exceptions masked
+            let mxcsr: u32 = 0x1F80;
+            asm!("ldmxcsr [$0]" : : "r"(&mxcsr) : "memory" : "intel", "volatile");
+            state.save();
+        }
+        state
+    });
+
+    set_task_switched(true);
+}
+
+/// Enable the same extended state on another CPU as `init` did on the boot processor
+pub fn init_cpu() {
+    if USE_XSAVE.load(Ordering::SeqCst) {
+        enable_xsave();
+    }
+    set_task_switched(true);
+}
+
+fn enable_xsave() {
+    let features = XSAVE_FEATURES.load(Ordering::SeqCst) as u64;
+    unsafe {
+        let cr4: usize;
+        asm!("mov $0, cr4" : "=r"(cr4) : : : "intel", "volatile");
+        asm!("mov cr4, $0" : : "r"(cr4 | CR4_OSXSAVE) : "memory" : "intel", "volatile");
+        asm!("xsetbv" : : "{ecx}"(0), "{eax}"(features as u32), "{edx}"((features >> 32) as u32)
+             : : "intel", "volatile");
+    }
+}
+
+fn set_task_switched(set: bool) {
+    unsafe {
+        if set {
+            cr0_write(cr0() | Cr0::TASK_SWITCHED);
+        } else {
+            asm!("clts" : : : "memory" : "intel", "volatile");
+        }
+    }
+}
+
+/// Called by `switch_to` before switching from `prev` to `next`. Traps the next use of the FPU,
+/// unless `next` owns the registers already.
+pub fn switching(prev: ThreadId, next: ThreadId) {
+    let owner = OWNER.get().load(Ordering::SeqCst);
+
+    // with more than one CPU, `prev` may run on another CPU next, and has to find its state in
+    // memory there
+    if owner == prev.0 + 1 && ::smp::cpu_count() > 1 {
+        set_task_switched(false);
+        unsafe { with_thread(prev, |thread| thread.fpu.save()); }
+        OWNER.get().store(0, Ordering::SeqCst);
+        set_task_switched(true);
+        return;
+    }
+
+    set_task_switched(owner != next.0 + 1);
+}
+
+/// Called when the thread `id` is freed, so its state isn't saved into freed memory later. Only
+/// the registers of the CPU the thread last ran on can hold its state, and a thread is freed by
+/// the CPU it exited on.
+pub fn forget(id: ThreadId) {
+    OWNER.get().compare_and_swap(id.0 + 1, 0, Ordering::SeqCst);
+}
+
+/// Handler for #NM: hand the registers over to the current thread
+pub fn device_not_available() {
+    set_task_switched(false);
+
+    let current = current_id();
+    let owner = OWNER.get().load(Ordering::SeqCst);
+    if owner == current.0 + 1 {
+        return;
+    }
+
+    unsafe {
+        if owner != 0 {
+            with_thread(ThreadId(owner - 1), |thread| thread.fpu.save());
+        }
+        with_thread(current, |thread| thread.fpu.restore());
+    }
+    OWNER.get().store(current.0 + 1, Ordering::SeqCst);
+}
+
+/// A copy of the current thread's registers, e.g. to put back once a signal handler is done
+pub fn save_current() -> FpuState {
+    let current = current_id();
+    let mut state = FpuState::zeroed();
+    ::interrupts::without_interrupts(|| {
+        if OWNER.get().load(Ordering::SeqCst) == current.0 + 1 {
+            // the registers are live, and CR0.TS is clear while the owner runs
+            unsafe { state.save(); }
+        } else {
+            with_thread(current, |thread| state.area_mut().copy_from_slice(thread.fpu.area()));
+        }
+    });
+    state
+}
+
+/// Replace the current thread's registers with `state`
+pub fn restore_current(state: FpuState) {
+    let current = current_id();
+    ::interrupts::without_interrupts(|| {
+        // drop the live registers, so the next use loads `state`
+        if OWNER.get().compare_and_swap(current.0 + 1, 0, Ordering::SeqCst) == current.0 + 1 {
+            set_task_switched(true);
+        }
+        with_thread(current, |thread| thread.fpu = state);
+    });
+}
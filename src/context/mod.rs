@@ -1,23 +1,283 @@
-// Switch to usermode, start executing at ip with stack at sp
-pub unsafe fn usermode(ip: usize, sp: usize) -> ! {
-    // Go to usermode
-    asm!("mov ds, ax
+use alloc::boxed::Box;
+use collections::BTreeMap;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use irq_lock::IrqMutex;
+use x86_64::instructions::interrupts;
+use x86_64::registers::control_regs;
+use x86_64::registers::msr::{wrmsr, IA32_FS_BASE};
+
+use interrupts::{gdt, set_kernel_stack};
+use memory::with_mem_ctrl;
+use process::{self, ProcessId, KERNEL_PID};
+use scheduler;
+
+pub use self::thread::{Status, Thread, ThreadId, ThreadStats, UserStart};
+
+#[cfg(target_arch = "x86_64")]
+#[path = "arch/x86_64.rs"]
+mod arch;
+pub mod fpu;
+mod thread;
+
+/// Size of the kernel stack of a new thread
+const KSTACK_PAGES: usize = 4;
+
+lazy_static! {
+    // Threads are boxed so their `arch::Context` stays put while `switch_to` uses it, even if the
+    // map moves its entries around
+    static ref THREADS: IrqMutex<BTreeMap<ThreadId, Box<Thread>>> =
+        IrqMutex::named(lock_class!("context::THREADS"), BTreeMap::new());
+}
+
+percpu! {
+    /// The thread running on this CPU
+    static CURRENT: AtomicUsize = AtomicUsize::new(0);
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+percpu! {
+    /// The thread this CPU last switched away from, until the switch is complete
+    static PREV: AtomicUsize = AtomicUsize::new(0);
+}
+
+/// Turn the code that's running into thread 0
+pub fn init() {
+    fpu::init();
+
+    let cr3 = control_regs::cr3().0 as usize;
+    // the context is filled in by the first switch away from it
+    THREADS.lock().insert(ThreadId(0), Box::new(Thread::new(ThreadId(0), KERNEL_PID,
+                                                            Status::Running,
+                                                            arch::Context::new(cr3, 0),
+                                                            None, None)));
+}
+
+/// Turn the code that's running on an application processor into a thread of its own
+pub fn init_cpu() {
+    fpu::init_cpu();
+
+    let id = ThreadId(NEXT_ID.fetch_add(1, Ordering::SeqCst));
+    let cr3 = control_regs::cr3().0 as usize;
+    THREADS.lock().insert(id, Box::new(Thread::new(id, KERNEL_PID, Status::Running,
+                                                   arch::Context::new(cr3, 0), None, None)));
+    CURRENT.get().store(id.0, Ordering::SeqCst);
+}
+
+pub fn current_id() -> ThreadId {
+    ThreadId(CURRENT.get().load(Ordering::SeqCst))
+}
+
+/// Create a kernel thread that runs `entry` and make it runnable
+pub fn spawn(entry: fn()) -> Option<ThreadId> {
+    let id = create(entry);
+    if let Some(id) = id {
+        scheduler::enqueue(id);
+    }
+    id
+}
+
+/// Create a kernel thread that runs `entry` once the scheduler switches to it
+pub fn create(entry: fn()) -> Option<ThreadId> {
+    create_in(KERNEL_PID, process::kernel_cr3(), entry)
+}
+
+/// Create a thread of `process` that runs `entry` on the page table loaded with `cr3`, once the
+/// scheduler switches to it
+pub fn create_in(process: ProcessId, cr3: usize, entry: fn()) -> Option<ThreadId> {
+    let kstack = match with_mem_ctrl(|m| m.alloc_stack(KSTACK_PAGES)) {
+        Ok(kstack) => kstack,
+        Err(_) => return None,
+    };
+
+    // `switch_to` returns into the trampoline, which must see the stack alignment of a function
+    // that was just called, so a fake return address goes underneath
+    let rsp = kstack.top() - 2 * 8;
+    unsafe {
+        *(rsp as *mut usize) = thread_trampoline as usize;
+        *((rsp + 8) as *mut usize) = 0;
+    }
+
+    let id = ThreadId(NEXT_ID.fetch_add(1, Ordering::SeqCst));
+    THREADS.lock().insert(id, Box::new(Thread::new(id, process, Status::Runnable,
+                                                   arch::Context::new(cr3, rsp),
+                                                   Some(kstack), Some(entry))));
+
+    Some(id)
+}
+
+/// Where every new thread starts out, with interrupts disabled
+extern "C" fn thread_trampoline() -> ! {
+    finish_switch();
+
+    let entry = THREADS.lock().get(&current_id()).and_then(|thread| thread.entry)
+        .expect("thread has no entry point");
+
+    unsafe { interrupts::enable(); }
+    entry();
+
+    scheduler::exit();
+}
+
+/// Switch from the current thread to `next`, which the scheduler has already marked as running.
+/// Returns once something switches back to the current thread. Interrupts must be disabled.
+pub unsafe fn switch_to(next: ThreadId) {
+    let current = current_id();
+    let (prev_context, next_context, next_switching_out, next_kstack_top, next_fs_base) = {
+        let mut threads = THREADS.lock();
+
+        let prev = {
+            let prev = threads.get_mut(&current).expect("current thread missing");
+            prev.switching_out.store(true, Ordering::SeqCst);
+            &mut prev.arch as *mut arch::Context
+        };
+
+        let (next, switching_out, kstack_top, fs_base) = {
+            let next = threads.get_mut(&next).expect("switching to a missing thread");
+            next.stats.switches += 1;
+            (&next.arch as *const arch::Context, &next.switching_out as *const AtomicBool,
+             next.kstack.as_ref().map(|kstack| kstack.top()), next.fs_base)
+        };
+
+        (prev, next, switching_out, kstack_top, fs_base)
+    };
+
+    // another CPU may have woken `next` right after blocking it, before it got to save its
+    // registers
+    while (*next_switching_out).load(Ordering::SeqCst) {
+        asm!("pause" : : : "memory" : "intel", "volatile");
+    }
+
+    // entering the kernel from user mode lands on top of the thread's own kernel stack. Threads
+    // without one never leave the kernel.
+    if let Some(top) = next_kstack_top {
+        set_kernel_stack(top);
+    }
+    wrmsr(IA32_FS_BASE, next_fs_base as u64);
+
+    fpu::switching(current, next);
+    PREV.get().store(current.0, Ordering::SeqCst);
+    CURRENT.get().store(next.0, Ordering::SeqCst);
+    (*prev_context).switch_to(&*next_context);
+
+    finish_switch();
+}
+
+/// Called on the stack of the thread a CPU switched to. Lets other CPUs run the thread switched
+/// away from, or frees it if it exited.
+fn finish_switch() {
+    let prev = ThreadId(PREV.get().load(Ordering::SeqCst));
+    let exited = {
+        let mut threads = THREADS.lock();
+        let exited = match threads.get(&prev) {
+            Some(thread) => {
+                thread.switching_out.store(false, Ordering::SeqCst);
+                thread.status == Status::Exited
+            }
+            None => false,
+        };
+        if exited { threads.remove(&prev) } else { None }
+    };
+
+    if let Some(thread) = exited {
+        free(thread);
+    }
+}
+
+/// Release what's left of an exited thread, now that nothing runs on its stack anymore
+fn free(mut thread: Box<Thread>) {
+    fpu::forget(thread.id);
+    if let Some(kstack) = thread.kstack.take() {
+        with_mem_ctrl(|m| m.free_stack(kstack));
+    }
+    process::thread_freed(thread.process, thread.id);
+}
+
+/// Whether the current thread's process has exited, so the thread should stop
+pub fn exit_pending() -> bool {
+    with_thread(current_id(), |thread| thread.exit_pending).unwrap_or(false)
+}
+
+/// Whether the current thread should stop waiting for whatever it's blocked on, because its
+/// process has exited or has signals to act on
+pub fn interrupted() -> bool {
+    with_thread(current_id(), |thread| thread.exit_pending || thread.signal_pending)
+        .unwrap_or(false)
+}
+
+pub fn status(id: ThreadId) -> Option<Status> {
+    THREADS.lock().get(&id).map(|thread| thread.status)
+}
+
+pub fn set_status(id: ThreadId, status: Status) {
+    if let Some(thread) = THREADS.lock().get_mut(&id) {
+        thread.status = status;
+    }
+}
+
+/// Run `f` on the thread `id`, if it exists
+pub fn with_thread<F, R>(id: ThreadId, f: F) -> Option<R> where F: FnOnce(&mut Thread) -> R {
+    THREADS.lock().get_mut(&id).map(|thread| f(thread))
+}
+
+pub fn stats(id: ThreadId) -> Option<ThreadStats> {
+    THREADS.lock().get(&id).map(|thread| thread.stats)
+}
+
+/// Entry point of user threads. Drops to user mode where the thread's `user_start` says.
+pub fn enter_user() {
+    let start = with_thread(current_id(), |thread| thread.user_start)
+        .and_then(|start| start)
+        .expect("thread has no user mode entry point");
+
+    // an interrupt between the `swapgs` in `usermode` and the return to user mode would find the
+    // user's GS base
+    interrupts::disable();
+    unsafe { usermode(start.ip, start.sp, start.arg, start.fs_base); }
+}
+
+// Switch to usermode, start executing at ip with stack at sp, arg in rdi and the FS base at fs_base
+pub unsafe fn usermode(ip: usize, sp: usize, arg: usize, fs_base: usize) -> ! {
+    // Loading the FS selector clobbers the FS base, so the base goes in afterwards
+    asm!("mov fs, ax" : : "{ax}"(gdt::GDT_USER_TLS << 3 | 3) : : "intel", "volatile");
+    wrmsr(IA32_FS_BASE, fs_base as u64);
+
+    // Go to usermode. The kernel's GS base has to be swapped out before GS is reloaded, as
+    // loading the selector clobbers the active base. Every register but the argument is cleared,
+    // so none of the kernel's pointers leak to the thread.
+    asm!("swapgs
+        mov ds, ax
         mov es, ax
-        mov fs, bx
         mov gs, ax
         push rax
         push rcx
         push rdx
         push rsi
         push rdi
+        mov rdi, r8
+        xor eax, eax
+        xor ebx, ebx
+        xor ecx, ecx
+        xor edx, edx
+        xor esi, esi
+        xor ebp, ebp
+        xor r8d, r8d
+        xor r9d, r9d
+        xor r10d, r10d
+        xor r11d, r11d
+        xor r12d, r12d
+        xor r13d, r13d
+        xor r14d, r14d
+        xor r15d, r15d
         iretq"
         : // No output because it never returns
         :   "{rax}"(gdt::GDT_USER_DATA << 3 | 3), // Data segment
-            "{rbx}"(gdt::GDT_USER_TLS << 3 | 3), // TLS segment
             "{rcx}"(sp), // Stack pointer
-            "{rdx}"(3 << 12 | 1 << 9), // Flags - Set IOPL and interrupt enable flag
+            "{rdx}"(1 << 9), // Flags - Set the interrupt enable flag, leave IOPL at 0
             "{rsi}"(gdt::GDT_USER_CODE << 3 | 3), // Code segment
-            "{rdi}"(ip) // IP
+            "{rdi}"(ip), // IP
+            "{r8}"(arg) // First argument
         : // No clobers because it never returns
         : "intel", "volatile");
     unreachable!();
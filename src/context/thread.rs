@@ -0,0 +1,103 @@
+use core::sync::atomic::AtomicBool;
+
+use memory::Stack;
+use process::ProcessId;
+use scheduler::Class;
+
+use super::arch;
+use super::fpu::FpuState;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(pub usize);
+
+/// Where a user thread starts out in user mode
+#[derive(Clone, Copy, Debug)]
+pub struct UserStart {
+    pub ip: usize,
+    pub sp: usize,
+    /// Handed to the thread in rdi, its first argument register
+    pub arg: usize,
+    /// See `Thread::fs_base`
+    pub fs_base: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    /// Waiting in the run queue
+    Runnable,
+    Running,
+    /// Waiting for something to call `scheduler::wake`
+    Blocked,
+    Exited,
+}
+
+pub struct Thread {
+    pub id: ThreadId,
+    /// The process the thread runs in
+    pub process: ProcessId,
+    pub status: Status,
+    pub arch: arch::Context,
+    /// Saved x87/SSE/AVX registers, loaded lazily
+    pub fpu: FpuState,
+    /// The stack the thread runs on in the kernel. `None` for the boot thread, which runs on the
+    /// stack set up in boot.asm.
+    pub kstack: Option<Stack>,
+    /// Where the thread starts executing
+    pub entry: Option<fn()>,
+    /// Where `context::enter_user` takes a user thread
+    pub user_start: Option<UserStart>,
+    /// FS base of a user thread, the thread pointer of its TLS block. Loaded whenever the thread
+    /// is switched to, as the kernel doesn't use FS itself.
+    pub fs_base: usize,
+    /// Scheduling class, which picks the policy that schedules the thread
+    pub class: Class,
+    pub stats: ThreadStats,
+    /// Tick at which the thread last became runnable
+    pub ready_since: usize,
+    /// Set by a wakeup that arrived while the thread wasn't blocked, so its next attempt to block
+    /// returns right away
+    pub wakeup_pending: bool,
+    /// Set while a CPU is still saving the thread's registers after deciding to switch away from
+    /// it. No other CPU may switch to the thread, or free it, until it's clear.
+    pub switching_out: AtomicBool,
+    /// Set when the thread's process exits. The thread stops instead of returning to user mode.
+    pub exit_pending: bool,
+    /// Set when the thread's process has signals to act on. Blocking syscalls give up, so the
+    /// thread gets to them on its way back to user mode.
+    pub signal_pending: bool,
+}
+
+impl Thread {
+    pub fn new(id: ThreadId, process: ProcessId, status: Status, arch: arch::Context,
+               kstack: Option<Stack>, entry: Option<fn()>) -> Thread {
+        Thread {
+            id: id,
+            process: process,
+            status: status,
+            arch: arch,
+            fpu: FpuState::new(),
+            kstack: kstack,
+            entry: entry,
+            user_start: None,
+            fs_base: 0,
+            class: Class::default(),
+            stats: ThreadStats::default(),
+            ready_since: 0,
+            wakeup_pending: false,
+            switching_out: AtomicBool::new(false),
+            exit_pending: false,
+            signal_pending: false,
+        }
+    }
+}
+
+/// Scheduling statistics, in scheduler ticks
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ThreadStats {
+    /// CPU time used
+    pub runtime: u64,
+    /// Time spent runnable but waiting for the CPU
+    pub wait_time: u64,
+    /// Number of times the thread was switched to
+    pub switches: u64,
+}
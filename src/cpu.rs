@@ -0,0 +1,11 @@
+/// Execute CPUID with `leaf` in EAX and `subleaf` in ECX, returning EAX, EBX, ECX and EDX
+pub fn cpuid(leaf: u32, subleaf: u32) -> (u32, u32, u32, u32) {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    unsafe {
+        asm!("cpuid"
+             : "={eax}"(eax), "={ebx}"(ebx), "={ecx}"(ecx), "={edx}"(edx)
+             : "{eax}"(leaf), "{ecx}"(subleaf)
+             : : "intel", "volatile");
+    }
+    (eax, ebx, ecx, edx)
+}
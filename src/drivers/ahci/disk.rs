@@ -3,7 +3,7 @@ use core::ptr;
 use ::syscall::error::Result;
 use ::syscall::io::{Dma, DmaAllocator};
 
-use super::hba::{HbaPort, HbaCmdTable, HbaCmdHeader};
+use super::hba::{HbaPort, HbaCmdTable, HbaCmdHeader, PortIrq};
 
 pub struct Disk<'a> {
     id: usize,
@@ -12,12 +12,15 @@ pub struct Disk<'a> {
     clb: Dma<'a, [HbaCmdHeader; 32]>,
     ctbas: [Dma<'a, HbaCmdTable>; 32],
     _fb: Dma<'a, [u8; 256]>,
-    buf: Dma<'a, [u8; 256 * 512]>
+    buf: Dma<'a, [u8; 256 * 512]>,
+    /// Interrupt state of the port, if it completes commands with interrupts
+    irq: Option<&'static PortIrq>,
 }
 
 impl<'a> Disk<'a> {
     pub fn new(dma_alloc: &'a DmaAllocator,
-               id: usize, port: &'static mut HbaPort) -> Result<Disk<'a>> {
+               id: usize, port: &'static mut HbaPort,
+               irq: Option<&'static PortIrq>) -> Result<Disk<'a>> {
         let mut clb = dma_alloc.allocate_zeroed()?;
         let mut ctbas = [
             dma_alloc.allocate_zeroed()?, dma_alloc.allocate_zeroed()?, dma_alloc.allocate_zeroed()?, dma_alloc.allocate_zeroed()?,
@@ -32,9 +35,9 @@ impl<'a> Disk<'a> {
         let mut fb = dma_alloc.allocate_zeroed()?;
         let buf = dma_alloc.allocate_zeroed()?;
 
-        port.init(&mut clb, &mut ctbas, &mut fb);
+        port.init(&mut clb, &mut ctbas, &mut fb, irq.is_some());
 
-        let size = unsafe { port.identify(dma_alloc, &mut clb, &mut ctbas).unwrap_or(0) };
+        let size = unsafe { port.identify(dma_alloc, &mut clb, &mut ctbas, irq).unwrap_or(0) };
 
         Ok(Disk {
             id: id,
@@ -43,7 +46,8 @@ impl<'a> Disk<'a> {
             clb: clb,
             ctbas: ctbas,
             _fb: fb,
-            buf: buf
+            buf: buf,
+            irq: irq,
         })
     }
 
@@ -60,7 +64,7 @@ impl<'a> Disk<'a> {
 
         let mut sector: usize = 0;
         while sectors - sector >= 255 {
-            if let Err(err) = self.port.ata_dma(block + sector as u64, 255, false, &mut self.clb, &mut self.ctbas, &mut self.buf) {
+            if let Err(err) = self.port.ata_dma(block + sector as u64, 255, false, &mut self.clb, &mut self.ctbas, &mut self.buf, self.irq) {
                 return Err(err);
             }
 
@@ -71,7 +75,7 @@ impl<'a> Disk<'a> {
         if sector < sectors {
             if let Err(err) = self.port.ata_dma(block + sector as u64, sectors - sector,
                                                 false, &mut self.clb, &mut self.ctbas,
-                                                &mut self.buf)
+                                                &mut self.buf, self.irq)
             {
                 return Err(err);
             }
@@ -98,7 +102,7 @@ impl<'a> Disk<'a> {
             }
 
             if let Err(err) = self.port.ata_dma(block + sector as u64, 255, true, 
-                                                &mut self.clb, &mut self.ctbas, &mut self.buf)
+                                                &mut self.clb, &mut self.ctbas, &mut self.buf, self.irq)
             {
                 return Err(err);
             }
@@ -109,7 +113,7 @@ impl<'a> Disk<'a> {
             unsafe { ptr::copy(buffer.as_ptr().offset(sector as isize * 512), self.buf.as_mut_ptr(), (sectors - sector) * 512); }
 
             if let Err(err) = self.port.ata_dma(block + sector as u64, sectors - sector, true,
-                                                &mut self.clb, &mut self.ctbas, &mut self.buf)
+                                                &mut self.clb, &mut self.ctbas, &mut self.buf, self.irq)
             {
                 return Err(err);
             }
@@ -3,9 +3,11 @@
 use collections::String;
 use core::mem::size_of;
 use core::ops::DerefMut;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use core::{ptr, u32};
-//use core::thread;
 
+use ::scheduler;
+use ::sync::Completion;
 use ::syscall::error::{Error, Result, EIO};
 use ::syscall::io::{Dma, DmaAllocator, Io, Mmio};
 
@@ -22,6 +24,15 @@ const HBA_PORT_CMD_FR: u32 = 1 << 14;
 const HBA_PORT_CMD_FRE: u32 = 1 << 4;
 const HBA_PORT_CMD_ST: u32 = 1;
 const HBA_PORT_IS_ERR: u32 = 1 << 30 | 1 << 29 | 1 << 28 | 1 << 27;
+const HBA_PORT_IS_DHRS: u32 = 1 << 0;
+const HBA_PORT_IS_PSS: u32 = 1 << 1;
+const HBA_PORT_IS_DSS: u32 = 1 << 2;
+const HBA_PORT_IS_SDBS: u32 = 1 << 3;
+/// Interrupts enabled on a port: command completion FISes and task file errors
+const HBA_PORT_IE_DEFAULT: u32 = HBA_PORT_IS_DHRS | HBA_PORT_IS_PSS | HBA_PORT_IS_DSS |
+                                 HBA_PORT_IS_SDBS | HBA_PORT_IS_ERR;
+const HBA_GHC_AE: u32 = 1 << 31;
+const HBA_GHC_IE: u32 = 1 << 1;
 const HBA_SSTS_PRESENT: u32 = 0x3;
 const HBA_SIG_ATA: u32 = 0x00000101;
 const HBA_SIG_ATAPI: u32 = 0xEB140101;
@@ -38,6 +49,34 @@ pub enum HbaPortType {
     SEMB,
 }
 
+/// Interrupt state of a port: the status bits accumulated by the interrupt handler, consumed by
+/// the command waiting on the port, and a completion the handler signals
+pub struct PortIrq {
+    pub status: AtomicUsize,
+    pub completion: Completion,
+}
+
+impl PortIrq {
+    pub fn new() -> PortIrq {
+        PortIrq {
+            status: AtomicUsize::new(0),
+            completion: Completion::new(),
+        }
+    }
+
+    /// Record interrupt status `is` and wake the waiting command. Called by the interrupt handler.
+    pub fn signal(&self, is: u32) {
+        self.status.fetch_or(is as usize, Ordering::SeqCst);
+        self.completion.complete();
+    }
+
+    /// Forget interrupts from earlier commands
+    pub fn clear(&self) {
+        self.status.store(0, Ordering::SeqCst);
+        self.completion.reset();
+    }
+}
+
 #[repr(packed)]
 pub struct HbaPort {
     pub clb: [Mmio<u32>; 2], // 0x00, command list base address, 1K-byte aligned
@@ -77,8 +116,7 @@ impl HbaPort {
 
     pub fn start(&mut self) {
         while self.cmd.readf(HBA_PORT_CMD_CR) {
-            // TODO: actually yield
-            //thread::yield_now();
+            scheduler::yield_now();
         }
 
         self.cmd.writef(HBA_PORT_CMD_FRE | HBA_PORT_CMD_ST, true);
@@ -88,8 +126,7 @@ impl HbaPort {
         self.cmd.writef(HBA_PORT_CMD_ST, false);
 
         while self.cmd.readf(HBA_PORT_CMD_FR | HBA_PORT_CMD_CR) {
-            // TODO: actually yield
-            //thread::yield_now();
+            scheduler::yield_now();
         }
 
         self.cmd.writef(HBA_PORT_CMD_FRE, false);
@@ -105,7 +142,31 @@ impl HbaPort {
         None
     }
 
-    pub fn init(&mut self, clb: &mut Dma<[HbaCmdHeader; 32]>, ctbas: &mut [Dma<HbaCmdTable>; 32], fb: &mut Dma<[u8; 256]>) {
+    /// Wait for the command in `slot` to complete. With `irq`, the thread sleeps until the port's
+    /// interrupt arrives instead of polling. Returns the interrupt status seen, for error checking.
+    fn wait(&mut self, slot: u32, irq: Option<&PortIrq>) -> u32 {
+        if let Some(irq) = irq {
+            loop {
+                let is = irq.status.load(Ordering::SeqCst) as u32;
+                let busy = self.ci.readf(1 << slot) || self.tfd.readf(ATA_DEV_BUSY as u32);
+                if !busy || is & HBA_PORT_IS_ERR != 0 {
+                    return irq.status.swap(0, Ordering::SeqCst) as u32 | self.is.read();
+                }
+                // an interrupt between the check and here leaves the completion signalled, and
+                // one after the reset is caught by the next check
+                irq.completion.wait();
+                irq.completion.reset();
+            }
+        } else {
+            while (self.ci.readf(1 << slot) || self.tfd.readf(0x80)) && self.is.read() & HBA_PORT_IS_ERR == 0 {
+                scheduler::yield_now();
+            }
+            self.is.read()
+        }
+    }
+
+    pub fn init(&mut self, clb: &mut Dma<[HbaCmdHeader; 32]>, ctbas: &mut [Dma<HbaCmdTable>; 32],
+                fb: &mut Dma<[u8; 256]>, interrupts: bool) {
         self.stop();
 
         for i in 0..32 {
@@ -131,13 +192,21 @@ impl HbaPort {
         // Power on and spin up device
         self.cmd.writef(1 << 2 | 1 << 1, true);
 
+        if interrupts {
+            self.ie.write(HBA_PORT_IE_DEFAULT);
+        }
+
         println!("   - AHCI init {:X}", self.cmd.read());
     }
 
     pub unsafe fn identify(&mut self, dma_alloc: &DmaAllocator,
                            clb: &mut Dma<[HbaCmdHeader; 32]>,
-                           ctbas: &mut [Dma<HbaCmdTable>; 32]) -> Option<u64> {
+                           ctbas: &mut [Dma<HbaCmdTable>; 32],
+                           irq: Option<&PortIrq>) -> Option<u64> {
         self.is.write(u32::MAX);
+        if let Some(irq) = irq {
+            irq.clear();
+        }
 
         let dest: Dma<[u16; 256]> = dma_alloc.allocate([0; 256]).unwrap();
 
@@ -167,23 +236,19 @@ impl HbaPort {
             }
 
             while self.tfd.readf((ATA_DEV_BUSY | ATA_DEV_DRQ) as u32) {
-                // TODO: actually yield
-                //thread::yield_now();
+                scheduler::yield_now();
             }
 
             self.ci.writef(1 << slot, true);
 
             self.start();
 
-            while (self.ci.readf(1 << slot) || self.tfd.readf(0x80)) && self.is.read() & HBA_PORT_IS_ERR == 0 {
-                // TODO: actually yield
-                //thread::yield_now();
-            }
+            let is = self.wait(slot, irq);
 
             self.stop();
 
-            if self.is.read() & HBA_PORT_IS_ERR != 0 {
-                println!("ERROR IS {:X} TFD {:X} SERR {:X}", self.is.read(), self.tfd.read(), self.serr.read());
+            if is & HBA_PORT_IS_ERR != 0 {
+                println!("ERROR IS {:X} TFD {:X} SERR {:X}", is, self.tfd.read(), self.serr.read());
                 return None;
             }
 
@@ -249,7 +314,7 @@ impl HbaPort {
 
     pub fn ata_dma(&mut self, block: u64, sectors: usize, write: bool,
                    clb: &mut Dma<[HbaCmdHeader; 32]>, ctbas: &mut [Dma<HbaCmdTable>; 32],
-                   buf: &mut Dma<[u8; 256 * 512]>) -> Result<usize> {
+                   buf: &mut Dma<[u8; 256 * 512]>, irq: Option<&PortIrq>) -> Result<usize> {
         if write {
             //print!("{}", format!("AHCI {:X} DMA BLOCK: {:X} SECTORS: {} WRITE: {}\n", (self as *mut HbaPort) as usize, block, sectors, write));
         }
@@ -257,6 +322,9 @@ impl HbaPort {
         assert!(sectors > 0 && sectors < 256);
 
         self.is.write(u32::MAX);
+        if let Some(irq) = irq {
+            irq.clear();
+        }
 
         if let Some(slot) = self.slot() {
             if write {
@@ -307,8 +375,7 @@ impl HbaPort {
                 //print!("WAIT ATA_DEV_BUSY | ATA_DEV_DRQ\n");
             }
             while self.tfd.readf((ATA_DEV_BUSY | ATA_DEV_DRQ) as u32) {
-                // TODO: actually yield
-                //thread::yield_now();
+                scheduler::yield_now();
             }
 
             if write {
@@ -321,19 +388,13 @@ impl HbaPort {
             if write {
                 //print!("{}", format!("WAIT CI {:X} in {:X}\n", 1 << slot, self.ci.read()));
             }
-            while (self.ci.readf(1 << slot) || self.tfd.readf(0x80)) && self.is.read() & HBA_PORT_IS_ERR == 0 {
-                // TODO: actually yield
-                //thread::yield_now();
-                if write {
-                    //print!("{}", format!("WAIT CI {:X} TFD {:X} IS {:X} CMD {:X} SERR {:X}\n", self.ci.read(), self.tfd.read(), self.is.read(), self.cmd.read(), self.serr.read()));
-                }
-            }
+            let is = self.wait(slot, irq);
 
             self.stop();
 
-            if self.is.read() & HBA_PORT_IS_ERR != 0 {
+            if is & HBA_PORT_IS_ERR != 0 {
                 println!("ERROR IS {:X} IE {:X} CMD {:X} TFD {:X}\nSSTS {:X} SCTL {:X} SERR {:X} SACT {:X}\nCI {:X} SNTF {:X} FBS {:X}",
-                        self.is.read(), self.ie.read(), self.cmd.read(), self.tfd.read(),
+                        is, self.ie.read(), self.cmd.read(), self.tfd.read(),
                         self.ssts.read(), self.sctl.read(), self.serr.read(), self.sact.read(),
                         self.ci.read(), self.sntf.read(), self.fbs.read());
                 self.is.write(u32::MAX);
@@ -377,14 +438,35 @@ impl HbaMem {
             pause();
         }
         */
-        // Report that OS is aware of AHCI device (32nd bit) and
-        // obtain ownership of the device (2nd bit)
-        self.ghc.write(1 << 31 | 1 << 1);
+        // Report that OS is aware of AHCI device (32nd bit), leaving the interrupt enable alone
+        self.ghc.writef(HBA_GHC_AE, true);
 
         println!("   - AHCI CAP {:X} GHC {:X} IS {:X} PI {:X} VS {:X} CAP2 {:X} BOHC {:X}",
                  self.cap.read(), self.ghc.read(), self.is.read(), self.pi.read(),
                  self.vs.read(), self.cap2.read(), self.bohc.read());
     }
+
+    /// Let the HBA raise interrupts for ports that have them enabled
+    pub fn enable_interrupts(&mut self) {
+        let is = self.is.read();
+        self.is.write(is);
+        self.ghc.writef(HBA_GHC_IE, true);
+    }
+
+    /// Acknowledge a pending interrupt. Calls `f` with the index and interrupt status of every
+    /// port that has one pending, after clearing it on the port.
+    pub fn acknowledge<F>(&mut self, mut f: F) where F: FnMut(usize, u32) {
+        let is = self.is.read();
+        for i in 0..32 {
+            if is & 1 << i != 0 {
+                let port_is = self.ports[i].is.read();
+                self.ports[i].is.write(port_is);
+                f(i, port_is);
+            }
+        }
+        // the global status can only be cleared once the port status is
+        self.is.write(is);
+    }
 }
 
 #[repr(packed)]
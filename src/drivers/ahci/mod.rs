@@ -1,8 +1,10 @@
 use collections::{String, Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 pub use self::disk::Disk;
-use self::hba::{HbaMem, HbaPortType};
+use self::hba::{HbaMem, HbaPortType, PortIrq};
 
+use ::interrupts;
 use ::memory;
 use ::drivers::pci::{PciBar, PciFunc, PciHeader};
 use ::syscall::io::{DmaAllocator, Io};
@@ -11,6 +13,14 @@ mod disk;
 mod fis;
 mod hba;
 
+/// Virtual address of the HBA registers, for the interrupt handler. 0 until initialized.
+static HBA_BASE: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    /// Interrupt state of each port, signalled by the interrupt handler
+    static ref PORT_IRQ: Vec<PortIrq> = (0..32).map(|_| PortIrq::new()).collect();
+}
+
 pub fn init(pci_func: PciFunc, pci_header: PciHeader) {
     println!("Starting AHCI driver");
 
@@ -22,8 +32,15 @@ pub fn init(pci_func: PciFunc, pci_header: PciHeader) {
             return;
         }
     };
-    let bar_virt = ::syscall::map_pm(bar, 4096, memory::WRITABLE).unwrap();
-    let mut disks = disks(&dma_alloc, bar_virt);
+    let bar_virt = ::syscall::map_pm(bar, 8192, memory::WRITABLE | memory::NO_CACHE).unwrap();
+    HBA_BASE.store(bar_virt, Ordering::SeqCst);
+
+    let interrupts = enable_interrupts(&pci_func, &pci_header);
+    if !interrupts {
+        println!("   - AHCI falling back to polling");
+    }
+
+    let mut disks = disks(&dma_alloc, bar_virt, interrupts);
 
     let mut buf = [0u8; 512];
 
@@ -32,9 +49,46 @@ pub fn init(pci_func: PciFunc, pci_header: PciHeader) {
     println!("{:?}", msg);
 }
 
-pub fn disks<'a>(dma_alloc: &'a DmaAllocator, base: usize) -> Vec<Disk<'a>> {
+/// Route the HBA's interrupt to `irq_handler`, preferring MSI over the legacy pin. Returns false
+/// if neither is available.
+fn enable_interrupts(pci_func: &PciFunc, pci_header: &PciHeader) -> bool {
+    if let Some(msi) = pci_func.msi() {
+        if let Some(vector) = interrupts::allocate_vector(irq_handler) {
+            msi.enable(vector, interrupts::local_apic().id());
+            println!("   - AHCI using MSI vector {:#x}", vector);
+            return true;
+        }
+    }
+
+    // 0xFF means the line isn't connected
+    let line = pci_header.interrupt_line;
+    if pci_header.interrupt_pin != 0 && line < 16 {
+        if let Some(vector) = interrupts::register_legacy(line, irq_handler) {
+            println!("   - AHCI using IRQ {} on vector {:#x}", line, vector);
+            return true;
+        }
+    }
+
+    false
+}
+
+fn irq_handler(_vector: u8) {
+    let base = HBA_BASE.load(Ordering::SeqCst);
+    if base == 0 {
+        return;
+    }
+
+    let hba_mem = unsafe { &mut *(base as *mut HbaMem) };
+    hba_mem.acknowledge(|port, is| PORT_IRQ[port].signal(is));
+}
+
+pub fn disks<'a>(dma_alloc: &'a DmaAllocator, base: usize, interrupts: bool) -> Vec<Disk<'a>> {
     let hba_mem = unsafe { &mut *(base as *mut HbaMem) };
     hba_mem.init();
+    // after `init`, which may reset the HBA, and before the disks wait on their first command
+    if interrupts {
+        hba_mem.enable_interrupts();
+    }
     let pi = hba_mem.pi.read();
     let ret: Vec<Disk> = (0..32)
           .filter(|&i| pi & 1 << i as i32 == 1 << i as i32)
@@ -44,7 +98,8 @@ pub fn disks<'a>(dma_alloc: &'a DmaAllocator, base: usize) -> Vec<Disk<'a>> {
               println!("disk {}: {:?}", i, port_type);
               match port_type {
                   HbaPortType::SATA => {
-                      match Disk::new(dma_alloc, i, port) {
+                      let irq = if interrupts { Some(&PORT_IRQ[i]) } else { None };
+                      match Disk::new(dma_alloc, i, port, irq) {
                           Ok(disk) => Some(disk),
                           Err(err) => {
                               println!("{}: {:?}", i, err);
@@ -0,0 +1,60 @@
+use super::PciFunc;
+use super::msi::{MsiCapability, MsixCapability};
+
+const PCI_STATUS_CAP_LIST: u32 = 1 << 4;
+const PCI_CAP_PTR: u8 = 0x34;
+
+pub const PCI_CAP_ID_MSI: u8 = 0x05;
+pub const PCI_CAP_ID_MSIX: u8 = 0x11;
+
+#[derive(Debug)]
+pub enum PciCapability {
+    Msi(MsiCapability),
+    Msix(MsixCapability),
+    /// Any capability we don't have a driver for, as (id, offset)
+    Other(u8, u8),
+}
+
+/// Walks the capability list in the configuration space of a function
+pub struct PciCapabilityIter {
+    func: PciFunc,
+    offset: u8,
+}
+
+impl PciCapabilityIter {
+    pub fn new(func: PciFunc) -> PciCapabilityIter {
+        // the status register is the upper half of the dword at 0x04
+        let status = unsafe { func.read(0x04) } >> 16;
+        let offset = if status & PCI_STATUS_CAP_LIST != 0 {
+            unsafe { func.read(PCI_CAP_PTR) as u8 & 0xFC }
+        } else {
+            0
+        };
+
+        PciCapabilityIter {
+            func: func,
+            offset: offset,
+        }
+    }
+}
+
+impl Iterator for PciCapabilityIter {
+    type Item = PciCapability;
+
+    fn next(&mut self) -> Option<PciCapability> {
+        if self.offset == 0 {
+            return None;
+        }
+
+        let offset = self.offset;
+        let header = unsafe { self.func.read(offset) };
+        let id = header as u8;
+        self.offset = (header >> 8) as u8 & 0xFC;
+
+        Some(match id {
+            PCI_CAP_ID_MSI => PciCapability::Msi(MsiCapability::new(self.func, offset)),
+            PCI_CAP_ID_MSIX => PciCapability::Msix(MsixCapability::new(self.func, offset)),
+            _ => PciCapability::Other(id, offset),
+        })
+    }
+}
@@ -1,15 +1,20 @@
 use collections::BTreeMap;
 
 pub use self::bar::PciBar;
+pub use self::cap::{PciCapability, PciCapabilityIter};
 pub use self::class::PciClass;
 pub use self::header::PciHeader;
+pub use self::msi::{MsiCapability, MsixCapability, MsixTable};
 
 mod bar;
+mod cap;
 mod class;
 mod header;
+mod msi;
 
 pub type PciDeviceDriver = fn(PciFunc, PciHeader);
 
+#[derive(Clone, Copy, Debug)]
 pub struct PciFunc {
     pub bus: u8,
     pub dev: u8,
@@ -45,6 +50,24 @@ impl PciFunc {
         }
     }
 
+    pub fn capabilities(&self) -> PciCapabilityIter {
+        PciCapabilityIter::new(*self)
+    }
+
+    pub fn msi(&self) -> Option<MsiCapability> {
+        self.capabilities().filter_map(|cap| match cap {
+            PciCapability::Msi(msi) => Some(msi),
+            _ => None,
+        }).next()
+    }
+
+    pub fn msix(&self) -> Option<MsixCapability> {
+        self.capabilities().filter_map(|cap| match cap {
+            PciCapability::Msix(msix) => Some(msix),
+            _ => None,
+        }).next()
+    }
+
     pub unsafe fn read(&self, offset: u8) -> u32 {
         read(self.bus, self.dev, self.func, offset)
     }
@@ -0,0 +1,234 @@
+use core::intrinsics::{volatile_load, volatile_store};
+
+use ::interrupts::msi_message;
+use ::memory::{NO_CACHE, WRITABLE};
+
+use super::{PciBar, PciFunc, PciHeader};
+
+const MSI_CTRL_ENABLE: u32 = 1 << 0;
+const MSI_CTRL_MMC_SHIFT: u32 = 1;
+const MSI_CTRL_MME_SHIFT: u32 = 4;
+const MSI_CTRL_MME_MASK: u32 = 0b111 << MSI_CTRL_MME_SHIFT;
+const MSI_CTRL_64_BIT: u32 = 1 << 7;
+const MSI_CTRL_PER_VECTOR_MASK: u32 = 1 << 8;
+
+const MSIX_CTRL_TABLE_SIZE_MASK: u32 = 0x7FF;
+const MSIX_CTRL_FUNCTION_MASK: u32 = 1 << 14;
+const MSIX_CTRL_ENABLE: u32 = 1 << 15;
+
+const MSIX_ENTRY_MASKED: u32 = 1 << 0;
+
+/// Command register bit that stops the function from asserting its INTx pin
+const PCI_CMD_INTX_DISABLE: u32 = 1 << 10;
+
+/// Read the 16 bit message control register, which shares a dword with the capability header
+unsafe fn read_control(func: &PciFunc, offset: u8) -> u32 {
+    func.read(offset) >> 16
+}
+
+unsafe fn write_control(func: &PciFunc, offset: u8, control: u32) {
+    let header = func.read(offset) & 0xFFFF;
+    func.write(offset, header | control << 16);
+}
+
+unsafe fn disable_intx(func: &PciFunc) {
+    let cmd = func.read(0x04);
+    func.write(0x04, cmd | PCI_CMD_INTX_DISABLE);
+}
+
+/// The MSI capability of a PCI function
+#[derive(Debug)]
+pub struct MsiCapability {
+    func: PciFunc,
+    offset: u8,
+}
+
+impl MsiCapability {
+    pub fn new(func: PciFunc, offset: u8) -> MsiCapability {
+        MsiCapability {
+            func: func,
+            offset: offset,
+        }
+    }
+
+    fn control(&self) -> u32 {
+        unsafe { read_control(&self.func, self.offset) }
+    }
+
+    fn is_64_bit(&self) -> bool {
+        self.control() & MSI_CTRL_64_BIT != 0
+    }
+
+    fn data_offset(&self) -> u8 {
+        if self.is_64_bit() { self.offset + 0x0C } else { self.offset + 0x08 }
+    }
+
+    fn mask_offset(&self) -> u8 {
+        if self.is_64_bit() { self.offset + 0x10 } else { self.offset + 0x0C }
+    }
+
+    /// The number of vectors the function can request
+    pub fn vectors(&self) -> usize {
+        1 << ((self.control() >> MSI_CTRL_MMC_SHIFT) & 0b111)
+    }
+
+    pub fn has_per_vector_masking(&self) -> bool {
+        self.control() & MSI_CTRL_PER_VECTOR_MASK != 0
+    }
+
+    /// Route the function's interrupt to `vector` on the local APIC `apic_id`, and stop it from
+    /// using its INTx pin. Only a single message is enabled.
+    pub fn enable(&self, vector: u8, apic_id: u8) {
+        let (address, data) = msi_message(vector, apic_id);
+
+        unsafe {
+            self.func.write(self.offset + 0x04, address as u32);
+            if self.is_64_bit() {
+                self.func.write(self.offset + 0x08, (address >> 32) as u32);
+            }
+            // the upper half of the data dword is reserved
+            let data_offset = self.data_offset();
+            let reserved = self.func.read(data_offset) & 0xFFFF_0000;
+            self.func.write(data_offset, reserved | data);
+
+            let control = self.control() & !MSI_CTRL_MME_MASK;
+            write_control(&self.func, self.offset, control | MSI_CTRL_ENABLE);
+
+            disable_intx(&self.func);
+        }
+    }
+
+    pub fn disable(&self) {
+        unsafe {
+            let control = self.control();
+            write_control(&self.func, self.offset, control & !MSI_CTRL_ENABLE);
+        }
+    }
+
+    /// Mask or unmask message `index`. Returns false if the function doesn't support masking.
+    pub fn set_masked(&self, index: usize, masked: bool) -> bool {
+        if !self.has_per_vector_masking() || index >= self.vectors() {
+            return false;
+        }
+
+        unsafe {
+            let mask_offset = self.mask_offset();
+            let mask = self.func.read(mask_offset);
+            let mask = if masked { mask | 1 << index } else { mask & !(1 << index) };
+            self.func.write(mask_offset, mask);
+        }
+        true
+    }
+}
+
+/// The MSI-X capability of a PCI function
+#[derive(Debug)]
+pub struct MsixCapability {
+    func: PciFunc,
+    offset: u8,
+}
+
+impl MsixCapability {
+    pub fn new(func: PciFunc, offset: u8) -> MsixCapability {
+        MsixCapability {
+            func: func,
+            offset: offset,
+        }
+    }
+
+    fn control(&self) -> u32 {
+        unsafe { read_control(&self.func, self.offset) }
+    }
+
+    /// The number of entries in the vector table
+    pub fn table_size(&self) -> usize {
+        (self.control() & MSIX_CTRL_TABLE_SIZE_MASK) as usize + 1
+    }
+
+    /// Map the vector table, which lives in one of the function's memory BARs. Every entry
+    /// starts out masked.
+    pub fn map_table(&self, header: &PciHeader) -> Option<MsixTable> {
+        let table = unsafe { self.func.read(self.offset + 0x04) };
+        let bir = (table & 0b111) as usize;
+        let table_offset = (table & !0b111) as usize;
+
+        let bar = match header.bar(bir) {
+            PciBar::Memory(bar) => bar as usize,
+            _ => return None,
+        };
+
+        let size = self.table_size() * 16;
+        let page_offset = (bar + table_offset) % 4096;
+        ::syscall::map_pm(bar + table_offset - page_offset, page_offset + size,
+                          WRITABLE | NO_CACHE)
+            .ok()
+            .map(|virt| {
+                let mut table = MsixTable {
+                    base: virt + page_offset,
+                    len: self.table_size(),
+                };
+                for i in 0..table.len {
+                    table.set_masked(i, true);
+                }
+                table
+            })
+    }
+
+    /// Turn on MSI-X, and stop the function from using its INTx pin
+    pub fn enable(&self) {
+        unsafe {
+            let control = self.control() & !MSIX_CTRL_FUNCTION_MASK;
+            write_control(&self.func, self.offset, control | MSIX_CTRL_ENABLE);
+            disable_intx(&self.func);
+        }
+    }
+
+    pub fn disable(&self) {
+        unsafe {
+            let control = self.control();
+            write_control(&self.func, self.offset, control & !MSIX_CTRL_ENABLE);
+        }
+    }
+}
+
+/// The memory mapped MSI-X vector table of a function
+pub struct MsixTable {
+    base: usize,
+    len: usize,
+}
+
+impl MsixTable {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    fn entry(&self, index: usize) -> *mut u32 {
+        assert!(index < self.len, "MSI-X entry {} out of range", index);
+        (self.base + index * 16) as *mut u32
+    }
+
+    /// Route entry `index` to `vector` on the local APIC `apic_id`. The entry's mask is left as
+    /// it was.
+    pub fn set_vector(&mut self, index: usize, vector: u8, apic_id: u8) {
+        let (address, data) = msi_message(vector, apic_id);
+        let entry = self.entry(index);
+        unsafe {
+            volatile_store(entry, address as u32);
+            volatile_store(entry.offset(1), (address >> 32) as u32);
+            volatile_store(entry.offset(2), data);
+        }
+    }
+
+    pub fn set_masked(&mut self, index: usize, masked: bool) {
+        let entry = self.entry(index);
+        unsafe {
+            let control = volatile_load(entry.offset(3));
+            let control = if masked {
+                control | MSIX_ENTRY_MASKED
+            } else {
+                control & !MSIX_ENTRY_MASKED
+            };
+            volatile_store(entry.offset(3), control);
+        }
+    }
+}
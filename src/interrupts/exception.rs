@@ -0,0 +1,111 @@
+// Exceptions that user code can raise. They come in through the stubs in interrupt_entry.asm, which
+// save every register. In user mode, each one becomes a signal to the faulting process, whose
+// handler may take over the thread. In the kernel they're bugs, and stop the CPU.
+
+use x86_64::registers::control_regs;
+use x86_64::structures::idt::{PageFaultErrorCode, CAUSED_BY_WRITE, INSTRUCTION_FETCH,
+                              PROTECTION_VIOLATION};
+
+use process::signal::{self, SIGBUS, SIGFPE, SIGILL, SIGSEGV, SIGTRAP};
+use process::vm::{PROT_EXEC, PROT_READ, PROT_WRITE};
+use super::SyscallFrame;
+
+extern "C" {
+    // Not `extern "x86-interrupt"` functions, but they have to be installed in the IDT as such
+    pub fn divide_error_entry();
+    pub fn debug_entry();
+    pub fn invalid_opcode_entry();
+    pub fn segment_not_present_entry();
+    pub fn stack_segment_entry();
+    pub fn general_protection_entry();
+    pub fn page_fault_entry();
+    pub fn x87_floating_point_entry();
+    pub fn alignment_check_entry();
+    pub fn simd_floating_point_entry();
+}
+
+#[no_mangle]
+pub extern "C" fn divide_error_handler(frame: &mut SyscallFrame, error_code: usize) {
+    fault(frame, error_code, SIGFPE, "DIVIDE ERROR");
+}
+
+/// Single stepping with the trap flag, which user code can set with `popf`. The syscall entry masks
+/// it, so the kernel itself never steps.
+#[no_mangle]
+pub extern "C" fn debug_handler(frame: &mut SyscallFrame, error_code: usize) {
+    fault(frame, error_code, SIGTRAP, "DEBUG");
+}
+
+#[no_mangle]
+pub extern "C" fn invalid_opcode_handler(frame: &mut SyscallFrame, error_code: usize) {
+    fault(frame, error_code, SIGILL, "INVALID OPCODE");
+}
+
+#[no_mangle]
+pub extern "C" fn segment_not_present_handler(frame: &mut SyscallFrame, error_code: usize) {
+    fault(frame, error_code, SIGBUS, "SEGMENT NOT PRESENT");
+}
+
+/// E.g. a push with a non-canonical stack pointer
+#[no_mangle]
+pub extern "C" fn stack_segment_handler(frame: &mut SyscallFrame, error_code: usize) {
+    fault(frame, error_code, SIGBUS, "STACK SEGMENT FAULT");
+}
+
+#[no_mangle]
+pub extern "C" fn general_protection_handler(frame: &mut SyscallFrame, error_code: usize) {
+    fault(frame, error_code, SIGSEGV, "GENERAL PROTECTION FAULT");
+}
+
+#[no_mangle]
+pub extern "C" fn page_fault_handler(frame: &mut SyscallFrame, error_code: usize) {
+    let error_code = PageFaultErrorCode::from_bits_truncate(error_code as u64);
+
+    // the first touch of a lazily mapped user page
+    let address = control_regs::cr2().0;
+    if address < 0x0000_8000_0000_0000 && !error_code.contains(PROTECTION_VIOLATION) {
+        let access = if error_code.contains(CAUSED_BY_WRITE) {
+            PROT_WRITE
+        } else if error_code.contains(INSTRUCTION_FETCH) {
+            PROT_EXEC
+        } else {
+            PROT_READ
+        };
+        if ::process::handle_page_fault(address, access) {
+            return;
+        }
+    }
+
+    if frame.cs & 3 != 0 {
+        signal::force(frame, SIGSEGV);
+        return;
+    }
+    println!("EXCEPTION: PAGE FAULT at {:#x}\nerror code: {:?}\n{:#?}", address, error_code, frame);
+    loop {}
+}
+
+#[no_mangle]
+pub extern "C" fn x87_floating_point_handler(frame: &mut SyscallFrame, error_code: usize) {
+    fault(frame, error_code, SIGFPE, "x87 FLOATING POINT");
+}
+
+#[no_mangle]
+pub extern "C" fn alignment_check_handler(frame: &mut SyscallFrame, error_code: usize) {
+    fault(frame, error_code, SIGBUS, "ALIGNMENT CHECK");
+}
+
+/// An unmasked SSE exception, as CR4.OSXMMEXCPT is set
+#[no_mangle]
+pub extern "C" fn simd_floating_point_handler(frame: &mut SyscallFrame, error_code: usize) {
+    fault(frame, error_code, SIGFPE, "SIMD FLOATING POINT");
+}
+
+fn fault(frame: &mut SyscallFrame, error_code: usize, signal: usize, name: &str) {
+    if frame.cs & 3 != 0 {
+        signal::force(frame, signal);
+        return;
+    }
+    println!("\nEXCEPTION: {} at {:#x}\nerror code: {:#x}\n{:#?}", name, frame.rip, error_code,
+             frame);
+    loop {}
+}
@@ -2,6 +2,17 @@ use x86_64::PrivilegeLevel;
 use x86_64::structures::gdt::SegmentSelector;
 use x86_64::structures::tss::TaskStateSegment;
 
+// The order of these entries is fixed: SYSCALL/SYSRET derive the kernel and user selectors from
+// the STAR MSR, which requires kernel data to follow kernel code, and user code to follow user
+// data.
+pub const GDT_NULL: u16 = 0;
+pub const GDT_KERNEL_CODE: u16 = 1;
+pub const GDT_KERNEL_DATA: u16 = 2;
+pub const GDT_USER_DATA: u16 = 3;
+pub const GDT_USER_CODE: u16 = 4;
+pub const GDT_USER_TLS: u16 = 5;
+pub const GDT_TSS: u16 = 6;
+
 pub struct Gdt {
     table: [u64; 8],
     next_free: usize,
@@ -29,15 +40,22 @@ impl Gdt {
     }
 
     pub fn add_entry(&mut self, entry: Descriptor) -> SegmentSelector {
-        let index = match entry {
-            Descriptor::UserSegment(value) => self.push(value),
+        let (index, rpl) = match entry {
+            Descriptor::UserSegment(value) => {
+                let rpl = if value & DPL_RING_3.bits() == DPL_RING_3.bits() {
+                    PrivilegeLevel::Ring3
+                } else {
+                    PrivilegeLevel::Ring0
+                };
+                (self.push(value), rpl)
+            }
             Descriptor::SystemSegment(value_low, value_high) => {
                 let index = self.push(value_low);
                 self.push(value_high);
-                index
+                (index, PrivilegeLevel::Ring0)
             }
         };
-        SegmentSelector::new(index as u16, PrivilegeLevel::Ring0)
+        SegmentSelector::new(index as u16, rpl)
     }
 
     fn push(&mut self, value: u64) -> usize {
@@ -59,9 +77,11 @@ pub enum Descriptor {
 
 bitflags! {
     flags DescriptorFlags: u64 {
+        const WRITABLE          = 1 << 41,
         const CONFORMING        = 1 << 42,
         const EXECUTABLE        = 1 << 43,
         const USER_SEGMENT      = 1 << 44,
+        const DPL_RING_3        = 3 << 45,
         const PRESENT           = 1 << 47,
         const LONG_MODE         = 1 << 53,
     }
@@ -73,6 +93,21 @@ impl Descriptor {
         Descriptor::UserSegment(flags.bits())
     }
 
+    pub fn kernel_data_segment() -> Descriptor {
+        let flags = USER_SEGMENT | PRESENT | WRITABLE;
+        Descriptor::UserSegment(flags.bits())
+    }
+
+    pub fn user_code_segment() -> Descriptor {
+        let flags = USER_SEGMENT | PRESENT | EXECUTABLE | LONG_MODE | DPL_RING_3;
+        Descriptor::UserSegment(flags.bits())
+    }
+
+    pub fn user_data_segment() -> Descriptor {
+        let flags = USER_SEGMENT | PRESENT | WRITABLE | DPL_RING_3;
+        Descriptor::UserSegment(flags.bits())
+    }
+
     pub fn tss_segment(tss: &'static TaskStateSegment) -> Descriptor {
         use core::mem::size_of;
         use bit_field::BitField;
@@ -0,0 +1,24 @@
+// Interprocessor interrupts, for making the other CPUs do something
+
+use x86_64::structures::idt::ExceptionStackFrame;
+
+use percpu::KernelGs;
+use super::{irq, local_apic};
+
+/// Asks the CPU to carry out the pending TLB shootdown
+pub const TLB_SHOOTDOWN_VECTOR: u8 = 0xF0;
+
+/// Raise `vector` on every other CPU. Does nothing until the application processors are up.
+pub fn send_all_but_self(vector: u8) {
+    if ::smp::cpu_count() > 1 {
+        local_apic::local_apic().send_all_but_self(vector);
+    }
+}
+
+pub extern "x86-interrupt" fn tlb_shootdown_handler(stack_frame: &mut ExceptionStackFrame) {
+    let _gs = KernelGs::enter(stack_frame);
+    irq::enter();
+    ::memory::handle_shootdown();
+    local_apic::local_apic().eoi();
+    irq::exit();
+}
@@ -0,0 +1,181 @@
+// Dynamically allocated device interrupt vectors
+
+use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use irq_lock::SpinMutex;
+use x86_64::structures::idt::{ExceptionStackFrame, Idt};
+
+use percpu::KernelGs;
+use super::{local_apic, pic, softirq};
+
+pub type IrqHandler = fn(u8);
+
+/// First vector handed out to device drivers
+pub const IRQ_VECTOR_START: u8 = 0x40;
+/// Number of vectors handed out to device drivers
+pub const IRQ_VECTOR_COUNT: usize = 32;
+
+/// Handlers indexed by `vector - IRQ_VECTOR_START`, stored as plain addresses so interrupt
+/// handlers can read them without taking a lock. 0 means no handler.
+static HANDLERS: [AtomicUsize; IRQ_VECTOR_COUNT] = [
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+];
+
+/// Handlers for the 16 legacy PIC lines, indexed by line
+static LEGACY_HANDLERS: [AtomicUsize; 16] = [
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+];
+
+/// Bitmap of vectors that have been handed out
+static ALLOCATED: SpinMutex<u32> = SpinMutex::named(lock_class!("irq::ALLOCATED"), 0);
+
+percpu! {
+    /// How many interrupt handlers are currently running on this CPU
+    static DEPTH: AtomicUsize = AtomicUsize::new(0);
+}
+
+/// Whether the CPU is running an interrupt handler
+pub fn in_interrupt() -> bool {
+    DEPTH.get().load(Ordering::SeqCst) != 0
+}
+
+/// Bookkeeping for interrupt entry. Every call must be paired with `exit`.
+pub fn enter() {
+    DEPTH.get().fetch_add(1, Ordering::SeqCst);
+}
+
+/// Bookkeeping for interrupt exit, after the interrupt has been acknowledged. Leaving the
+/// outermost handler runs pending softirqs, and switches threads if the scheduler asked for it.
+pub fn exit() {
+    // softirqs run in interrupt context too, so the interrupts that nest inside them neither
+    // run softirqs nor switch threads on their way out
+    if DEPTH.get().load(Ordering::SeqCst) == 1 {
+        softirq::run_pending();
+    }
+    if DEPTH.get().fetch_sub(1, Ordering::SeqCst) == 1 {
+        ::scheduler::preempt();
+    }
+}
+
+/// Reserve a vector and route it to `handler`. The handler runs in interrupt context with
+/// interrupts disabled, and the local APIC is acknowledged after it returns.
+pub fn allocate_vector(handler: IrqHandler) -> Option<u8> {
+    let mut allocated = ALLOCATED.lock();
+    for i in 0..IRQ_VECTOR_COUNT {
+        if *allocated & 1 << i == 0 {
+            *allocated |= 1 << i;
+            HANDLERS[i].store(handler as usize, Ordering::SeqCst);
+            return Some(IRQ_VECTOR_START + i as u8);
+        }
+    }
+    None
+}
+
+/// Release a vector obtained from `allocate_vector`. The device must no longer raise it.
+pub fn free_vector(vector: u8) {
+    let i = (vector - IRQ_VECTOR_START) as usize;
+    let mut allocated = ALLOCATED.lock();
+    assert!(*allocated & 1 << i != 0, "vector {:#x} was not allocated", vector);
+    HANDLERS[i].store(0, Ordering::SeqCst);
+    *allocated &= !(1 << i);
+}
+
+/// Route the legacy PIC `line` to `handler` and unmask it. Returns the vector the line is
+/// delivered on, or `None` if the line already has a handler. Only meant for devices that can't
+/// use MSIs.
+pub fn register_legacy(line: u8, handler: IrqHandler) -> Option<u8> {
+    let slot = &LEGACY_HANDLERS[line as usize];
+    if slot.compare_and_swap(0, handler as usize, Ordering::SeqCst) == 0 {
+        pic::unmask(line);
+        Some(pic::PIC_VECTOR_OFFSET + line)
+    } else {
+        None
+    }
+}
+
+fn handle_legacy(line: u8) {
+    enter();
+    let handler = LEGACY_HANDLERS[line as usize].load(Ordering::SeqCst);
+    if handler != 0 {
+        let handler: IrqHandler = unsafe { mem::transmute(handler) };
+        handler(pic::PIC_VECTOR_OFFSET + line);
+    }
+    pic::eoi(line);
+    exit();
+}
+
+fn handle(vector: u8) {
+    enter();
+    let handler = HANDLERS[(vector - IRQ_VECTOR_START) as usize].load(Ordering::SeqCst);
+    if handler != 0 {
+        let handler: IrqHandler = unsafe { mem::transmute(handler) };
+        handler(vector);
+    } else {
+        println!("unexpected interrupt on vector {:#x}", vector);
+    }
+    local_apic::local_apic().eoi();
+    exit();
+}
+
+extern "x86-interrupt" fn spurious_handler(_: &mut ExceptionStackFrame) {
+    // spurious interrupts are not acknowledged
+}
+
+// `extern "x86-interrupt"` handlers have no way of finding out which vector they were invoked
+// through, so every vector gets a stub of its own.
+macro_rules! irq_stubs {
+    ($($legacy_name:ident = $line:expr,)*; $($name:ident = $vector:expr,)*) => {
+        $(
+            extern "x86-interrupt" fn $legacy_name(stack_frame: &mut ExceptionStackFrame) {
+                let _gs = KernelGs::enter(stack_frame);
+                handle_legacy($line);
+            }
+        )*
+
+        $(
+            extern "x86-interrupt" fn $name(stack_frame: &mut ExceptionStackFrame) {
+                let _gs = KernelGs::enter(stack_frame);
+                handle($vector);
+            }
+        )*
+
+        pub fn install(idt: &mut Idt) {
+            $(
+                idt.interrupts[pic::PIC_VECTOR_OFFSET as usize + $line - 32]
+                    .set_handler_fn($legacy_name);
+            )*
+            $(
+                idt.interrupts[$vector - 32].set_handler_fn($name);
+            )*
+            idt.interrupts[local_apic::SPURIOUS_VECTOR as usize - 32]
+                .set_handler_fn(spurious_handler);
+        }
+    }
+}
+
+irq_stubs! {
+    legacy_0 = 0, legacy_1 = 1, legacy_2 = 2, legacy_3 = 3,
+    legacy_4 = 4, legacy_5 = 5, legacy_6 = 6, legacy_7 = 7,
+    legacy_8 = 8, legacy_9 = 9, legacy_10 = 10, legacy_11 = 11,
+    legacy_12 = 12, legacy_13 = 13, legacy_14 = 14, legacy_15 = 15,
+    ;
+    irq_40 = 0x40, irq_41 = 0x41, irq_42 = 0x42, irq_43 = 0x43,
+    irq_44 = 0x44, irq_45 = 0x45, irq_46 = 0x46, irq_47 = 0x47,
+    irq_48 = 0x48, irq_49 = 0x49, irq_4a = 0x4a, irq_4b = 0x4b,
+    irq_4c = 0x4c, irq_4d = 0x4d, irq_4e = 0x4e, irq_4f = 0x4f,
+    irq_50 = 0x50, irq_51 = 0x51, irq_52 = 0x52, irq_53 = 0x53,
+    irq_54 = 0x54, irq_55 = 0x55, irq_56 = 0x56, irq_57 = 0x57,
+    irq_58 = 0x58, irq_59 = 0x59, irq_5a = 0x5a, irq_5b = 0x5b,
+    irq_5c = 0x5c, irq_5d = 0x5d, irq_5e = 0x5e, irq_5f = 0x5f,
+}
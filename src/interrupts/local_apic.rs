@@ -0,0 +1,175 @@
+use core::intrinsics::{volatile_load, volatile_store};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::Once;
+use x86_64::registers::msr::{rdmsr, IA32_APIC_BASE};
+
+use memory::{MemoryController, NO_CACHE, WRITABLE};
+use syscall::io::{Io, Pio};
+use super::without_interrupts;
+
+/// Vector for spurious interrupts, which must not be acknowledged
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+const REG_ID: usize = 0x20;
+const REG_EOI: usize = 0xB0;
+const REG_SVR: usize = 0xF0;
+const REG_ICR_LOW: usize = 0x300;
+const REG_ICR_HIGH: usize = 0x310;
+const REG_LVT_TIMER: usize = 0x320;
+const REG_TIMER_INITIAL: usize = 0x380;
+const REG_TIMER_CURRENT: usize = 0x390;
+const REG_TIMER_DIVIDE: usize = 0x3E0;
+
+const SVR_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+/// Frequency of the PIT's input clock, in Hz
+const PIT_FREQUENCY: u32 = 1193182;
+
+/// Base of the MSI message address window for the local APICs
+const MSI_ADDRESS_BASE: u64 = 0xFEE0_0000;
+
+static LOCAL_APIC: Once<LocalApic> = Once::new();
+
+/// Timer ticks per millisecond, measured once by the boot processor. All local APIC timers run
+/// off the same bus clock, so the other processors don't have to compete for the PIT.
+static TIMER_TICKS_PER_MS: AtomicUsize = AtomicUsize::new(0);
+
+pub struct LocalApic {
+    base: usize,
+}
+
+impl LocalApic {
+    unsafe fn read(&self, reg: usize) -> u32 {
+        volatile_load((self.base + reg) as *const u32)
+    }
+
+    unsafe fn write(&self, reg: usize, value: u32) {
+        volatile_store((self.base + reg) as *mut u32, value);
+    }
+
+    pub fn id(&self) -> u8 {
+        unsafe { (self.read(REG_ID) >> 24) as u8 }
+    }
+
+    pub fn eoi(&self) {
+        unsafe { self.write(REG_EOI, 0) };
+    }
+
+    /// Measure how many timer ticks pass in a millisecond, using PIT channel 2 as the reference
+    fn calibrate_timer(&self) -> u32 {
+        const CALIBRATION_MS: u32 = 10;
+
+        let mut gate = Pio::<u8>::new(0x61);
+        let mut pit_command = Pio::<u8>::new(0x43);
+        let mut pit_channel_2 = Pio::<u8>::new(0x42);
+
+        // enable the channel 2 gate, but keep the speaker off
+        let value = gate.read();
+        gate.write((value & !0b10) | 0b1);
+
+        // channel 2, lobyte/hibyte access, mode 0 (interrupt on terminal count)
+        pit_command.write(0b1011_0000);
+        let count = PIT_FREQUENCY / (1000 / CALIBRATION_MS);
+        pit_channel_2.write(count as u8);
+        pit_channel_2.write((count >> 8) as u8);
+
+        unsafe {
+            self.write(REG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+            self.write(REG_LVT_TIMER, LVT_MASKED);
+
+            // restart the PIT countdown by toggling the gate, and start the APIC timer with it
+            let value = gate.read() & !0b1;
+            gate.write(value);
+            gate.write(value | 0b1);
+            self.write(REG_TIMER_INITIAL, u32::max_value());
+
+            // the channel 2 output goes high when the count reaches 0
+            while gate.read() & 0x20 == 0 {}
+
+            let elapsed = u32::max_value() - self.read(REG_TIMER_CURRENT);
+            self.write(REG_TIMER_INITIAL, 0);
+            elapsed / CALIBRATION_MS
+        }
+    }
+
+    /// Raise `vector` `frequency` times per second
+    pub fn start_periodic_timer(&self, vector: u8, frequency: u32) {
+        let mut ticks_per_ms = TIMER_TICKS_PER_MS.load(Ordering::SeqCst) as u32;
+        if ticks_per_ms == 0 {
+            ticks_per_ms = self.calibrate_timer();
+            TIMER_TICKS_PER_MS.store(ticks_per_ms as usize, Ordering::SeqCst);
+        }
+        unsafe {
+            self.write(REG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+            self.write(REG_LVT_TIMER, LVT_TIMER_PERIODIC | vector as u32);
+            self.write(REG_TIMER_INITIAL, ticks_per_ms * 1000 / frequency);
+        }
+    }
+
+    /// Send an interprocessor interrupt described by the low half of the ICR to `apic_id`, and
+    /// wait until it's been accepted
+    fn send_ipi(&self, apic_id: u8, command: u32) {
+        without_interrupts(|| unsafe {
+            self.write(REG_ICR_HIGH, (apic_id as u32) << 24);
+            self.write(REG_ICR_LOW, command);
+            while self.read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {}
+        });
+    }
+
+    /// Reset the processor with `apic_id`, leaving it waiting for a startup IPI
+    pub fn send_init(&self, apic_id: u8) {
+        self.send_ipi(apic_id, ICR_INIT | ICR_LEVEL_ASSERT);
+    }
+
+    /// Start the processor with `apic_id` in real mode at physical address `page << 12`
+    pub fn send_startup(&self, apic_id: u8, page: u8) {
+        self.send_ipi(apic_id, ICR_STARTUP | page as u32);
+    }
+
+    /// Raise `vector` on every other processor
+    pub fn send_all_but_self(&self, vector: u8) {
+        self.send_ipi(0, ICR_ALL_EXCLUDING_SELF | ICR_LEVEL_ASSERT | vector as u32);
+    }
+}
+
+/// Map and software-enable the local APIC of the current CPU
+pub fn init(memory_controller: &mut MemoryController) {
+    let apic_base = unsafe { rdmsr(IA32_APIC_BASE) };
+    assert!(apic_base & APIC_BASE_ENABLE != 0, "local APIC is disabled");
+
+    let local_apic = LOCAL_APIC.call_once(|| {
+        let phys = (apic_base & APIC_BASE_ADDRESS_MASK) as usize;
+        let base = memory_controller.map_pm(phys, 4096, WRITABLE | NO_CACHE)
+            .expect("could not map local APIC");
+        LocalApic { base: base }
+    });
+
+    unsafe {
+        local_apic.write(REG_SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+    }
+
+    println!("Local APIC {} at {:#x}", local_apic.id(), local_apic.base);
+}
+
+pub fn local_apic() -> &'static LocalApic {
+    LOCAL_APIC.try().expect("local APIC not initialized")
+}
+
+/// Message address and data that make a PCI device deliver an MSI to `vector` on the CPU with
+/// local APIC `apic_id`. Uses fixed delivery and edge triggering.
+pub fn msi_message(vector: u8, apic_id: u8) -> (u64, u32) {
+    (MSI_ADDRESS_BASE | (apic_id as u64) << 12, vector as u32)
+}
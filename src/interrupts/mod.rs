@@ -1,100 +1,202 @@
+use core::mem;
+
 use spin::Once;
-use x86_64::structures::idt::{ExceptionStackFrame, Idt, PageFaultErrorCode};
+use x86_64::PrivilegeLevel;
+use x86_64::structures::idt::{ExceptionStackFrame, HandlerFunc, Idt};
 use x86_64::structures::tss::TaskStateSegment;
 
 use memory::MemoryController;
-
-mod gdt;
+use percpu::KernelGs;
+
+pub use self::irq::{allocate_vector, free_vector, in_interrupt, register_legacy};
+pub use self::local_apic::{local_apic, msi_message};
+pub use self::syscall::SyscallFrame;
+pub use self::timer::start as start_timer;
+
+mod exception;
+pub mod gdt;
+pub mod ipi;
+mod irq;
+mod local_apic;
+mod pic;
+pub mod softirq;
+mod syscall;
+mod timer;
 
 const DOUBLE_FAULT_IST_INDEX: usize = 0;
 
+/// Size of the stack the kernel runs on after a syscall or an interrupt from user mode
+const KERNEL_STACK_PAGES: usize = 4;
+
 lazy_static! {
     static ref IDT: Idt = {
         let mut idt = Idt::new();
         idt.breakpoint.set_handler_fn(breakpoint_handler);
         idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.device_not_available.set_handler_fn(device_not_available_handler);
         idt.invalid_tss.set_handler_fn(invalid_tss_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
         unsafe {
+            // exceptions user code can raise, and the timer, which interrupts it, go through
+            // entry points that save every register, see interrupt_entry.asm
+            idt.divide_by_zero.set_handler_fn(
+                mem::transmute(exception::divide_error_entry as unsafe extern "C" fn()));
+            idt.debug.set_handler_fn(
+                mem::transmute(exception::debug_entry as unsafe extern "C" fn()));
+            idt.invalid_opcode.set_handler_fn(
+                mem::transmute(exception::invalid_opcode_entry as unsafe extern "C" fn()));
+            idt.segment_not_present.set_handler_fn(
+                mem::transmute(exception::segment_not_present_entry as unsafe extern "C" fn()));
+            idt.stack_segment_fault.set_handler_fn(
+                mem::transmute(exception::stack_segment_entry as unsafe extern "C" fn()));
+            idt.general_protection_fault.set_handler_fn(
+                mem::transmute(exception::general_protection_entry as unsafe extern "C" fn()));
+            idt.page_fault.set_handler_fn(
+                mem::transmute(exception::page_fault_entry as unsafe extern "C" fn()));
+            idt.x87_floating_point.set_handler_fn(
+                mem::transmute(exception::x87_floating_point_entry as unsafe extern "C" fn()));
+            idt.alignment_check.set_handler_fn(
+                mem::transmute(exception::alignment_check_entry as unsafe extern "C" fn()));
+            idt.simd_floating_point.set_handler_fn(
+                mem::transmute(exception::simd_floating_point_entry as unsafe extern "C" fn()));
+            idt.interrupts[timer::TIMER_VECTOR as usize - 32].set_handler_fn(
+                mem::transmute(timer::timer_entry as unsafe extern "C" fn()));
+
             idt.double_fault.set_handler_fn(double_fault_handler)
                .set_stack_index(DOUBLE_FAULT_IST_INDEX as u16);
         }
-        // Interrupt 0x80 is syscall
-        idt.interrupts[0x80 - 32].set_handler_fn(syscall_handler);
+        // Interrupt 0x80 is the slow syscall path, callable from user mode
+        let syscall_handler: HandlerFunc = unsafe {
+            mem::transmute(syscall::syscall_interrupt_entry as unsafe extern "C" fn())
+        };
+        idt.interrupts[0x80 - 32].set_handler_fn(syscall_handler)
+                                 .set_privilege_level(PrivilegeLevel::Ring3);
+        idt.interrupts[ipi::TLB_SHOOTDOWN_VECTOR as usize - 32]
+            .set_handler_fn(ipi::tlb_shootdown_handler);
+        irq::install(&mut idt);
         idt
     };
 }
 
-static TSS: Once<TaskStateSegment> = Once::new();
-static GDT: Once<gdt::Gdt> = Once::new();
+percpu! {
+    static TSS: Once<TaskStateSegment> = Once::new();
+}
+
+percpu! {
+    static GDT: Once<gdt::Gdt> = Once::new();
+}
 
+/// Run `f` with interrupts disabled, restoring the previous state afterwards
+pub fn without_interrupts<F, R>(f: F) -> R where F: FnOnce() -> R {
+    use x86_64::instructions::interrupts;
+
+    let rflags: usize;
+    unsafe { asm!("pushfq; pop $0" : "=r"(rflags) : : "memory" : "intel", "volatile"); }
+    let enabled = rflags & 1 << 9 != 0;
+
+    interrupts::disable();
+    let result = f();
+    if enabled {
+        unsafe { interrupts::enable(); }
+    }
+    result
+}
+
+/// Set up interrupt handling on the boot processor
 pub fn init(memory_controller: &mut MemoryController) {
+    ::percpu::setup(0, memory_controller);
+    ::percpu::load(0);
+
+    pic::init();
+    init_cpu(memory_controller);
+}
+
+/// Give the calling CPU its GDT, TSS and stacks, and load the IDT. Interrupts must be disabled,
+/// and the CPU's per-CPU block loaded.
+pub fn init_cpu(memory_controller: &mut MemoryController) {
     use x86_64::structures::gdt::SegmentSelector;
-    use x86_64::instructions::segmentation::set_cs;
+    use x86_64::instructions::segmentation::{set_cs, load_ss, load_ds, load_es};
     use x86_64::instructions::tables::load_tss;
     use x86_64::VirtualAddress;
 
     let double_fault_stack = memory_controller.alloc_stack(1)
         .expect("could not allocate double fault stack");
+    let kernel_stack = memory_controller.alloc_stack(KERNEL_STACK_PAGES)
+        .expect("could not allocate kernel stack");
 
-    let tss = TSS.call_once(|| {
+    let tss = TSS.get().call_once(|| {
         let mut tss = TaskStateSegment::new();
+        tss.privilege_stack_table[0] = VirtualAddress(kernel_stack.top());
         tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX] =
             VirtualAddress(double_fault_stack.top());
         tss
     });
 
     let mut code_selector = SegmentSelector(0);
+    let mut data_selector = SegmentSelector(0);
     let mut tss_selector = SegmentSelector(0);
-    let gdt = GDT.call_once(|| {
+    let gdt = GDT.get().call_once(|| {
+        // entries must be added in the order of the `gdt::GDT_*` indices
         let mut gdt = gdt::Gdt::new();
         code_selector = gdt.add_entry(gdt::Descriptor::kernel_code_segment());
+        data_selector = gdt.add_entry(gdt::Descriptor::kernel_data_segment());
+        gdt.add_entry(gdt::Descriptor::user_data_segment());
+        gdt.add_entry(gdt::Descriptor::user_code_segment());
+        gdt.add_entry(gdt::Descriptor::user_data_segment()); // user TLS
         tss_selector = gdt.add_entry(gdt::Descriptor::tss_segment(&tss));
         gdt
     });
     gdt.load();
 
     unsafe {
-        // reload code segment register
+        // reload segment registers
         set_cs(code_selector);
+        load_ss(data_selector);
+        load_ds(data_selector);
+        load_es(data_selector);
         // load TSS
         load_tss(tss_selector);
     }
 
+    // the handlers are the same everywhere, so all CPUs share one IDT
     IDT.load();
+
+    local_apic::init(memory_controller);
+
+    ::percpu::set_kernel_stack(kernel_stack.top());
+    syscall::init();
 }
 
-extern "x86-interrupt"
-fn syscall_handler(_: &mut ExceptionStackFrame) {
-    println!("SYSCALL");
+/// Set the stack the calling CPU switches to when it enters the kernel from user mode, through an
+/// interrupt or `syscall`
+pub fn set_kernel_stack(top: usize) {
+    use x86_64::VirtualAddress;
+
+    let tss = TSS.get().try().expect("TSS isn't set up") as *const TaskStateSegment;
+    // the CPU only reads RSP0 on its way in from user mode, which can't happen while we're here
+    unsafe { (*(tss as *mut TaskStateSegment)).privilege_stack_table[0] = VirtualAddress(top); }
+    ::percpu::set_kernel_stack(top);
 }
 
 extern "x86-interrupt"
 fn breakpoint_handler(stack_frame: &mut ExceptionStackFrame) {
+    let _gs = KernelGs::enter(stack_frame);
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
-extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: &mut ExceptionStackFrame) {
-    println!("\nEXCEPTION: INVALID OPCODE at {:#x}\n{:#?}",
-             stack_frame.instruction_pointer,
-             stack_frame);
-    loop {}
+/// Raised by the first FPU or vector instruction after a thread switch
+extern "x86-interrupt" fn device_not_available_handler(stack_frame: &mut ExceptionStackFrame) {
+    let _gs = KernelGs::enter(stack_frame);
+    ::context::fpu::device_not_available();
 }
 
 extern "x86-interrupt" fn invalid_tss_handler(stack_frame: &mut ExceptionStackFrame, error_code: u64) {
+    let _gs = KernelGs::enter(stack_frame);
     println!("\nEXCEPTION: INVALID TSS\nerror_code: {}\n{:#?}",
              error_code,
              stack_frame);
     loop {}
 }
 
-extern "x86-interrupt"
-fn page_fault_handler(stack_frame: &mut ExceptionStackFrame, error_code: PageFaultErrorCode) {
-    println!("EXCEPTION: PAGE FAULT\nerror code: {:?}\n{:#?}", error_code, stack_frame);
-    loop {}
-}
-
 extern "x86-interrupt"
 fn double_fault_handler(stack_frame: &mut ExceptionStackFrame, error_code: u64) {
     println!("EXCEPTION: DOUBLE FAULT:\nerror code: {}\n{:#?}", error_code, stack_frame);
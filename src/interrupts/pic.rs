@@ -0,0 +1,74 @@
+// Legacy 8259 programmable interrupt controllers
+
+use ::syscall::io::{Io, Pio};
+
+/// Vector of the first legacy IRQ after remapping, so they don't collide with CPU exceptions
+pub const PIC_VECTOR_OFFSET: u8 = 32;
+
+const ICW1_INIT: u8 = 0x10;
+const ICW1_ICW4: u8 = 0x01;
+const ICW4_8086: u8 = 0x01;
+const OCW2_EOI: u8 = 0x20;
+
+struct Pic {
+    command: Pio<u8>,
+    data: Pio<u8>,
+}
+
+impl Pic {
+    const fn new(port: u16) -> Pic {
+        Pic {
+            command: Pio::new(port),
+            data: Pio::new(port + 1),
+        }
+    }
+}
+
+/// Remap both PICs past the exception vectors and mask every IRQ line. Device interrupts should
+/// be delivered as MSIs instead, legacy lines are only unmasked for devices that lack them.
+pub fn init() {
+    let mut master = Pic::new(0x20);
+    let mut slave = Pic::new(0xA0);
+
+    // start initialization sequence
+    master.command.write(ICW1_INIT | ICW1_ICW4);
+    slave.command.write(ICW1_INIT | ICW1_ICW4);
+
+    // vector offsets
+    master.data.write(PIC_VECTOR_OFFSET);
+    slave.data.write(PIC_VECTOR_OFFSET + 8);
+
+    // master has the slave on IRQ 2, slave has cascade identity 2
+    master.data.write(1 << 2);
+    slave.data.write(2);
+
+    master.data.write(ICW4_8086);
+    slave.data.write(ICW4_8086);
+
+    // mask all lines, except for the cascade
+    master.data.write(!(1 << 2));
+    slave.data.write(0xFF);
+}
+
+/// Let the PICs deliver IRQ `line` again
+pub fn unmask(line: u8) {
+    assert!(line < 16, "invalid legacy IRQ {}", line);
+    let mut pic = if line < 8 { Pic::new(0x20) } else { Pic::new(0xA0) };
+    let mask = pic.data.read();
+    pic.data.write(mask & !(1 << (line % 8)));
+}
+
+pub fn mask(line: u8) {
+    assert!(line < 16, "invalid legacy IRQ {}", line);
+    let mut pic = if line < 8 { Pic::new(0x20) } else { Pic::new(0xA0) };
+    let mask = pic.data.read();
+    pic.data.write(mask | 1 << (line % 8));
+}
+
+/// Acknowledge IRQ `line`
+pub fn eoi(line: u8) {
+    if line >= 8 {
+        Pic::new(0xA0).command.write(OCW2_EOI);
+    }
+    Pic::new(0x20).command.write(OCW2_EOI);
+}
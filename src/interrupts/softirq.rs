@@ -0,0 +1,80 @@
+// Softirqs: work an interrupt handler defers until the outermost interrupt returns, and which
+// then runs with interrupts enabled, still in interrupt context. There are `SOFTIRQ_COUNT` of
+// them, handed out by `allocate` to whoever needs one, rather than one for each interrupt vector;
+// a handler raises the softirq it was given.
+
+use core::mem;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use irq_lock::SpinMutex;
+use x86_64::instructions::interrupts;
+
+pub type SoftirqHandler = fn();
+
+pub const SOFTIRQ_COUNT: usize = 16;
+
+/// Bit n is set while softirq n is waiting to run
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+/// Set while a CPU runs softirqs, so only one does at a time. It also runs those raised in the
+/// meantime.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+static HANDLERS: [AtomicUsize; SOFTIRQ_COUNT] = [
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+];
+
+static ALLOCATED: SpinMutex<u16> = SpinMutex::named(lock_class!("softirq::ALLOCATED"), 0);
+
+/// Reserve a softirq that runs `handler`. Handlers must not block, as they run on whatever stack
+/// the interrupt arrived on.
+pub fn allocate(handler: SoftirqHandler) -> Option<usize> {
+    let mut allocated = ALLOCATED.lock();
+    for nr in 0..SOFTIRQ_COUNT {
+        if *allocated & 1 << nr == 0 {
+            *allocated |= 1 << nr;
+            HANDLERS[nr].store(handler as usize, Ordering::SeqCst);
+            return Some(nr);
+        }
+    }
+    None
+}
+
+/// Mark softirq `nr` pending. It runs when the current interrupt returns, or when the next one
+/// does if called outside of interrupt context.
+pub fn raise(nr: usize) {
+    assert!(nr < SOFTIRQ_COUNT, "invalid softirq {}", nr);
+    PENDING.fetch_or(1 << nr, Ordering::SeqCst);
+}
+
+/// Run pending softirqs. Called with interrupts disabled on the way out of the outermost
+/// interrupt handler, before it leaves interrupt context, and returns with them disabled again.
+pub fn run_pending() {
+    if PENDING.load(Ordering::SeqCst) == 0 || RUNNING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    loop {
+        let pending = PENDING.swap(0, Ordering::SeqCst);
+        if pending == 0 {
+            break;
+        }
+
+        unsafe { interrupts::enable(); }
+        for nr in 0..SOFTIRQ_COUNT {
+            if pending & 1 << nr != 0 {
+                let handler = HANDLERS[nr].load(Ordering::SeqCst);
+                if handler != 0 {
+                    let handler: SoftirqHandler = unsafe { mem::transmute(handler) };
+                    handler();
+                }
+            }
+        }
+        interrupts::disable();
+    }
+
+    RUNNING.store(false, Ordering::SeqCst);
+}
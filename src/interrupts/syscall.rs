@@ -0,0 +1,81 @@
+use x86_64::registers::msr::{rdmsr, wrmsr, IA32_EFER, IA32_FMASK, IA32_LSTAR, IA32_STAR};
+
+use super::gdt;
+
+/// The registers of the calling thread, as saved on kernel entry. The last five fields have the
+/// layout of an interrupt stack frame, so `int 0x80` and `syscall` produce the same structure, as
+/// do the entry points in interrupt_entry.asm.
+#[derive(Debug)]
+#[repr(C)]
+pub struct SyscallFrame {
+    pub r15: usize,
+    pub r14: usize,
+    pub r13: usize,
+    pub r12: usize,
+    pub rbp: usize,
+    pub rbx: usize,
+    pub r11: usize,
+    pub r10: usize,
+    pub r9: usize,
+    pub r8: usize,
+    pub rax: usize,
+    pub rcx: usize,
+    pub rdx: usize,
+    pub rsi: usize,
+    pub rdi: usize,
+    pub rip: usize,
+    pub cs: usize,
+    pub rflags: usize,
+    pub rsp: usize,
+    pub ss: usize,
+}
+
+extern "C" {
+    fn syscall_entry();
+    /// Entry point for `int 0x80`. Not an `extern "x86-interrupt"` function, but it has to be
+    /// installed in the IDT as one.
+    pub fn syscall_interrupt_entry();
+}
+
+const EFER_SYSCALL_ENABLE: u64 = 1 << 0;
+
+const RFLAGS_TF: u64 = 1 << 8;
+const RFLAGS_IF: u64 = 1 << 9;
+const RFLAGS_DF: u64 = 1 << 10;
+const RFLAGS_AC: u64 = 1 << 18;
+
+/// Program the MSRs used by the `syscall` instruction. `syscall_entry` switches to the stack in
+/// `PerCpu::kernel_rsp`, so the per-CPU block must be set up.
+pub fn init() {
+    unsafe {
+        // SYSCALL loads CS from STAR[47:32] and SS from STAR[47:32] + 8. SYSRET loads SS from
+        // STAR[63:48] + 8 and CS from STAR[63:48] + 16.
+        let kernel_base = (gdt::GDT_KERNEL_CODE << 3) as u64;
+        let user_base = ((gdt::GDT_USER_DATA - 1) << 3 | 3) as u64;
+        wrmsr(IA32_STAR, user_base << 48 | kernel_base << 32);
+        wrmsr(IA32_LSTAR, syscall_entry as u64);
+        // don't trust the user's flags on entry, `syscall_entry` turns interrupts back on itself
+        wrmsr(IA32_FMASK, RFLAGS_TF | RFLAGS_IF | RFLAGS_DF | RFLAGS_AC);
+
+        let efer = rdmsr(IA32_EFER);
+        wrmsr(IA32_EFER, efer | EFER_SYSCALL_ENABLE);
+    }
+}
+
+/// Called by both syscall entry points with the saved registers of the caller. Whatever is left
+/// in the frame is restored on return.
+#[no_mangle]
+pub extern "C" fn syscall_dispatch(frame: &mut SyscallFrame) {
+    if frame.rax == ::syscall::number::SYS_SIGRETURN {
+        ::process::signal::sigreturn(frame);
+    } else {
+        frame.rax = ::syscall::syscall(frame.rax, frame.rdi, frame.rsi, frame.rdx,
+                                       frame.r10, frame.r8, frame.r9);
+    }
+
+    // stop if another thread ended the process while this one was in the kernel, and act on
+    // signals that came in
+    if frame.cs & 3 != 0 {
+        ::process::signal::return_to_user(frame);
+    }
+}
@@ -0,0 +1,29 @@
+use super::{irq, local_apic, SyscallFrame};
+
+pub const TIMER_VECTOR: u8 = 0x30;
+
+extern "C" {
+    /// Entry point of the timer interrupt, see interrupt_entry.asm. Not an `extern
+    /// "x86-interrupt"` function, but it has to be installed in the IDT as one.
+    pub fn timer_entry();
+}
+
+/// Start raising timer interrupts `frequency` times per second. Each one is a scheduler tick.
+pub fn start(frequency: u32) {
+    local_apic::local_apic().start_periodic_timer(TIMER_VECTOR, frequency);
+}
+
+/// Called by `timer_entry` with the registers of the interrupted code
+#[no_mangle]
+pub extern "C" fn timer_handler(frame: &mut SyscallFrame, _error_code: usize) {
+    irq::enter();
+    ::scheduler::tick();
+    local_apic::local_apic().eoi();
+    irq::exit();
+
+    // user code that never makes a syscall still gets to its signals, and stops if its process
+    // ended, at the next tick
+    if frame.cs & 3 != 0 {
+        ::process::signal::return_to_user(frame);
+    }
+}
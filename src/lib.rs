@@ -13,6 +13,8 @@ extern crate spin;
 extern crate x86_64;
 
 extern crate hole_list_allocator;
+#[macro_use]
+extern crate irq_lock;
 extern crate alloc;
 #[macro_use]
 extern crate collections;
@@ -22,14 +24,31 @@ use collections::BTreeMap;
 // Declare before other modules so that they can use println! macro
 #[macro_use]
 pub mod vga_buffer;
+#[macro_use]
+mod percpu;
 
+mod acpi;
+mod context;
+mod cpu;
 mod drivers;
 mod memory;
 mod interrupts;
+mod process;
+mod scheduler;
+mod smp;
+mod sync;
 mod syscall;
+mod workqueue;
 
 #[no_mangle]
 pub extern "C" fn rust_main(multiboot_info_addr: usize) {
+    irq_lock::set_in_interrupt_fn(interrupts::in_interrupt);
+    irq_lock::set_spin_fn(memory::handle_shootdown);
+    #[cfg(feature = "lockdep")]
+    irq_lock::lockdep::set_report_fn(vga_buffer::print_lockdep);
+    #[cfg(feature = "lockdep")]
+    irq_lock::lockdep::set_cpu_id_fn(percpu::cpu_id);
+
     vga_buffer::clear_screen();
 
     println!("Hello, rust!");
@@ -42,6 +61,13 @@ pub extern "C" fn rust_main(multiboot_info_addr: usize) {
 
     // initialize our IDT
     memory::with_mem_ctrl(|m| { interrupts::init(m); });
+    unsafe { x86_64::instructions::interrupts::enable(); }
+
+    context::init();
+    process::init();
+    scheduler::init();
+    workqueue::init();
+    smp::init();
 
     // provoke a divide-by-zero fault
     //divide_by_zero();
@@ -53,7 +79,15 @@ pub extern "C" fn rust_main(multiboot_info_addr: usize) {
     //unsafe { let u: u32 = *(0x20000000 as *const u32); }
 
     x86_64::instructions::interrupts::int3();
-    unsafe { int!(0x80); }
+
+    // try the slow syscall path with a syscall that's harmless in the kernel process
+    let pid: usize;
+    unsafe {
+        asm!("int 0x80" : "={rax}"(pid) : "{rax}"(syscall::number::SYS_GETPID)
+             : "rcx", "rdx", "rsi", "rdi", "r8", "r9", "r10", "r11", "memory"
+             : "intel", "volatile");
+    }
+    assert_eq!(pid, process::KERNEL_PID.0, "getpid through int 0x80 failed");
 
     println!("It did not crash!");
 
@@ -65,8 +99,11 @@ pub extern "C" fn rust_main(multiboot_info_addr: usize) {
     for i in v.iter().rev() {
         println!("{}", i);
     }
-    
-    loop { }
+
+    let init = process::start_init();
+    println!("started init: {:?}", init);
+
+    scheduler::exit();
 }
 
 fn divide_by_zero() {
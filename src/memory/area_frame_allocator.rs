@@ -18,7 +18,8 @@ impl AreaFrameAllocator {
                memory_areas: MemoryAreaIter) -> AreaFrameAllocator
     {
         let mut allocator = AreaFrameAllocator {
-            next_free_frame: Frame::containing_address(0),
+            // the first MiB holds BIOS data, and the trampoline that starts the other CPUs
+            next_free_frame: Frame::containing_address(0x10_0000),
             current_area: None,
             areas: memory_areas,
             kernel_start: Frame::containing_address(kernel_start),
@@ -22,17 +22,21 @@ pub const KERNEL_HEAP_SIZE: usize = 128 * 1024 * 1024; // 128 MB
 #[cfg(feature = "live")]
 pub const KERNEL_HEAP_SIZE: usize = 640 * 1024 * 1024; // 640 MB - 128 default + 512 for the live disk
 
-/// Offset to kernel percpu variables
-//TODO: Use 64-bit fs offset to enable this pub const KERNEL_PERCPU_OFFSET: usize = KERNEL_HEAP_OFFSET - PML4_SIZE;
+/// Offset to kernel percpu variables, one block of `KERNEL_PERCPU_SIZE` per CPU, reached through
+/// the GS base
 pub const KERNEL_PERCPU_OFFSET: usize = 0xC000_0000;
-/// Size of kernel percpu variables
+/// Size of kernel percpu variables of a single CPU
 pub const KERNEL_PERCPU_SIZE: usize = 64 * 1024; // 64 KB
 
 /// Offset for a temporary page used to create new page tables
 pub const KERNEL_TMP_PAGE_OFFSET: usize = KERNEL_OFFSET + PML4_SIZE;
 
-/// Offset to user image
-pub const USER_OFFSET: usize = 1;
+/// End of the kernel's part of the address space. Every page table shares the PML4 entries below
+/// it. The temporary page isn't shared, each page table maps its own.
+pub const KERNEL_SHARED_END: usize = KERNEL_TMP_PAGE_OFFSET;
+
+/// Offset to user image, right after the temporary page's PML4
+pub const USER_OFFSET: usize = KERNEL_TMP_PAGE_OFFSET + PML4_SIZE;
 
 /// Offset to user TCB (Thread Control Block)
 pub const USER_TCB_OFFSET: usize = 0xB000_0000;
@@ -43,6 +47,9 @@ pub const USER_ARG_OFFSET: usize = USER_OFFSET + PML4_SIZE/2;
 /// Offset to user heap
 pub const USER_HEAP_OFFSET: usize = USER_OFFSET + PML4_SIZE;
 
+/// Offset to user anonymous mappings, which take the upper half of the heap's PML4
+pub const USER_MMAP_OFFSET: usize = USER_HEAP_OFFSET + PML4_SIZE/2;
+
 /// Offset to user grants
 pub const USER_GRANT_OFFSET: usize = USER_HEAP_OFFSET + PML4_SIZE;
 
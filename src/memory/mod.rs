@@ -1,13 +1,20 @@
+use core::slice;
+
+use irq_lock::IrqMutex;
 use multiboot2::BootInformation;
-use spin;
 
-use self::area_frame_allocator::AreaFrameAllocator;
+use syscall::{Error, Result};
+use syscall::error::{EINVAL, ENOMEM};
+
 use self::page_allocator::PageAllocator;
-use self::paging::{InactivePageTable, Mapper, Page, TemporaryPage};
+use self::paging::{Page, TemporaryPage};
 use self::stack_allocator::StackAllocator;
 
+pub use self::area_frame_allocator::AreaFrameAllocator;
 pub use self::layout::*;
-pub use self::paging::{PhysicalAddress, VirtualAddress, EntryFlags, WRITABLE};
+pub use self::paging::{PhysicalAddress, VirtualAddress, EntryFlags, WRITABLE, NO_CACHE,
+                       NO_EXECUTE, USER_ACCESSIBLE};
+pub use self::paging::{handle_shootdown, pcid, InactivePageTable, Mapper};
 pub use self::stack_allocator::Stack;
 
 mod area_frame_allocator;
@@ -18,7 +25,8 @@ mod stack_allocator;
 
 pub const PAGE_SIZE: usize = 4096;
 
-static MEM_CONTROLLER: spin::Mutex<Option<MemoryController>> = spin::Mutex::new(None);
+static MEM_CONTROLLER: IrqMutex<Option<MemoryController>> =
+    IrqMutex::named(lock_class!("memory::MEM_CONTROLLER"), None);
 
 #[inline(always)]
 pub fn with_mem_ctrl<F, R>(f: F) -> R
@@ -53,6 +61,8 @@ pub fn init(boot_info: &BootInformation) {
                                                       boot_info.end_address(),
                                                       memory_map_tag.memory_areas());
 
+    // before the switch to the kernel's page table, which gets a PCID
+    pcid::init_cpu();
     let mut active_table = paging::remap_kernel(&mut frame_allocator, boot_info);
 
     // Memory map the kernel heap
@@ -63,7 +73,8 @@ pub fn init(boot_info: &BootInformation) {
     let heap_end_page = Page::containing_address(HEAP_START + HEAP_SIZE-1);
 
     for page in Page::range_inclusive(heap_start_page, heap_end_page) {
-        active_table.map(page, paging::WRITABLE, &mut frame_allocator);
+        active_table.map(page, paging::WRITABLE, &mut frame_allocator)
+                    .expect("could not map the kernel heap");
     }
 
     // Initialize the heap
@@ -73,10 +84,17 @@ pub fn init(boot_info: &BootInformation) {
 
     // Create a stack allocator
     let stack_alloc_start = heap_end_page + 1;
-    let stack_alloc_end = stack_alloc_start + 100;
+    let stack_alloc_end = stack_alloc_start + 4096;
     let stack_allocator =
         StackAllocator::new(Page::range_inclusive(stack_alloc_start, stack_alloc_end));
 
+    // every page table operation maps the temporary page, so its page tables are created up
+    // front, and mapping it never runs out of memory. `new_page_table` does the same for the
+    // other page tables.
+    active_table.create_tables(Page::containing_address(KERNEL_TMP_PAGE_OFFSET),
+                               &mut frame_allocator)
+                .expect("could not map the temporary page");
+
     let page_alloc_start = stack_alloc_end + 1;
     let page_alloc_end = page_alloc_start + 65536; // 256 MB of pages
     let page_allocator =
@@ -98,13 +116,33 @@ pub struct MemoryController {
 }
 
 impl MemoryController {
+    /// Set up paging on an application processor, which starts out on the active table with
+    /// PCID 0
+    pub fn init_cpu(&mut self) {
+        pcid::init_cpu();
+        self.active_table.reload();
+    }
+
     pub fn new_page_table(&mut self) -> Option<InactivePageTable> {
         let mut tmp_page = TemporaryPage::new(Page::containing_address(KERNEL_TMP_PAGE_OFFSET));
 
-        self.frame_allocator.allocate_frame().map(|new_table_frame| {
-            InactivePageTable::new(new_table_frame, &mut self.active_table,
-                                   &mut tmp_page, &mut self.frame_allocator)
-        })
+        let mut table = match self.frame_allocator.allocate_frame() {
+            Some(frame) => InactivePageTable::new(frame, &mut self.active_table, &mut tmp_page,
+                                                  &mut self.frame_allocator),
+            None => return None,
+        };
+
+        // mapping the temporary page while the table is active mustn't run out of memory
+        let mut created = Ok(());
+        self.with_inactive_table(&mut table, |mapper, allocator| {
+            created = mapper.create_tables(Page::containing_address(KERNEL_TMP_PAGE_OFFSET),
+                                           allocator);
+        });
+        if created.is_err() {
+            self.free_page_table(table);
+            return None;
+        }
+        Some(table)
     }
 
     pub fn with_inactive_table<F>(&mut self, table: &mut InactivePageTable, f: F)
@@ -114,38 +152,182 @@ impl MemoryController {
         self.active_table.with(table, &mut tmp_page, &mut self.frame_allocator, f);
     }
 
+    /// Map a fresh frame at the page containing `address` in `table`, filled in by `fill` first.
+    /// The frame starts out zeroed. Returns `None` if out of memory.
+    pub fn map_filled<F>(&mut self, table: &mut InactivePageTable, address: VirtualAddress,
+                         flags: paging::EntryFlags, fill: F) -> Option<()>
+        where F: FnOnce(&mut [u8])
+    {
+        let frame = match self.filled_frame(fill) {
+            Some(frame) => frame,
+            None => return None,
+        };
+        let mut mapped = Ok(());
+        self.with_inactive_table(table, |mapper, allocator| {
+            mapped = mapper.map_to(Page::containing_address(address), frame.clone(), flags,
+                                   allocator);
+        });
+        if mapped.is_err() {
+            self.frame_allocator.deallocate_frame(frame);
+            return None;
+        }
+        Some(())
+    }
+
+    /// Map a fresh, zeroed frame at the page containing `address` in the active table. Returns
+    /// `None` if out of memory.
+    pub fn map_zeroed_page(&mut self, address: VirtualAddress, flags: paging::EntryFlags)
+                           -> Option<()> {
+        let frame = match self.filled_frame(|_| ()) {
+            Some(frame) => frame,
+            None => return None,
+        };
+        let mapped = self.active_table.map_to(Page::containing_address(address), frame.clone(),
+                                              flags, &mut self.frame_allocator);
+        if mapped.is_err() {
+            self.frame_allocator.deallocate_frame(frame);
+            return None;
+        }
+        Some(())
+    }
+
+    /// A fresh frame, zeroed and then filled in by `fill`
+    fn filled_frame<F>(&mut self, fill: F) -> Option<Frame> where F: FnOnce(&mut [u8]) {
+        let frame = match self.frame_allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => return None,
+        };
+
+        // the frame isn't mapped anywhere yet, so fill it in through the temporary page
+        let mut tmp_page = TemporaryPage::new(Page::containing_address(KERNEL_TMP_PAGE_OFFSET));
+        {
+            let start = tmp_page.map(frame.clone(), &mut self.active_table,
+                                     &mut self.frame_allocator);
+            let bytes = unsafe { slice::from_raw_parts_mut(start as *mut u8, PAGE_SIZE) };
+            for byte in bytes.iter_mut() {
+                *byte = 0;
+            }
+            fill(bytes);
+        }
+        tmp_page.unmap(&mut self.active_table);
+        Some(frame)
+    }
+
+    /// Unmap whatever is mapped in the page aligned range `start..end` of the active table,
+    /// freeing the frames. Returns how many pages were mapped.
+    pub fn unmap_user(&mut self, start: VirtualAddress, end: VirtualAddress) -> usize {
+        let pages = Page::range_inclusive(Page::containing_address(start),
+                                          Page::containing_address(end - 1));
+        self.active_table.unmap_mapped(pages, &mut self.frame_allocator)
+    }
+
+    /// Change the flags of whatever is mapped in the page aligned range `start..end` of the active
+    /// table
+    pub fn set_user_flags(&mut self, start: VirtualAddress, end: VirtualAddress,
+                          flags: paging::EntryFlags) {
+        let pages = Page::range_inclusive(Page::containing_address(start),
+                                          Page::containing_address(end - 1));
+        self.active_table.set_flags(pages, flags);
+    }
+
+    /// Free `table` along with everything mapped in its own part. It must not be active on any
+    /// CPU.
+    pub fn free_page_table(&mut self, mut table: InactivePageTable) {
+        self.with_inactive_table(&mut table, |mapper, allocator| mapper.free_user_part(allocator));
+        table.free(&mut self.frame_allocator);
+    }
+
     pub fn translate_address(&mut self, address: VirtualAddress) -> Option<PhysicalAddress> {
         self.active_table.translate(address)
     }
 
-    pub fn alloc_vm(&mut self, size: usize, flags: paging::EntryFlags) -> Option<VirtualAddress> {
-        self.page_allocator.allocate((size + 4095) / PAGE_SIZE).map(|pages| {
-            let start_address = pages.start_address();
-            // TODO verify pages were mapped properly and return None otherwise
-            self.active_table.map_range(pages, flags, &mut self.frame_allocator);
-            start_address
-        })
+    /// Map `size` bytes of fresh, physically contiguous memory somewhere in the kernel's page
+    /// area. Fails with `ENOMEM` if there's no room or no memory left.
+    pub fn alloc_vm(&mut self, size: usize, flags: paging::EntryFlags) -> Result<VirtualAddress> {
+        let pages = self.page_allocator.allocate((size + 4095) / PAGE_SIZE)
+            .ok_or(Error::new(ENOMEM))?;
+        let start_address = pages.start_address();
+        if let Err(error) = self.active_table.map_range(pages.clone(), flags,
+                                                        &mut self.frame_allocator) {
+            self.page_allocator.free(pages);
+            return Err(error);
+        }
+        Ok(start_address)
     }
 
-    pub fn map_pm(&mut self, address: PhysicalAddress, size: usize, flags: paging::EntryFlags) -> Option<VirtualAddress> {
-        self.page_allocator.allocate((size + 4095) / PAGE_SIZE).map(|pages| {
-            let start_address = pages.start_address();
+    /// Map the `size` bytes of physical memory at `address`, starting at the returned address
+    /// rounded down to a page. Fails with `EINVAL` for an empty or overflowing range, and with
+    /// `ENOMEM` if there's no room or no memory left for the page tables.
+    pub fn map_pm(&mut self, address: PhysicalAddress, size: usize, flags: paging::EntryFlags)
+                  -> Result<VirtualAddress> {
+        let end = match address.checked_add(size) {
+            Some(end) if size > 0 => end,
+            _ => return Err(Error::new(EINVAL)),
+        };
+        let start_frame = Frame::containing_address(address);
+        let end_frame = Frame::containing_address(end - 1);
+        let pages = self.page_allocator.allocate(end_frame.number - start_frame.number + 1)
+            .ok_or(Error::new(ENOMEM))?;
+        let start_address = pages.start_address();
+
+        // map grant pages to physical frames
+        if let Err(error) = self.active_table.map_range_to(pages.clone(),
+                                                           Frame::range_inclusive(start_frame,
+                                                                                  end_frame),
+                                                           flags, &mut self.frame_allocator) {
+            self.page_allocator.free(pages);
+            return Err(error);
+        }
+        Ok(start_address)
+    }
+
+    /// Map `size` bytes of fresh memory at the fixed address `address`. Fails with `ENOMEM`,
+    /// mapping nothing, if there isn't enough memory left.
+    pub fn map_fixed(&mut self, address: VirtualAddress, size: usize, flags: paging::EntryFlags)
+                     -> Result<()> {
+        let start_page = Page::containing_address(address);
+        let end_page = Page::containing_address(address + size - 1);
+        self.active_table.map_range(Page::range_inclusive(start_page, end_page), flags,
+                                    &mut self.frame_allocator)
+    }
 
-            let start_frame = Frame::containing_address(address);
-            let end_frame = Frame::containing_address(address + size - 1);
+    /// Map the page containing `address` to the frame with the same address. Fails with
+    /// `ENOMEM` if there's no memory left for the page tables.
+    pub fn identity_map(&mut self, address: PhysicalAddress, flags: paging::EntryFlags)
+                        -> Result<()> {
+        self.active_table.identity_map(Frame::containing_address(address), flags,
+                                       &mut self.frame_allocator)
+    }
 
-            // map grant pages to physical frames
-            self.active_table.map_range_to(pages, Frame::range_inclusive(start_frame, end_frame),
-                                           flags, &mut self.frame_allocator);
-            
-            start_address
+    /// Whether user mode may access every page of `address..address + size` with `flags`, e.g.
+    /// `WRITABLE` to write to them
+    pub fn is_user_accessible(&mut self, address: VirtualAddress, size: usize,
+                              flags: paging::EntryFlags) -> bool {
+        if size == 0 {
+            return true;
+        }
+        let end = match address.checked_add(size - 1) {
+            // user space is the lower half
+            Some(end) if end < 0x0000_8000_0000_0000 => end,
+            _ => return false,
+        };
+        let flags = flags | USER_ACCESSIBLE;
+        let start_page = Page::containing_address(address);
+        let end_page = Page::containing_address(end);
+        Page::range_inclusive(start_page, end_page).all(|page| {
+            self.active_table.page_flags(page).map_or(false, |page_flags| page_flags.contains(flags))
         })
     }
 
-    pub fn alloc_stack(&mut self, size_in_pages: usize) -> Option<Stack> {
+    pub fn alloc_stack(&mut self, size_in_pages: usize) -> Result<Stack> {
         self.stack_allocator.alloc_stack(&mut self.active_table, &mut self.frame_allocator,
                                          size_in_pages)
     }
+
+    /// Unmap a stack from `alloc_stack`, freeing its frames, and give its pages back
+    pub fn free_stack(&mut self, stack: Stack) {
+        self.stack_allocator.free_stack(&mut self.active_table, &mut self.frame_allocator, stack);
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
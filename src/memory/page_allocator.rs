@@ -1,13 +1,20 @@
+use collections::Vec;
+
 use memory::paging::{Page, PageIter, VirtualAddress};
 use memory::PAGE_SIZE;
 
 pub struct PageAllocator {
     range: PageIter,
+    /// Ranges given back with `free`, reused before `range`
+    free: Vec<PageIter>,
 }
 
 impl PageAllocator {
     pub fn new(page_range: PageIter) -> PageAllocator {
-        PageAllocator { range: page_range }
+        PageAllocator {
+            range: page_range,
+            free: Vec::new(),
+        }
     }
 
     pub fn allocate(&mut self, size_in_pages: usize) -> Option<PageIter> {
@@ -15,6 +22,18 @@ impl PageAllocator {
             return None; // a zero sized VM area makes no sense
         }
 
+        // the first freed range that's big enough, keeping what's left of it
+        let found = self.free.iter().position(|pages| pages.size() >= size_in_pages);
+        if let Some(index) = found {
+            let pages = self.free.swap_remove(index);
+            let start = Page::containing_address(pages.start_address());
+            if pages.size() > size_in_pages {
+                self.free.push(Page::range_inclusive(start + size_in_pages,
+                                                     start + (pages.size() - 1)));
+            }
+            return Some(Page::range_inclusive(start, start + (size_in_pages - 1)));
+        }
+
         // clone the range, since we only want to change it on success
         let mut range = self.range.clone();
 
@@ -39,4 +58,26 @@ impl PageAllocator {
             _ => None, // not enough pages
         }
     }
+
+    /// Give back `pages`, as returned by `allocate`. Nothing may be mapped there anymore.
+    pub fn free(&mut self, pages: PageIter) {
+        let mut start = Page::containing_address(pages.start_address());
+        let mut end = start + (pages.size() - 1);
+
+        // merge it with the freed ranges right before and after it
+        self.free.retain(|other| {
+            let other_start = Page::containing_address(other.start_address());
+            let other_end = other_start + (other.size() - 1);
+            if other_end + 1 == start {
+                start = other_start;
+                false
+            } else if end + 1 == other_start {
+                end = other_end;
+                false
+            } else {
+                true
+            }
+        });
+        self.free.push(Page::range_inclusive(start, end));
+    }
 }
@@ -66,4 +66,14 @@ impl Entry {
         assert!(frame.start_address() & !0x000fffff_fffff000 == 0);
         self.0 = (frame.start_address() as u64) | flags.bits();
     }
+
+    /// The PCID kept in bits 52-62, which the CPU ignores. Only used in recursive entries.
+    pub fn pcid(&self) -> usize {
+        (self.0 >> 52) as usize & 0x7FF
+    }
+
+    pub fn set_pcid(&mut self, pcid: usize) {
+        assert!(pcid < 0x800, "PCID {:#x} doesn't fit in an entry", pcid);
+        self.0 = self.0 & !(0x7FF << 52) | (pcid as u64) << 52;
+    }
 }
@@ -1,9 +1,15 @@
 use core::ptr::Unique;
 
+use x86_64;
+use x86_64::instructions::tlb;
+
 use super::{Page, PageIter, ENTRY_COUNT, VirtualAddress, PhysicalAddress};
 use super::entry::*;
-use super::table::{self, Table, Level4};
-use ::memory::{PAGE_SIZE, Frame, FrameAllocator, FrameIter};
+use super::table::{self, Table, Level1, Level4};
+use super::tlb::TlbBatch;
+use ::memory::{PAGE_SIZE, PML4_SIZE, KERNEL_SHARED_END, Frame, FrameAllocator, FrameIter};
+use ::syscall::{Error, Result};
+use ::syscall::error::ENOMEM;
 
 pub struct Mapper {
     p4: Unique<Table<Level4>>,
@@ -22,6 +28,14 @@ impl Mapper {
         unsafe { self.p4.as_mut() }
     }
 
+    /// The address space this mapper changes, identified by the physical address of its P4 table
+    /// and its PCID, like in CR3. Not necessarily the active one, see `ActivePageTable::with`.
+    pub fn address_space(&self) -> PhysicalAddress {
+        // the recursive entry points at the table itself
+        let recursive = &self.p4()[511];
+        recursive.pointed_frame().expect("no recursive mapping").start_address() | recursive.pcid()
+    }
+
     pub fn translate(&self, virtual_address: VirtualAddress) -> Option<PhysicalAddress> {
         let offset = virtual_address % PAGE_SIZE;
         self.translate_page(Page::containing_address(virtual_address))
@@ -70,43 +84,107 @@ impl Mapper {
           .or_else(huge_page)
     }
 
-    pub fn map_to<A: FrameAllocator>(&mut self, page: Page, frame: Frame, flags: EntryFlags, allocator: &mut A) {
-        let mut p3 = self.p4_mut().next_table_create(page.p4_index(), allocator);
-        let mut p2 = p3.next_table_create(page.p3_index(), allocator);
-        let mut p1 = p2.next_table_create(page.p2_index(), allocator);
+    /// Flags of the entry mapping `page`, if it's mapped by a 4KiB page
+    pub fn page_flags(&self, page: Page) -> Option<EntryFlags> {
+        self.p4().next_table(page.p4_index())
+            .and_then(|p3| p3.next_table(page.p3_index()))
+            .and_then(|p2| p2.next_table(page.p2_index()))
+            .map(|p1| p1[page.p1_index()].flags())
+            .and_then(|flags| if flags.contains(PRESENT) { Some(flags) } else { None })
+    }
 
+    /// Map `page` to `frame`. Fails with `ENOMEM`, leaving `page` unmapped, if there's no frame
+    /// left for a page table on the way. `frame` is left to the caller then.
+    pub fn map_to<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags, allocator: &mut A)
+                     -> Result<()>
+        where A: FrameAllocator
+    {
+        let p1 = self.p1_create(page, allocator)?;
         assert!(p1[page.p1_index()].is_unused());
         p1[page.p1_index()].set(frame, flags | PRESENT);
+        Ok(())
     }
 
-    pub fn map<A>(&mut self, page: Page, flags: EntryFlags, allocator: &mut A)
+    /// Create the page tables that `page` needs to be mapped. Fails with `ENOMEM` if there are
+    /// no frames left for them.
+    pub fn create_tables<A>(&mut self, page: Page, allocator: &mut A) -> Result<()>
         where A: FrameAllocator
     {
-        let frame = allocator.allocate_frame().expect("out of memory");
-        self.map_to(page, frame, flags, allocator)
+        self.p1_create(page, allocator).map(|_| ())
     }
 
+    fn p1_create<A>(&mut self, page: Page, allocator: &mut A) -> Result<&mut Table<Level1>>
+        where A: FrameAllocator
+    {
+        let p3 = self.p4_mut().next_table_create(page.p4_index(), allocator)?;
+        let p2 = p3.next_table_create(page.p3_index(), allocator)?;
+        p2.next_table_create(page.p2_index(), allocator)
+    }
+
+    /// Map `page` to a fresh frame. Fails with `ENOMEM` if there are no frames left.
+    pub fn map<A>(&mut self, page: Page, flags: EntryFlags, allocator: &mut A) -> Result<()>
+        where A: FrameAllocator
+    {
+        let frame = allocator.allocate_frame().ok_or(Error::new(ENOMEM))?;
+        if let Err(error) = self.map_to(page, frame.clone(), flags, allocator) {
+            allocator.deallocate_frame(frame);
+            return Err(error);
+        }
+        Ok(())
+    }
+
+    /// Map `pages` to fresh, contiguous frames. Fails with `ENOMEM`, mapping nothing, if there
+    /// aren't enough of them.
     pub fn map_range<A>(&mut self, pages: PageIter, flags: EntryFlags, allocator: &mut A)
+                        -> Result<()>
         where A: FrameAllocator
     {
-        let frames = allocator.allocate_frames(pages.size()).expect("out of memory");
-        for (page, frame) in pages.zip(frames) {
-            self.map_to(page, frame, flags, allocator)
+        let mut frames = allocator.allocate_frames(pages.size()).ok_or(Error::new(ENOMEM))?;
+        let first = pages.start;
+        for page in pages {
+            let frame = frames.next().expect("fewer frames than pages");
+            if let Err(error) = self.map_to(page, frame.clone(), flags, allocator) {
+                if page > first {
+                    self.unmap_range(Page::range_inclusive(first, page - 1), allocator);
+                }
+                allocator.deallocate_frame(frame);
+                for frame in frames {
+                    allocator.deallocate_frame(frame);
+                }
+                return Err(error);
+            }
         }
+        Ok(())
     }
 
-    pub fn map_range_to<A>(&mut self, pages: PageIter, frames: FrameIter, flags: EntryFlags, allocator: &mut A)
+    /// Map `pages` to `frames`, which stay the caller's. Fails with `ENOMEM`, mapping nothing, if
+    /// there are no frames left for the page tables.
+    pub fn map_range_to<A>(&mut self, pages: PageIter, frames: FrameIter, flags: EntryFlags,
+                           allocator: &mut A) -> Result<()>
         where A: FrameAllocator
     {
+        let first = pages.start;
         for (page, frame) in pages.zip(frames) {
-            self.map_to(page, frame, flags, allocator)
+            if let Err(error) = self.map_to(page, frame, flags, allocator) {
+                if page > first {
+                    let mut batch = TlbBatch::new(self.address_space());
+                    for mapped in Page::range_inclusive(first, page - 1) {
+                        self.clear_entry(mapped);
+                        batch.add(mapped);
+                    }
+                    batch.invalidate();
+                }
+                return Err(error);
+            }
         }
+        Ok(())
     }
 
     pub fn identity_map<A>(&mut self,
                            frame: Frame,
                            flags: EntryFlags,
                            allocator: &mut A)
+                           -> Result<()>
         where A: FrameAllocator
     {
         let page = Page::containing_address(frame.start_address());
@@ -116,9 +194,31 @@ impl Mapper {
     pub fn unmap<A>(&mut self, page: Page, allocator: &mut A)
         where A: FrameAllocator
     {
-        use x86_64;
-        use x86_64::instructions::tlb;
+        let mut batch = TlbBatch::new(self.address_space());
+        self.unmap_batched(page, &mut batch, allocator);
+        batch.flush(allocator);
+    }
+
+    /// Unmap `page`, leaving the TLB flush and freeing its frame to `batch`
+    pub fn unmap_batched<A>(&mut self, page: Page, batch: &mut TlbBatch, allocator: &mut A)
+        where A: FrameAllocator
+    {
+        let frame = self.clear_entry(page);
+        batch.add(page);
+        batch.free_later(frame, allocator);
+    }
 
+    /// Unmap `page` and hand back the frame it mapped instead of freeing it. Only this CPU's TLB
+    /// is flushed, so `page` must be one no other CPU uses, like the temporary page, and the
+    /// mapper the active address space's.
+    pub fn unmap_keep_frame_local(&mut self, page: Page) -> Frame {
+        let frame = self.clear_entry(page);
+        tlb::flush(x86_64::VirtualAddress(page.start_address()));
+        frame
+    }
+
+    /// Clear the entry mapping `page`, returning the frame it pointed to
+    fn clear_entry(&mut self, page: Page) -> Frame {
         assert!(self.translate(page.start_address()).is_some());
 
         let p1 = self.p4_mut()
@@ -128,16 +228,99 @@ impl Mapper {
                      .expect("mapping code does not support huge pages");
         let frame = p1[page.p1_index()].pointed_frame().unwrap();
         p1[page.p1_index()].set_unused();
-        tlb::flush(x86_64::VirtualAddress(page.start_address()));
         // TODO free p(1,2,3) table if empty
-        allocator.deallocate_frame(frame);
+        frame
     }
 
     pub fn unmap_range<A>(&mut self, pages: PageIter, allocator: &mut A)
         where A: FrameAllocator
     {
+        let mut batch = TlbBatch::new(self.address_space());
+        for page in pages {
+            self.unmap_batched(page, &mut batch, allocator);
+        }
+        batch.flush(allocator);
+    }
+
+    /// Unmap whichever pages of `pages` are mapped, freeing their frames. Returns how many there
+    /// were.
+    pub fn unmap_mapped<A>(&mut self, pages: PageIter, allocator: &mut A) -> usize
+        where A: FrameAllocator
+    {
+        let mut batch = TlbBatch::new(self.address_space());
+        let mut count = 0;
         for page in pages {
-            self.unmap(page, allocator);
+            if self.translate_page(page).is_some() {
+                self.unmap_batched(page, &mut batch, allocator);
+                count += 1;
+            }
         }
+        batch.flush(allocator);
+        count
+    }
+
+    /// Change the flags of whichever pages of `pages` are mapped to `flags`
+    pub fn set_flags(&mut self, pages: PageIter, flags: EntryFlags) {
+        let mut batch = TlbBatch::new(self.address_space());
+        for page in pages {
+            let p1 = match self.p4_mut().next_table_mut(page.p4_index())
+                                  .and_then(|p3| p3.next_table_mut(page.p3_index()))
+                                  .and_then(|p2| p2.next_table_mut(page.p2_index())) {
+                Some(p1) => p1,
+                None => continue,
+            };
+            if let Some(frame) = p1[page.p1_index()].pointed_frame() {
+                p1[page.p1_index()].set(frame, flags | PRESENT);
+                batch.add(page);
+            }
+        }
+        // rights may have been taken away
+        batch.invalidate();
+    }
+
+    /// Free every frame and page table mapped outside the kernel's shared part, leaving only the
+    /// kernel and the recursive mapping. The address space must not be active on any CPU, so its
+    /// TLB entries are left alone.
+    pub fn free_user_part<A>(&mut self, allocator: &mut A)
+        where A: FrameAllocator
+    {
+        for p4_index in KERNEL_SHARED_END / PML4_SIZE..511 {
+            {
+                let p3 = match self.p4_mut().next_table_mut(p4_index) {
+                    Some(p3) => p3,
+                    None => continue,
+                };
+                for p3_index in 0..ENTRY_COUNT {
+                    {
+                        let p2 = match p3.next_table_mut(p3_index) {
+                            Some(p2) => p2,
+                            None => continue,
+                        };
+                        for p2_index in 0..ENTRY_COUNT {
+                            {
+                                let p1 = match p2.next_table_mut(p2_index) {
+                                    Some(p1) => p1,
+                                    None => continue,
+                                };
+                                for p1_index in 0..ENTRY_COUNT {
+                                    free_entry(&mut p1[p1_index], allocator);
+                                }
+                            }
+                            free_entry(&mut p2[p2_index], allocator);
+                        }
+                    }
+                    free_entry(&mut p3[p3_index], allocator);
+                }
+            }
+            free_entry(&mut self.p4_mut()[p4_index], allocator);
+        }
+    }
+}
+
+/// Clear `entry`, freeing the frame it points to
+fn free_entry<A: FrameAllocator>(entry: &mut Entry, allocator: &mut A) {
+    if let Some(frame) = entry.pointed_frame() {
+        allocator.deallocate_frame(frame);
     }
+    entry.set_unused();
 }
@@ -4,14 +4,17 @@ use multiboot2::BootInformation;
 
 use super::{PAGE_SIZE, Frame, FrameAllocator};
 
-pub use self::entry::{EntryFlags, PRESENT, WRITABLE};
+pub use self::entry::{EntryFlags, PRESENT, WRITABLE, NO_CACHE, NO_EXECUTE, USER_ACCESSIBLE};
 pub use self::mapper::Mapper;
 pub use self::temporary_page::TemporaryPage;
+pub use self::tlb::{handle_shootdown, TlbBatch};
 
 mod entry;
 mod mapper;
+pub mod pcid;
 mod table;
 mod temporary_page;
+mod tlb;
 
 const ENTRY_COUNT: usize = 512;
 
@@ -146,27 +149,44 @@ impl ActivePageTable {
         use x86_64::instructions::tlb;
         use x86_64::registers::control_regs;
 
-        let flush_tlb = || tlb::flush_all();
+        // Only the holder of the memory controller goes through the recursive mapping, so only
+        // this CPU can have cached the redirected entry. Other CPUs learn of changes `f` makes
+        // through the shootdowns of the mapper.
+        let cr3 = control_regs::cr3().0 as usize;
+        let pcid = self.p4()[511].pcid();
 
         {
-            let backup = Frame::containing_address(control_regs::cr3().0 as usize);
+            let backup = Frame::containing_address(cr3);
 
             // map temporary_page to current p4 table
             let p4_table = temporary_page.map_table_frame(backup.clone(), self, allocator);
 
             // overwrite recursive mapping
-            self.p4_mut()[511].set(table.p4_frame.clone(), PRESENT | WRITABLE);
-            flush_tlb();
+            p4_table[511].set(table.p4_frame.clone(), PRESENT | WRITABLE);
+            if pcid::enabled() {
+                // Make the changes under a PCID of their own, which this flushes of whatever the
+                // last changes left behind. The translations of the active address space are
+                // left alone.
+                unsafe { asm!("mov cr3, $0" : : "r"(cr3 & !pcid::PCID_MASK | pcid::EDIT_PCID)
+                              : "memory" : "intel", "volatile"); }
+            } else {
+                tlb::flush_all();
+            }
 
             // execute f in the new context
             f(self, allocator);
 
             // restore recursive mapping to original p4 table
             p4_table[511].set(backup, PRESENT | WRITABLE);
-            flush_tlb();
+            p4_table[511].set_pcid(pcid);
+            if pcid::enabled() {
+                unsafe { pcid::load_cr3_no_flush(cr3); }
+            } else {
+                tlb::flush_all();
+            }
         }
         
-        temporary_page.unmap(self, allocator);
+        temporary_page.unmap(self);
     }
 
     pub fn switch(&mut self, new_table: InactivePageTable) -> InactivePageTable {
@@ -175,16 +195,32 @@ impl ActivePageTable {
 
         let old_table = InactivePageTable {
             p4_frame: Frame::containing_address(control_regs::cr3().0 as usize),
+            pcid: self.p4()[511].pcid(),
         };
+        let cr3 = pcid::cr3(new_table.p4_frame.start_address(), new_table.pcid);
         unsafe {
-            control_regs::cr3_write(x86_64::PhysicalAddress(new_table.p4_frame.start_address() as u64));
+            control_regs::cr3_write(x86_64::PhysicalAddress(cr3 as u64));
         }
         old_table
     }
+
+    /// Load CR3 again, with the PCID of the active table if PCIDs have been enabled since it was
+    /// loaded
+    pub fn reload(&mut self) {
+        use x86_64;
+        use x86_64::registers::control_regs;
+
+        let p4_address = control_regs::cr3().0 as usize & !pcid::PCID_MASK;
+        let cr3 = pcid::cr3(p4_address, self.p4()[511].pcid());
+        unsafe {
+            control_regs::cr3_write(x86_64::PhysicalAddress(cr3 as u64));
+        }
+    }
 }
 
 pub struct InactivePageTable {
     p4_frame: Frame,
+    pcid: usize,
 }
 
 impl InactivePageTable {
@@ -194,6 +230,7 @@ impl InactivePageTable {
                                   allocator: &mut A)
                                   -> InactivePageTable
     {
+        let pcid = pcid::allocate();
         {
             let table =
                 temporary_page.map_table_frame(frame.clone(), active_table, allocator);
@@ -201,10 +238,25 @@ impl InactivePageTable {
             table.zero();
             // set up recursive mapping for the table
             table[511].set(frame.clone(), PRESENT | WRITABLE);
+            table[511].set_pcid(pcid);
+        }
+        temporary_page.unmap(active_table);
+
+        InactivePageTable {
+            p4_frame: frame,
+            pcid: pcid,
         }
-        temporary_page.unmap(active_table, allocator);
+    }
+
+    /// The value CR3 holds while the table is active
+    pub fn cr3(&self) -> usize {
+        pcid::cr3(self.p4_frame.start_address(), self.pcid)
+    }
 
-        InactivePageTable { p4_frame: frame }
+    /// Free the P4 table and its PCID. Everything it maps must have been freed already.
+    pub fn free<A: FrameAllocator>(self, allocator: &mut A) {
+        pcid::free(self.p4_frame.start_address(), self.pcid);
+        allocator.deallocate_frame(self.p4_frame);
     }
 }
 
@@ -237,19 +289,22 @@ pub fn remap_kernel<A: FrameAllocator>(allocator: &mut A, boot_info: &BootInform
             let start_frame = Frame::containing_address(section.start_address());
             let end_frame = Frame::containing_address(section.end_address() - 1);
             for frame in Frame::range_inclusive(start_frame, end_frame) {
-                mapper.identity_map(frame, flags, allocator);
+                mapper.identity_map(frame, flags, allocator)
+                      .expect("could not map the kernel");
             }
         }
 
         // identity map the VGA text buffer
         let vga_buffer_frame = Frame::containing_address(0xb8000);
-        mapper.identity_map(vga_buffer_frame, WRITABLE, allocator);
+        mapper.identity_map(vga_buffer_frame, WRITABLE, allocator)
+              .expect("could not map the kernel");
 
         // identity map the multiboot info structure
         let multiboot_start = Frame::containing_address(boot_info.start_address());
         let multiboot_end = Frame::containing_address(boot_info.end_address() - 1);
         for frame in Frame::range_inclusive(multiboot_start, multiboot_end) {
-            mapper.identity_map(frame, PRESENT, allocator);
+            mapper.identity_map(frame, PRESENT, allocator)
+                  .expect("could not map the kernel");
         }
     });
 
@@ -0,0 +1,140 @@
+// Process-context identifiers. With PCIDs enabled the TLB tags each translation with the PCID of
+// the address space it came from, so switching address spaces doesn't have to flush the TLB, and
+// the translations of an address space can be invalidated without it being active. Each page
+// table gets a PCID when it's created, kept in the ignored bits of its recursive entry. Tables
+// created after the PCIDs ran out share PCID 0, which is flushed whenever it's loaded.
+//
+// Without PCID and INVPCID support, CR3 keeps its PCID bits clear and every switch flushes.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use irq_lock::IrqMutex;
+
+use cpu;
+use super::PhysicalAddress;
+
+/// The bits of CR3 holding the PCID
+pub const PCID_MASK: usize = 0xFFF;
+/// Shared by the page tables that didn't get a PCID of their own, and the boot page table
+pub const UNTAGGED_PCID: usize = 0;
+/// Tags the translations made while `ActivePageTable::with` has the recursive mapping redirected
+pub const EDIT_PCID: usize = 0xFFF;
+/// Page tables are handed out PCIDs below this, as the recursive entry only has room for 11 bits
+pub const MAX_PCID: usize = 0x800;
+
+const CR4_PCIDE: usize = 1 << 17;
+const CR3_NO_FLUSH: usize = 1 << 63;
+
+const CPUID_1_ECX_PCID: u32 = 1 << 17;
+const CPUID_7_EBX_INVPCID: u32 = 1 << 10;
+
+const INVPCID_ADDRESS: usize = 0;
+const INVPCID_CONTEXT: usize = 1;
+const INVPCID_ALL_NON_GLOBAL: usize = 3;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Bitmap of the PCIDs that have been handed out
+static ALLOCATED: IrqMutex<[u64; MAX_PCID / 64]> =
+    IrqMutex::named(lock_class!("pcid::ALLOCATED"), [0; MAX_PCID / 64]);
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+fn supported() -> bool {
+    let (_, _, ecx, _) = cpu::cpuid(1, 0);
+    let (max_leaf, _, _, _) = cpu::cpuid(0, 0);
+    let invpcid = max_leaf >= 7 && cpu::cpuid(7, 0).1 & CPUID_7_EBX_INVPCID != 0;
+    ecx & CPUID_1_ECX_PCID != 0 && invpcid
+}
+
+/// Enable PCIDs on the calling CPU if it supports them. The boot processor decides for all CPUs.
+/// CR3 must be loaded with PCID 0.
+pub fn init_cpu() {
+    let boot_processor = ::percpu::cpu_id() == 0;
+    if boot_processor {
+        ENABLED.store(supported(), Ordering::SeqCst);
+    } else if enabled() {
+        assert!(supported(), "CPU {} doesn't support PCIDs", ::percpu::cpu_id());
+    }
+
+    if enabled() {
+        unsafe {
+            let cr4: usize;
+            asm!("mov $0, cr4" : "=r"(cr4) : : : "intel", "volatile");
+            asm!("mov cr4, $0" : : "r"(cr4 | CR4_PCIDE) : "memory" : "intel", "volatile");
+        }
+        if boot_processor {
+            println!("Address spaces are tagged with PCIDs");
+        }
+    }
+}
+
+/// Hand out a PCID for a new page table, or `UNTAGGED_PCID` if they ran out
+pub fn allocate() -> usize {
+    let mut allocated = ALLOCATED.lock();
+    // PCID 0 stays reserved for the untagged tables
+    for pcid in 1..MAX_PCID {
+        if allocated[pcid / 64] & 1 << (pcid % 64) == 0 {
+            allocated[pcid / 64] |= 1 << (pcid % 64);
+            return pcid;
+        }
+    }
+    UNTAGGED_PCID
+}
+
+/// Give back the PCID of a page table that's gone. Its translations are dropped on every CPU
+/// first, so the next table to get it starts out clean.
+pub fn free(p4_address: PhysicalAddress, pcid: usize) {
+    if pcid == UNTAGGED_PCID {
+        return;
+    }
+    super::tlb::flush_address_space(p4_address | pcid);
+    ALLOCATED.lock()[pcid / 64] &= !(1 << (pcid % 64));
+}
+
+/// The value to load CR3 with to switch to the page table at `p4_address`
+pub fn cr3(p4_address: PhysicalAddress, pcid: usize) -> usize {
+    if enabled() {
+        p4_address | pcid
+    } else {
+        p4_address
+    }
+}
+
+/// The bit to set when loading `cr3` so the TLB keeps the translations of its address space
+#[inline(always)]
+pub fn no_flush_bit(cr3: usize) -> usize {
+    // translations tagged with the untagged PCID may come from another address space
+    if enabled() && cr3 & PCID_MASK != UNTAGGED_PCID {
+        CR3_NO_FLUSH
+    } else {
+        0
+    }
+}
+
+/// Load CR3 with `cr3` without flushing the TLB
+pub unsafe fn load_cr3_no_flush(cr3: usize) {
+    asm!("mov cr3, $0" : : "r"(cr3 | CR3_NO_FLUSH) : "memory" : "intel", "volatile");
+}
+
+unsafe fn invpcid(kind: usize, pcid: usize, address: usize) {
+    let descriptor: [u64; 2] = [pcid as u64, address as u64];
+    asm!("invpcid $0, [$1]" : : "r"(kind), "r"(&descriptor) : "memory" : "intel", "volatile");
+}
+
+/// Invalidate the translation of `address` tagged with `pcid`
+pub fn invalidate_address(pcid: usize, address: usize) {
+    unsafe { invpcid(INVPCID_ADDRESS, pcid, address); }
+}
+
+/// Invalidate every translation tagged with `pcid`
+pub fn invalidate_context(pcid: usize) {
+    unsafe { invpcid(INVPCID_CONTEXT, pcid, 0); }
+}
+
+/// Invalidate the translations of every address space
+pub fn invalidate_all() {
+    unsafe { invpcid(INVPCID_ALL_NON_GLOBAL, 0, 0); }
+}
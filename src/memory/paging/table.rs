@@ -4,6 +4,8 @@ use core::marker::PhantomData;
 use super::entry::*;
 use super::ENTRY_COUNT;
 use super::super::FrameAllocator;
+use syscall::{Error, Result};
+use syscall::error::ENOMEM;
 
 pub const P4: *mut Table<Level4> = 0xffffffff_fffff000 as *mut _;
 
@@ -41,17 +43,20 @@ impl<L: HierarchicalLevel> Table<L> {
             .map(|address| unsafe { &mut *(address as *mut _) })
     }
 
+    /// The next level table at `index`, created if there isn't one. Fails with `ENOMEM` if there's
+    /// no frame left for it.
     pub fn next_table_create<A: FrameAllocator>(&mut self, index: usize, allocator: &mut A)
-                                                -> &mut Table<L::NextLevel>
+                                                -> Result<&mut Table<L::NextLevel>>
     {
         if self.next_table(index).is_none() {
             assert!(!self.entries[index].flags().contains(HUGE_PAGE),
                     "mapping code does not support huge pages");
-            let frame = allocator.allocate_frame().expect("no frames available");
-            self.entries[index].set(frame, PRESENT | WRITABLE);
+            let frame = allocator.allocate_frame().ok_or(Error::new(ENOMEM))?;
+            // the rights of a page are decided by its own entry
+            self.entries[index].set(frame, PRESENT | WRITABLE | USER_ACCESSIBLE);
             self.next_table_mut(index).unwrap().zero();
         }
-        self.next_table_mut(index).unwrap()
+        Ok(self.next_table_mut(index).unwrap())
     }
 }
 
@@ -1,3 +1,6 @@
+use x86_64;
+use x86_64::instructions::tlb;
+
 use super::super::{Frame, FrameAllocator};
 use super::{ActivePageTable, Page, VirtualAddress};
 use super::table::{Table, Level1};
@@ -15,6 +18,10 @@ impl TemporaryPage {
 
     /// Maps the temporary page to the given frame in the active table.
     /// Returns the start address of the temporary page.
+    ///
+    /// Only the CPU holding the memory controller uses the temporary page, so its TLB entries are
+    /// flushed on this CPU alone, without a shootdown. Other CPUs may still have cached it while
+    /// another CPU had it mapped, which is harmless as they flush it before they use it.
     pub fn map<A: FrameAllocator>(&mut self, frame: Frame, active_table: &mut ActivePageTable,
                                   allocator: &mut A) -> VirtualAddress
     {
@@ -22,14 +29,17 @@ impl TemporaryPage {
 
         assert!(active_table.translate_page(self.page).is_none(),
                 "temporary page is already mapped");
-        active_table.map_to(self.page, frame, WRITABLE, allocator);
+        // every page table gets the temporary page's page tables when it's created
+        active_table.map_to(self.page, frame, WRITABLE, allocator)
+                    .expect("no memory for the temporary page's page tables");
+        // whatever this CPU cached of it, e.g. speculatively, may point at an earlier frame
+        tlb::flush(x86_64::VirtualAddress(self.page.start_address()));
         self.page.start_address()
     }
 
-    /// Unmaps the temporary page in the active table.
-    pub fn unmap<A: FrameAllocator>(&mut self, active_table: &mut ActivePageTable,
-                                    allocator: &mut A) {
-        active_table.unmap(self.page, allocator)
+    /// Unmaps the temporary page in the active table. The frame it was mapped to is left alone.
+    pub fn unmap(&mut self, active_table: &mut ActivePageTable) {
+        active_table.unmap_keep_frame_local(self.page);
     }
 
     /// Maps the temporary page to the given page table frame in the active
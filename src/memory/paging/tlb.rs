@@ -0,0 +1,198 @@
+// Keeping the TLBs of all CPUs in step with the page tables. Changes that take rights away, like
+// unmapping a page, are collected in a `TlbBatch` for the address space they were made in, and
+// carried out with a single shootdown: every other CPU is sent an IPI, flushes the range if it may
+// cache translations for it, and acknowledges. The frames the changes released are only freed once
+// every CPU has acknowledged, so nothing can reach them through a stale translation.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use irq_lock::IrqMutex;
+use x86_64;
+use x86_64::instructions::tlb;
+use x86_64::registers::control_regs;
+
+use interrupts::ipi::{self, TLB_SHOOTDOWN_VECTOR};
+use memory::{PAGE_SIZE, KERNEL_SHARED_END, Frame, FrameAllocator};
+use smp::MAX_CPUS;
+use super::{pcid, Page, PhysicalAddress, VirtualAddress};
+
+/// Ranges of more pages than this are flushed by flushing the whole TLB instead
+const MAX_INVLPG_PAGES: usize = 32;
+/// Frames a batch holds on to before it has to flush. Batches live on the stack, as the page
+/// tables are changed before there's a heap.
+const BATCH_FRAMES: usize = 32;
+
+/// Held by the CPU carrying out a shootdown until every other CPU has acknowledged it
+static SHOOTDOWN: IrqMutex<()> = IrqMutex::named(lock_class!("tlb::SHOOTDOWN"), ());
+
+// the range to flush in the current shootdown
+static REQUEST_ADDRESS_SPACE: AtomicUsize = AtomicUsize::new(0);
+static REQUEST_START: AtomicUsize = AtomicUsize::new(0);
+static REQUEST_END: AtomicUsize = AtomicUsize::new(0);
+
+/// Set for each CPU that has yet to carry out the current shootdown, indexed by CPU ID
+static PENDING: [AtomicBool; MAX_CPUS] = [
+    AtomicBool::new(false), AtomicBool::new(false), AtomicBool::new(false), AtomicBool::new(false),
+    AtomicBool::new(false), AtomicBool::new(false), AtomicBool::new(false), AtomicBool::new(false),
+    AtomicBool::new(false), AtomicBool::new(false), AtomicBool::new(false), AtomicBool::new(false),
+    AtomicBool::new(false), AtomicBool::new(false), AtomicBool::new(false), AtomicBool::new(false),
+];
+
+/// Invalidations collected while changing the page tables of one address space, identified by the
+/// physical address of its P4 table and its PCID, like in CR3. `flush` carries them out, and must
+/// be called before the batch is dropped.
+pub struct TlbBatch {
+    address_space: PhysicalAddress,
+    start: VirtualAddress,
+    /// Exclusive. Equal to `start` while the batch is empty.
+    end: VirtualAddress,
+    /// Numbers of the frames to free once no CPU can reach them anymore
+    frames: [usize; BATCH_FRAMES],
+    frame_count: usize,
+}
+
+impl TlbBatch {
+    pub fn new(address_space: PhysicalAddress) -> TlbBatch {
+        TlbBatch {
+            address_space: address_space,
+            start: 0,
+            end: 0,
+            frames: [0; BATCH_FRAMES],
+            frame_count: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    /// Invalidate the translation of `page`
+    pub fn add(&mut self, page: Page) {
+        let start = page.start_address();
+        if self.is_empty() {
+            self.start = start;
+            self.end = start + PAGE_SIZE;
+        } else {
+            self.start = self.start.min(start);
+            self.end = self.end.max(start + PAGE_SIZE);
+        }
+    }
+
+    /// Free `frame` once the batch is flushed. Flushes early if the batch is full.
+    pub fn free_later<A: FrameAllocator>(&mut self, frame: Frame, allocator: &mut A) {
+        if self.frame_count == BATCH_FRAMES {
+            self.flush(allocator);
+        }
+        self.frames[self.frame_count] = frame.number;
+        self.frame_count += 1;
+    }
+
+    /// Invalidate the collected translations on every CPU, then free the collected frames. The
+    /// batch is empty afterwards.
+    pub fn flush<A: FrameAllocator>(&mut self, allocator: &mut A) {
+        self.invalidate();
+        for &number in &self.frames[..self.frame_count] {
+            allocator.deallocate_frame(Frame { number: number });
+        }
+        self.frame_count = 0;
+    }
+
+    /// Invalidate the collected translations on every CPU, keeping the collected frames for
+    /// `flush`
+    pub fn invalidate(&mut self) {
+        if !self.is_empty() {
+            flush_local(self.address_space, self.start, self.end);
+            shootdown(self.address_space, self.start, self.end);
+            self.end = self.start;
+        }
+    }
+}
+
+impl Drop for TlbBatch {
+    fn drop(&mut self) {
+        debug_assert!(self.is_empty() && self.frame_count == 0, "TlbBatch dropped without a flush");
+    }
+}
+
+/// Invalidate the whole user part of `address_space` on every CPU
+pub fn flush_address_space(address_space: PhysicalAddress) {
+    const USER_END: VirtualAddress = 0x0000_8000_0000_0000;
+
+    flush_local(address_space, KERNEL_SHARED_END, USER_END);
+    shootdown(address_space, KERNEL_SHARED_END, USER_END);
+}
+
+/// Invalidate `start..end` of `address_space` on this CPU, if it may have any of it cached
+fn flush_local(address_space: PhysicalAddress, start: VirtualAddress, end: VirtualAddress) {
+    let pages = (end - start) / PAGE_SIZE;
+
+    if pcid::enabled() {
+        // translations of every address space this CPU ran may still be around, tagged with their
+        // PCIDs
+        let pcid = address_space & pcid::PCID_MASK;
+        if start < KERNEL_SHARED_END {
+            // the kernel's part could be cached under any PCID
+            pcid::invalidate_all();
+        } else if pages > MAX_INVLPG_PAGES {
+            pcid::invalidate_context(pcid);
+        } else {
+            let mut address = start;
+            while address < end {
+                pcid::invalidate_address(pcid, address);
+                address += PAGE_SIZE;
+            }
+        }
+        return;
+    }
+
+    let active = control_regs::cr3().0 as usize & !pcid::PCID_MASK;
+    if start >= KERNEL_SHARED_END && active != address_space & !pcid::PCID_MASK {
+        // translations of other address spaces don't survive the switch to this one
+        return;
+    }
+
+    if pages > MAX_INVLPG_PAGES {
+        tlb::flush_all();
+    } else {
+        let mut address = start;
+        while address < end {
+            tlb::flush(x86_64::VirtualAddress(address));
+            address += PAGE_SIZE;
+        }
+    }
+}
+
+/// Have every other CPU invalidate `start..end` of `address_space`, and wait until they all have
+fn shootdown(address_space: PhysicalAddress, start: VirtualAddress, end: VirtualAddress) {
+    if ::smp::cpu_count() == 1 {
+        return;
+    }
+
+    let _shootdown = SHOOTDOWN.lock();
+    REQUEST_ADDRESS_SPACE.store(address_space, Ordering::SeqCst);
+    REQUEST_START.store(start, Ordering::SeqCst);
+    REQUEST_END.store(end, Ordering::SeqCst);
+
+    let this_cpu = ::percpu::cpu_id();
+    for cpu in (0..MAX_CPUS).filter(|&cpu| cpu != this_cpu && ::smp::is_online(cpu)) {
+        PENDING[cpu].store(true, Ordering::SeqCst);
+    }
+    ipi::send_all_but_self(TLB_SHOOTDOWN_VECTOR);
+
+    while PENDING.iter().any(|pending| pending.load(Ordering::SeqCst)) {
+        unsafe { asm!("pause" : : : "memory" : "intel", "volatile"); }
+    }
+}
+
+/// Carry out the current shootdown if this CPU hasn't yet. Called from the shootdown IPI, and by
+/// CPUs spinning on a lock with interrupts disabled, which could otherwise hold up the initiator
+/// forever.
+pub fn handle_shootdown() {
+    let pending = &PENDING[::percpu::cpu_id()];
+    if pending.load(Ordering::SeqCst) {
+        flush_local(REQUEST_ADDRESS_SPACE.load(Ordering::SeqCst),
+                    REQUEST_START.load(Ordering::SeqCst),
+                    REQUEST_END.load(Ordering::SeqCst));
+        pending.store(false, Ordering::SeqCst);
+    }
+}
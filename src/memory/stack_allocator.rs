@@ -1,57 +1,67 @@
+use memory::page_allocator::PageAllocator;
 use memory::paging::{self, ActivePageTable, Page, PageIter};
 use memory::{PAGE_SIZE, FrameAllocator};
+use syscall::{Error, Result};
+use syscall::error::{EINVAL, ENOMEM};
 
 pub struct StackAllocator {
-    range: PageIter,
+    /// Each stack takes its pages along with the guard page underneath
+    pages: PageAllocator,
 }
 
 impl StackAllocator {
     pub fn new(page_range: PageIter) -> StackAllocator {
-        StackAllocator { range: page_range }
+        StackAllocator { pages: PageAllocator::new(page_range) }
     }
 
+    /// Map a stack of `size_in_pages` pages, with an unmapped guard page underneath. Fails with
+    /// `ENOMEM` if there's no room or no memory left for it.
     pub fn alloc_stack<FA: FrameAllocator>(&mut self,
                                            active_table: &mut ActivePageTable,
                                            frame_allocator: &mut FA,
                                            size_in_pages: usize)
-                                           -> Option<Stack> {
+                                           -> Result<Stack> {
         if size_in_pages == 0 {
-            return None; /* a zero sized stack makes no sense */
+            return Err(Error::new(EINVAL)); /* a zero sized stack makes no sense */
         }
 
-        // clone the range, since we only want to change it on success
-        let mut range = self.range.clone();
-
-        // try to allocate the stack pages and a guard page
-        let guard_page = range.next();
-        let stack_start = range.next();
-        let stack_end = if size_in_pages == 1 {
-            stack_start
-        } else {
-            // choose the (size_in_pages-2)th element, since index
-            // starts at 0 and we already allocated the start page
-            range.nth(size_in_pages - 2)
+        // allocate the stack pages and a guard page
+        let pages = match self.pages.allocate(size_in_pages + 1) {
+            Some(pages) => pages,
+            None => return Err(Error::new(ENOMEM)), /* not enough pages */
         };
+        let start = Page::containing_address(pages.start_address()) + 1;
+        let end = start + (size_in_pages - 1);
 
-        match (guard_page, stack_start, stack_end) {
-            (Some(_), Some(start), Some(end)) => {
-                // success! write back updated range
-                self.range = range;
-
-                // map stack pages to physical frames
-                for page in Page::range_inclusive(start, end) {
-                    active_table.map(page, paging::WRITABLE, frame_allocator);
+        // map stack pages to physical frames
+        for page in Page::range_inclusive(start, end) {
+            if let Err(error) = active_table.map(page, paging::WRITABLE, frame_allocator) {
+                if page > start {
+                    active_table.unmap_range(Page::range_inclusive(start, page - 1),
+                                             frame_allocator);
                 }
-
-                // create a new stack
-                let top_of_stack = end.start_address() + PAGE_SIZE;
-                Some(Stack::new(top_of_stack, start.start_address()))
+                self.pages.free(pages);
+                return Err(error);
             }
-            _ => None, /* not enough pages */
         }
+
+        // create a new stack
+        let top_of_stack = end.start_address() + PAGE_SIZE;
+        Ok(Stack::new(top_of_stack, start.start_address()))
     }
-}
 
+    /// Unmap `stack`, freeing its frames, and give its pages back for later stacks
+    pub fn free_stack<FA: FrameAllocator>(&mut self,
+                                          active_table: &mut ActivePageTable,
+                                          frame_allocator: &mut FA,
+                                          stack: Stack) {
+        let start = Page::containing_address(stack.bottom());
+        let end = Page::containing_address(stack.top() - 1);
+        active_table.unmap_range(Page::range_inclusive(start, end), frame_allocator);
+        // the guard page goes back along with the stack
+        self.pages.free(Page::range_inclusive(start - 1, end));
+    }
+}
 #[derive(Debug)]
 pub struct Stack {
     top: usize,
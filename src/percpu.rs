@@ -0,0 +1,175 @@
+// Per-CPU data. Each CPU has a block at `KERNEL_PERCPU_OFFSET + id * KERNEL_PERCPU_SIZE`, found
+// through its GS base while in the kernel: a fixed `PerCpu` header, followed by a copy of the
+// `.percpu` section, which holds the variables declared with `percpu!`.
+//
+// User code has its own GS base. Every way into the kernel from user mode swaps the two with
+// `swapgs`: the syscall entry points in assembly, and `KernelGs` in interrupt handlers.
+
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use x86_64::registers::msr::{wrmsr, IA32_GS_BASE, IA32_KERNEL_GSBASE};
+use x86_64::structures::idt::ExceptionStackFrame;
+
+use memory::{MemoryController, KERNEL_PERCPU_OFFSET, KERNEL_PERCPU_SIZE, WRITABLE};
+
+/// Declare a per-CPU variable. Every CPU gets its own copy, starting out as `$init`, and `get`
+/// returns the copy of the CPU it's called on.
+///
+/// ```
+/// percpu! {
+///     static DEPTH: AtomicUsize = AtomicUsize::new(0);
+/// }
+/// ```
+macro_rules! percpu {
+    ($(#[$attr:meta])* static $name:ident: $ty:ty = $init:expr;) => {
+        $(#[$attr])*
+        #[link_section = ".percpu"]
+        static $name: $crate::percpu::PerCpuVar<$ty> = $crate::percpu::PerCpuVar::new($init);
+    };
+}
+
+/// The start of each CPU's block. The field offsets are hardcoded in syscall.asm.
+#[repr(C)]
+pub struct PerCpu {
+    /// Address of this block, so it can be found with a single GS-relative load
+    self_ptr: usize,
+    /// Top of the stack the kernel runs on after entering from user mode
+    pub kernel_rsp: usize,
+    /// Scratch space for the user stack pointer while `syscall_entry` switches stacks
+    pub user_rsp: usize,
+    pub cpu_id: usize,
+}
+
+/// Offset of the copy of the `.percpu` section in each block
+const VARS_OFFSET: usize = 64;
+
+extern "C" {
+    static __percpu_start: u8;
+    static __percpu_end: u8;
+}
+
+/// Set once the boot CPU has its block. Until then, `percpu!` variables are accessed in the
+/// `.percpu` section itself, which is what the block is initialized from.
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// A variable declared with `percpu!`
+pub struct PerCpuVar<T> {
+    /// The initial value every CPU's copy starts from
+    template: T,
+}
+
+// each CPU only ever touches its own copy
+unsafe impl<T> Sync for PerCpuVar<T> {}
+
+impl<T> PerCpuVar<T> {
+    pub const fn new(value: T) -> PerCpuVar<T> {
+        PerCpuVar { template: value }
+    }
+
+    /// This CPU's copy of the variable. A thread can be moved to another CPU whenever it can be
+    /// preempted, so the reference should only be held on to with interrupts disabled.
+    pub fn get(&'static self) -> &'static T {
+        if !READY.load(Ordering::SeqCst) {
+            return &self.template;
+        }
+
+        let offset = &self.template as *const T as usize - section_start();
+        unsafe { &*((this_cpu_base() + VARS_OFFSET + offset) as *const T) }
+    }
+}
+
+fn section_start() -> usize {
+    unsafe { &__percpu_start as *const u8 as usize }
+}
+
+fn section_end() -> usize {
+    unsafe { &__percpu_end as *const u8 as usize }
+}
+
+fn this_cpu_base() -> usize {
+    let base: usize;
+    unsafe { asm!("mov $0, gs:[0]" : "=r"(base) : : : "intel"); }
+    base
+}
+
+fn block_base(cpu_id: usize) -> usize {
+    KERNEL_PERCPU_OFFSET + cpu_id * KERNEL_PERCPU_SIZE
+}
+
+/// Set up the block of CPU `cpu_id`. Done by the boot processor for every CPU, so the application
+/// processors can `load` theirs without taking any locks.
+pub fn setup(cpu_id: usize, memory_controller: &mut MemoryController) {
+    let base = block_base(cpu_id);
+    let vars_size = section_end() - section_start();
+    assert!(VARS_OFFSET + vars_size <= KERNEL_PERCPU_SIZE, "per-CPU variables don't fit");
+
+    memory_controller.map_fixed(base, VARS_OFFSET + vars_size, WRITABLE)
+                     .expect("could not map per-CPU variables");
+
+    unsafe {
+        ptr::write(base as *mut PerCpu, PerCpu {
+            self_ptr: base,
+            kernel_rsp: 0,
+            user_rsp: 0,
+            cpu_id: cpu_id,
+        });
+        ptr::copy_nonoverlapping(section_start() as *const u8, (base + VARS_OFFSET) as *mut u8,
+                                 vars_size);
+    }
+}
+
+/// Point the GS base of the calling CPU at the block of CPU `cpu_id`, which `setup` has prepared.
+/// Each CPU has to do this before it touches any per-CPU variable.
+pub fn load(cpu_id: usize) {
+    unsafe {
+        // we're in the kernel, so the kernel's GS base is the active one
+        wrmsr(IA32_GS_BASE, block_base(cpu_id) as u64);
+        wrmsr(IA32_KERNEL_GSBASE, 0);
+    }
+
+    READY.store(true, Ordering::SeqCst);
+}
+
+/// The header of this CPU's block
+pub fn this_cpu() -> &'static mut PerCpu {
+    assert!(READY.load(Ordering::SeqCst), "per-CPU blocks aren't set up yet");
+    unsafe { &mut *(this_cpu_base() as *mut PerCpu) }
+}
+
+pub fn cpu_id() -> usize {
+    if READY.load(Ordering::SeqCst) {
+        this_cpu().cpu_id
+    } else {
+        0
+    }
+}
+
+/// Set the stack this CPU switches to when entering the kernel through `syscall`
+pub fn set_kernel_stack(top: usize) {
+    this_cpu().kernel_rsp = top;
+}
+
+/// Makes the kernel's GS base the active one in an interrupt handler entered from user mode, and
+/// swaps the user's back in when dropped. Must be created before anything per-CPU is accessed.
+pub struct KernelGs {
+    swapped: bool,
+}
+
+impl KernelGs {
+    pub fn enter(stack_frame: &ExceptionStackFrame) -> KernelGs {
+        let from_user = stack_frame.code_segment & 3 != 0;
+        if from_user {
+            unsafe { asm!("swapgs" : : : "memory" : "intel", "volatile"); }
+        }
+        KernelGs { swapped: from_user }
+    }
+}
+
+impl Drop for KernelGs {
+    fn drop(&mut self) {
+        if self.swapped {
+            unsafe { asm!("swapgs" : : : "memory" : "intel", "volatile"); }
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use collections::Vec;
+use core::cmp;
+
+use memory::{self, AreaFrameAllocator, EntryFlags, InactivePageTable, Mapper, VirtualAddress,
+             PAGE_SIZE, PML4_SIZE, KERNEL_SHARED_END};
+
+/// A page table of a process's own. The kernel's part is shared with every other page table; the
+/// rest, and the frames mapped there, are freed when the address space is dropped.
+pub struct AddressSpace {
+    /// Only `None` while being dropped
+    table: Option<InactivePageTable>,
+}
+
+impl AddressSpace {
+    /// An address space with nothing but the kernel mapped
+    pub fn new() -> Option<AddressSpace> {
+        memory::with_mem_ctrl(|m| {
+            let mut table = match m.new_page_table() {
+                Some(table) => table,
+                None => return None,
+            };
+
+            let kernel_entries: Vec<_> = (0..KERNEL_SHARED_END / PML4_SIZE).map(|i| {
+                let entry = &m.active_table.p4()[i];
+                (entry.pointed_frame().expect("kernel table not mapped"), entry.flags())
+            }).collect();
+
+            m.with_inactive_table(&mut table, |mapper, _allocator| {
+                // Copy kernel mapping
+                for (i, (frame, flags)) in kernel_entries.into_iter().enumerate() {
+                    mapper.p4_mut()[i].set(frame, flags);
+                }
+            });
+
+            Some(AddressSpace { table: Some(table) })
+        })
+    }
+
+    /// The value CR3 holds while the address space is active
+    pub fn cr3(&self) -> usize {
+        self.table.as_ref().unwrap().cr3()
+    }
+
+    /// Run `f` on the page table, active or not
+    pub fn with<F>(&mut self, f: F) where F: FnOnce(&mut Mapper, &mut AreaFrameAllocator) {
+        let table = self.table.as_mut().unwrap();
+        memory::with_mem_ctrl(|m| m.with_inactive_table(table, f));
+    }
+
+    /// Map a fresh page at `address` with `flags`, its contents written by `fill` into a zeroed
+    /// buffer. Returns `None` if out of memory.
+    pub fn map_filled<F>(&mut self, address: VirtualAddress, flags: EntryFlags, fill: F)
+                         -> Option<()>
+        where F: FnOnce(&mut [u8])
+    {
+        let table = self.table.as_mut().unwrap();
+        memory::with_mem_ctrl(|m| m.map_filled(table, address, flags, fill))
+    }
+
+    /// Map fresh pages over `address..address + size` with `flags`, holding `contents` at
+    /// `contents_address` and zeroes everywhere else. Returns `None` if out of memory.
+    pub fn map_copy(&mut self, address: VirtualAddress, size: usize, flags: EntryFlags,
+                    contents: &[u8], contents_address: VirtualAddress) -> Option<()> {
+        let mut page = address / PAGE_SIZE * PAGE_SIZE;
+        while page < address + size {
+            let filled = self.map_filled(page, flags, |bytes| {
+                copy_to_page(bytes, page, contents, contents_address)
+            });
+            if filled.is_none() {
+                return None;
+            }
+            page += PAGE_SIZE;
+        }
+        Some(())
+    }
+
+    /// Map fresh, zeroed pages over `address..address + size` with `flags`. Returns `None` if out
+    /// of memory.
+    pub fn map_zeroed(&mut self, address: VirtualAddress, size: usize, flags: EntryFlags)
+                      -> Option<()> {
+        self.map_copy(address, size, flags, &[], address)
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if let Some(table) = self.table.take() {
+            memory::with_mem_ctrl(|m| m.free_page_table(table));
+        }
+    }
+}
+
+/// Copy the part of `source`, which belongs at `address`, that falls on the page starting at
+/// `page` into `bytes`, the page's contents
+pub fn copy_to_page(bytes: &mut [u8], page: VirtualAddress, source: &[u8],
+                    address: VirtualAddress) {
+    let start = cmp::max(page, address);
+    let end = cmp::min(page + PAGE_SIZE, address + source.len());
+    if start < end {
+        bytes[start - page..end - page]
+            .copy_from_slice(&source[start - address..end - address]);
+    }
+}
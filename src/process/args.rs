@@ -0,0 +1,105 @@
+// The initial stack of a process, laid out the way the System V x86_64 ABI has it. The stack
+// pointer points at argc, followed by the argv and envp pointer arrays, each ended by a null
+// pointer, and the auxiliary vector, ended by an `AT_NULL` entry. The strings they point to go in
+// the argument area at `USER_ARG_OFFSET`.
+
+use collections::Vec;
+use core::{mem, slice};
+
+use memory::{VirtualAddress, NO_EXECUTE, USER_ACCESSIBLE, WRITABLE, PAGE_SIZE, USER_ARG_OFFSET,
+             USER_STACK_OFFSET, USER_STACK_SIZE};
+use super::{AddressSpace, ExecError};
+use super::elf::{self, Image};
+
+/// Most bytes the arguments and environment may take up, terminating nulls included
+pub const ARG_MAX: usize = 128 * 1024;
+
+// auxiliary vector entry types
+const AT_NULL: usize = 0;
+const AT_PHDR: usize = 3;
+const AT_PHENT: usize = 4;
+const AT_PHNUM: usize = 5;
+const AT_PAGESZ: usize = 6;
+const AT_ENTRY: usize = 9;
+
+/// Entries of the auxiliary vector `map_stack` builds, `AT_NULL` included
+const AUXV_ENTRIES: usize = 6;
+
+/// Map the argument area and the stack of a new process with `args` and `env` on them. `data` is
+/// the executable `image` was loaded from. Returns the initial stack pointer. Fails with
+/// `ArgumentsTooLarge` if their pointers take up more than a quarter of the stack, and with
+/// `OutOfMemory`. The sizes of `args` and `env` must have been checked against `ARG_MAX`.
+pub fn map_stack(address_space: &mut AddressSpace, data: &[u8], image: &Image, args: &[&[u8]],
+                 env: &[&[u8]]) -> Result<VirtualAddress, ExecError> {
+    // argc, the two pointer arrays with their nulls, and the auxiliary vector, before any of it
+    // is built. The rest of the stack is the program's, like Linux leaves it.
+    let words_size = (3 + args.len() + env.len() + 2 * AUXV_ENTRIES) * mem::size_of::<usize>();
+    if words_size > USER_STACK_SIZE / 4 {
+        return Err(ExecError::ArgumentsTooLarge);
+    }
+
+    let mut area = Vec::new();
+    let arg_pointers: Vec<_> = args.iter().map(|arg| push_string(&mut area, arg)).collect();
+    let env_pointers: Vec<_> = env.iter().map(|var| push_string(&mut area, var)).collect();
+
+    // the runtime finds the TLS template and such through the program headers, so they have to
+    // be somewhere in memory
+    let program_headers = match image.program_headers {
+        Some(address) => address,
+        None => {
+            while area.len() % mem::align_of::<usize>() != 0 {
+                area.push(0);
+            }
+            let start = image.program_header_offset;
+            let end = start + image.program_header_count * elf::PROGRAM_HEADER_SIZE;
+            let address = USER_ARG_OFFSET + area.len();
+            area.extend_from_slice(&data[start..end]);
+            address
+        }
+    };
+
+    let mut words = Vec::new();
+    words.push(args.len());
+    words.extend(arg_pointers);
+    words.push(0);
+    words.extend(env_pointers);
+    words.push(0);
+    for &(kind, value) in &[(AT_PHDR, program_headers),
+                            (AT_PHENT, elf::PROGRAM_HEADER_SIZE),
+                            (AT_PHNUM, image.program_header_count),
+                            (AT_PAGESZ, PAGE_SIZE),
+                            (AT_ENTRY, image.entry),
+                            (AT_NULL, 0)] {
+        words.push(kind);
+        words.push(value);
+    }
+
+    // the ABI wants the stack pointer 16 byte aligned on entry
+    let stack_top = USER_STACK_OFFSET + USER_STACK_SIZE;
+    let sp = (stack_top - words.len() * mem::size_of::<usize>()) & !0xF;
+    let stack = unsafe {
+        slice::from_raw_parts(words.as_ptr() as *const u8, words.len() * mem::size_of::<usize>())
+    };
+
+    let flags = USER_ACCESSIBLE | WRITABLE | NO_EXECUTE;
+    if !area.is_empty() && address_space.map_copy(USER_ARG_OFFSET, area.len(), flags, &area,
+                                                  USER_ARG_OFFSET).is_none() {
+        return Err(ExecError::OutOfMemory);
+    }
+    address_space.map_copy(USER_STACK_OFFSET, USER_STACK_SIZE, flags, stack, sp)
+                 .map(|_| sp)
+                 .ok_or(ExecError::OutOfMemory)
+}
+
+/// Append `string` and a terminating null to the argument area. Returns where it'll be in memory.
+fn push_string(area: &mut Vec<u8>, string: &[u8]) -> VirtualAddress {
+    let address = USER_ARG_OFFSET + area.len();
+    area.extend_from_slice(string);
+    area.push(0);
+    address
+}
+
+/// Bytes `args` and `env` take up in the argument area, terminating nulls included
+pub fn size(args: &[&[u8]], env: &[&[u8]]) -> usize {
+    args.iter().chain(env).map(|string| string.len() + 1).sum()
+}
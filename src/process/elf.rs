@@ -0,0 +1,369 @@
+// Loading ELF64 executables into an address space. Only statically linked x86_64 executables are
+// supported. Each `PT_LOAD` segment is copied to the address it asks for, which has to be in the
+// user image area, and the `PT_TLS` segment, if there is one, becomes the TLS block of the first
+// thread at `USER_TLS_OFFSET`. Later threads get TLS blocks of their own from the same template,
+// see thread.rs.
+
+use core::{cmp, mem, ptr};
+
+use memory::{EntryFlags, VirtualAddress, NO_EXECUTE, USER_ACCESSIBLE, WRITABLE, PAGE_SIZE,
+             USER_OFFSET, USER_ARG_OFFSET, USER_TLS_OFFSET, USER_TMP_OFFSET};
+use syscall;
+use syscall::error::{ENOEXEC, ENOMEM};
+use super::AddressSpace;
+use super::address_space::copy_to_page;
+
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u32 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 62;
+
+const PT_LOAD: u32 = 1;
+const PT_PHDR: u32 = 6;
+const PT_TLS: u32 = 7;
+
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Header {
+    ident: [u8; 16],
+    kind: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+/// Why a binary couldn't be loaded
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The file is too short to hold an ELF header
+    Truncated,
+    /// The file doesn't start with the ELF magic
+    NotElf,
+    /// Not a 64-bit ELF file
+    Not64Bit,
+    /// Not a little-endian ELF file
+    NotLittleEndian,
+    /// An ELF version other than the current one
+    UnsupportedVersion,
+    /// Not an executable, e.g. a relocatable object or a shared library
+    NotExecutable,
+    /// Built for a machine other than x86_64
+    WrongMachine,
+    /// Program header entries of an unexpected size
+    BadProgramHeaderSize,
+    /// The program header table reaches past the end of the file
+    ProgramHeadersOutOfBounds,
+    /// A segment's contents reach past the end of the file
+    SegmentOutOfBounds,
+    /// A segment has more contents in the file than it has room for in memory
+    SegmentFileSizeTooLarge,
+    /// A segment lies outside of the user image area
+    SegmentOutsideImage,
+    /// Two segments share a page
+    OverlappingSegments,
+    /// There's nothing to load
+    NoLoadableSegments,
+    /// The entry point isn't in an executable segment
+    EntryNotExecutable,
+    /// There's more than one `PT_TLS` segment
+    MultipleTls,
+    /// The TLS alignment isn't a power of two, or is larger than a page
+    BadTlsAlignment,
+    /// The TLS block doesn't fit in the user TLS area
+    TlsTooLarge,
+    OutOfMemory,
+}
+
+impl From<Error> for syscall::Error {
+    fn from(error: Error) -> syscall::Error {
+        match error {
+            Error::OutOfMemory => syscall::Error::new(ENOMEM),
+            _ => syscall::Error::new(ENOEXEC),
+        }
+    }
+}
+
+/// What's needed to start running a loaded program
+#[derive(Clone, Copy, Debug)]
+pub struct Image {
+    pub entry: VirtualAddress,
+    /// Where the first thread's FS base goes: the end of its TLS block, holding a pointer to
+    /// itself. `None` if the program has no TLS.
+    pub thread_pointer: Option<VirtualAddress>,
+    /// Where the program header table ended up in memory, if a segment covers it
+    pub program_headers: Option<VirtualAddress>,
+    /// Offset of the program header table in the file
+    pub program_header_offset: usize,
+    pub program_header_count: usize,
+    /// The TLS template, `None` if the program has no TLS
+    pub tls: Option<Tls>,
+}
+
+/// Where the initialization image of each thread's TLS block is in the file, and how large a
+/// block is
+#[derive(Clone, Copy, Debug)]
+pub struct Tls {
+    /// Offset of the initialization image in the file
+    pub offset: usize,
+    /// Size of the initialization image. The rest of the block is zeroed.
+    pub file_size: usize,
+    /// Size of the block, rounded up to its alignment. The thread pointer follows it.
+    pub size: usize,
+}
+
+/// Size of a program header table entry
+pub const PROGRAM_HEADER_SIZE: usize = 56;
+
+/// Load the executable `data` into `address_space`, which should have nothing of the user's
+/// mapped yet. On error, whatever was mapped already is left for the address space to free.
+pub fn load(data: &[u8], address_space: &mut AddressSpace) -> Result<Image, Error> {
+    let header = read_header(data)?;
+    let program_headers = program_headers(data, &header)?;
+
+    let mut loads = 0;
+    let mut tls = None;
+    let mut entry_executable = false;
+    let mut phdr_segment = None;
+    let mut program_headers_loaded = None;
+    let table_end = header.phoff + header.phnum as u64 * PROGRAM_HEADER_SIZE as u64;
+    for (i, segment) in program_headers.clone().enumerate() {
+        match segment.kind {
+            PT_LOAD => {
+                check_contents(data, &segment)?;
+                let end = segment.vaddr.checked_add(segment.memsz)
+                    .ok_or(Error::SegmentOutsideImage)?;
+                if segment.vaddr < USER_OFFSET as u64 || end > USER_ARG_OFFSET as u64 {
+                    return Err(Error::SegmentOutsideImage);
+                }
+                let earlier = program_headers.clone().take(i);
+                for other in earlier.filter(|other| other.kind == PT_LOAD) {
+                    if overlaps(&segment, &other) {
+                        return Err(Error::OverlappingSegments);
+                    }
+                }
+                if segment.flags & PF_X != 0 && segment.vaddr <= header.entry &&
+                   header.entry < end {
+                    entry_executable = true;
+                }
+                if segment.offset <= header.phoff && table_end <= segment.offset + segment.filesz {
+                    program_headers_loaded = Some(segment.vaddr + (header.phoff - segment.offset));
+                }
+                loads += 1;
+            }
+            PT_PHDR => phdr_segment = Some(segment.vaddr),
+            PT_TLS => {
+                if tls.is_some() {
+                    return Err(Error::MultipleTls);
+                }
+                check_contents(data, &segment)?;
+                tls = Some(segment);
+            }
+            _ => (),
+        }
+    }
+    if loads == 0 {
+        return Err(Error::NoLoadableSegments);
+    }
+    if !entry_executable {
+        return Err(Error::EntryNotExecutable);
+    }
+
+    for segment in program_headers.filter(|segment| segment.kind == PT_LOAD) {
+        load_segment(data, &segment, address_space)?;
+    }
+    let tls = match tls {
+        Some(tls) => Some(tls_layout(&tls)?),
+        None => None,
+    };
+    let thread_pointer = match tls {
+        Some(tls) => Some(load_tls(data, &tls, address_space)?),
+        None => None,
+    };
+
+    Ok(Image {
+        entry: header.entry as usize,
+        thread_pointer: thread_pointer,
+        // a PT_PHDR segment says where the table is explicitly
+        program_headers: phdr_segment.or(program_headers_loaded).map(|address| address as usize),
+        program_header_offset: header.phoff as usize,
+        program_header_count: header.phnum as usize,
+        tls: tls,
+    })
+}
+
+fn read_header(data: &[u8]) -> Result<Header, Error> {
+    if data.len() < mem::size_of::<Header>() {
+        return Err(Error::Truncated);
+    }
+    let header = unsafe { ptr::read_unaligned(data.as_ptr() as *const Header) };
+
+    if header.ident[..4] != ELF_MAGIC {
+        return Err(Error::NotElf);
+    }
+    if header.ident[4] != ELFCLASS64 {
+        return Err(Error::Not64Bit);
+    }
+    if header.ident[5] != ELFDATA2LSB {
+        return Err(Error::NotLittleEndian);
+    }
+    if header.ident[6] as u32 != EV_CURRENT || header.version != EV_CURRENT {
+        return Err(Error::UnsupportedVersion);
+    }
+    if header.kind != ET_EXEC {
+        return Err(Error::NotExecutable);
+    }
+    if header.machine != EM_X86_64 {
+        return Err(Error::WrongMachine);
+    }
+    Ok(header)
+}
+
+/// The entries of the program header table
+#[derive(Clone)]
+struct ProgramHeaders<'a> {
+    table: &'a [u8],
+}
+
+impl<'a> Iterator for ProgramHeaders<'a> {
+    type Item = ProgramHeader;
+
+    fn next(&mut self) -> Option<ProgramHeader> {
+        if self.table.is_empty() {
+            return None;
+        }
+        let entry = unsafe { ptr::read_unaligned(self.table.as_ptr() as *const ProgramHeader) };
+        self.table = &self.table[mem::size_of::<ProgramHeader>()..];
+        Some(entry)
+    }
+}
+
+fn program_headers<'a>(data: &'a [u8], header: &Header) -> Result<ProgramHeaders<'a>, Error> {
+    if header.phnum != 0 && header.phentsize as usize != PROGRAM_HEADER_SIZE {
+        return Err(Error::BadProgramHeaderSize);
+    }
+    let size = header.phnum as u64 * PROGRAM_HEADER_SIZE as u64;
+    match header.phoff.checked_add(size) {
+        Some(end) if end <= data.len() as u64 => Ok(ProgramHeaders {
+            table: &data[header.phoff as usize..end as usize],
+        }),
+        _ => Err(Error::ProgramHeadersOutOfBounds),
+    }
+}
+
+/// Check that the file contents of `segment` are in `data`, and fit in its memory size
+fn check_contents(data: &[u8], segment: &ProgramHeader) -> Result<(), Error> {
+    match segment.offset.checked_add(segment.filesz) {
+        Some(end) if end <= data.len() as u64 => (),
+        _ => return Err(Error::SegmentOutOfBounds),
+    }
+    if segment.filesz > segment.memsz {
+        return Err(Error::SegmentFileSizeTooLarge);
+    }
+    Ok(())
+}
+
+/// The file contents of a checked segment
+fn contents<'a>(data: &'a [u8], segment: &ProgramHeader) -> &'a [u8] {
+    &data[segment.offset as usize..(segment.offset + segment.filesz) as usize]
+}
+
+/// Whether two checked `PT_LOAD` segments have a page in common
+fn overlaps(a: &ProgramHeader, b: &ProgramHeader) -> bool {
+    if a.memsz == 0 || b.memsz == 0 {
+        return false;
+    }
+    let page = |address: u64| address / PAGE_SIZE as u64;
+    page(a.vaddr) <= page(b.vaddr + b.memsz - 1) && page(b.vaddr) <= page(a.vaddr + a.memsz - 1)
+}
+
+fn segment_flags(flags: u32) -> EntryFlags {
+    // every mapped page is readable, so there's nothing to do for PF_R
+    let mut entry_flags = USER_ACCESSIBLE;
+    if flags & PF_W != 0 {
+        entry_flags = entry_flags | WRITABLE;
+    }
+    if flags & PF_X == 0 {
+        entry_flags = entry_flags | NO_EXECUTE;
+    }
+    entry_flags
+}
+
+fn load_segment(data: &[u8], segment: &ProgramHeader, address_space: &mut AddressSpace)
+                -> Result<(), Error> {
+    let start = segment.vaddr as usize;
+    // whatever isn't in the file, like .bss, stays zeroed
+    address_space.map_copy(start, segment.memsz as usize, segment_flags(segment.flags),
+                           contents(data, segment), start)
+        .ok_or(Error::OutOfMemory)
+}
+
+/// Check the alignment and size of the checked `PT_TLS` segment `tls`
+fn tls_layout(tls: &ProgramHeader) -> Result<Tls, Error> {
+    let align = cmp::max(tls.align, 1);
+    if !align.is_power_of_two() || align > PAGE_SIZE as u64 {
+        return Err(Error::BadTlsAlignment);
+    }
+    let size = match tls.memsz.checked_add(align - 1) {
+        Some(size) => size / align * align,
+        None => return Err(Error::TlsTooLarge),
+    };
+    let area = (USER_TMP_OFFSET - USER_TLS_OFFSET) as u64;
+    if size > area - mem::size_of::<u64>() as u64 {
+        return Err(Error::TlsTooLarge);
+    }
+    Ok(Tls {
+        offset: tls.offset as usize,
+        file_size: tls.filesz as usize,
+        size: size as usize,
+    })
+}
+
+/// Map the initial TLS block at `USER_TLS_OFFSET`, followed by the thread pointer, which points
+/// at itself as the x86_64 TLS ABI wants. Returns the thread pointer.
+fn load_tls(data: &[u8], tls: &Tls, address_space: &mut AddressSpace)
+            -> Result<VirtualAddress, Error> {
+    let start = USER_TLS_OFFSET;
+    let thread_pointer = start + tls.size;
+    let end = thread_pointer + mem::size_of::<u64>();
+    let contents = &data[tls.offset..tls.offset + tls.file_size];
+    let self_pointer: [u8; 8] = unsafe { mem::transmute(thread_pointer as u64) };
+
+    let mut page = start;
+    while page < end {
+        address_space.map_filled(page, USER_ACCESSIBLE | WRITABLE | NO_EXECUTE, |bytes| {
+            copy_to_page(bytes, page, contents, start);
+            copy_to_page(bytes, page, &self_pointer, thread_pointer);
+        }).ok_or(Error::OutOfMemory)?;
+        page += PAGE_SIZE;
+    }
+    Ok(thread_pointer)
+}
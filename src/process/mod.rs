@@ -0,0 +1,526 @@
+// Processes: an address space and the threads running in it. Every process has a parent, which
+// collects its exit status with `wait`. When a process exits its other threads are stopped, and
+// once the last of them has been switched away from, its address space is freed. What's left is a
+// zombie holding the exit status until the parent collects it. The children of an exiting process
+// are handed to the kernel process, which reaps them as soon as they exit.
+//
+// A process can start more threads in its address space with `clone`. Each has a stack and a TLS
+// block of its own (see thread.rs), and can end on its own with `exit_thread`, leaving a value for
+// another thread to collect with `join`.
+//
+// Processes can be sent signals, which terminate, stop or continue them, or run a handler in one
+// of their threads (see signal.rs). A process a signal terminates exits with status 128 plus the
+// signal number.
+//
+// The first user process, init, is built into the kernel image (see init_image.asm).
+
+use collections::{BTreeMap, Vec};
+use core::{mem, slice};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use irq_lock::IrqMutex;
+use x86_64::registers::control_regs;
+
+use context::{self, ThreadId, UserStart};
+use memory::{VirtualAddress, USER_OFFSET};
+use scheduler;
+use sync::WaitQueue;
+use syscall::{self, write_user};
+use syscall::error::{E2BIG, EAGAIN, ENOMEM};
+use workqueue;
+
+pub use self::address_space::AddressSpace;
+pub use self::signal::Signals;
+pub use self::thread::{ThreadSlots, TlsTemplate};
+pub use self::vm::{MemoryUsage, Vm};
+
+pub mod args;
+pub mod elf;
+pub mod signal;
+pub mod thread;
+pub mod vm;
+
+mod address_space;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ProcessId(pub usize);
+
+/// The process kernel threads belong to. It runs on the kernel's page table and never exits.
+pub const KERNEL_PID: ProcessId = ProcessId(0);
+
+/// Largest executable the `exec` syscall takes. It copies the whole of it to the kernel heap,
+/// along with up to `args::ARG_MAX` bytes of arguments.
+pub const EXEC_MAX: usize = 256 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Alive,
+    /// Exited with the given status, waiting for its threads to stop
+    Exiting(i32),
+    /// Exited with the given status, waiting for the parent to collect it
+    Zombie(i32),
+}
+
+/// Why `exec` couldn't start a process
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExecError {
+    /// The executable is malformed
+    BadExecutable(elf::Error),
+    /// The arguments and environment take up more than `args::ARG_MAX` bytes, or their pointers
+    /// too much of the stack
+    ArgumentsTooLarge,
+    OutOfMemory,
+}
+
+/// Why `clone` couldn't start a thread
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CloneError {
+    /// The process has `thread::MAX_THREADS` threads, or no room for another TLS block
+    TooManyThreads,
+    OutOfMemory,
+}
+
+impl From<elf::Error> for ExecError {
+    fn from(error: elf::Error) -> ExecError {
+        match error {
+            elf::Error::OutOfMemory => ExecError::OutOfMemory,
+            error => ExecError::BadExecutable(error),
+        }
+    }
+}
+
+impl From<ExecError> for syscall::Error {
+    fn from(error: ExecError) -> syscall::Error {
+        match error {
+            ExecError::BadExecutable(error) => syscall::Error::from(error),
+            ExecError::ArgumentsTooLarge => syscall::Error::new(E2BIG),
+            ExecError::OutOfMemory => syscall::Error::new(ENOMEM),
+        }
+    }
+}
+
+impl From<CloneError> for syscall::Error {
+    fn from(error: CloneError) -> syscall::Error {
+        match error {
+            CloneError::TooManyThreads => syscall::Error::new(EAGAIN),
+            CloneError::OutOfMemory => syscall::Error::new(ENOMEM),
+        }
+    }
+}
+
+pub struct Process {
+    pub pid: ProcessId,
+    pub parent: ProcessId,
+    pub children: Vec<ProcessId>,
+    /// Threads that haven't been freed yet
+    pub threads: Vec<ThreadId>,
+    /// Stacks and TLS blocks of the user threads
+    pub slots: ThreadSlots,
+    /// Values left by threads that ended with `exit_thread`, until they're joined
+    pub exited_threads: BTreeMap<ThreadId, usize>,
+    /// The value CR3 holds while the process runs
+    pub cr3: usize,
+    /// `None` for the kernel process, and once the process has exited
+    pub address_space: Option<AddressSpace>,
+    /// The heap and anonymous mappings
+    pub vm: Vm,
+    pub signals: Signals,
+    pub status: Status,
+}
+
+lazy_static! {
+    static ref PROCESSES: IrqMutex<BTreeMap<ProcessId, Process>> =
+        IrqMutex::named(lock_class!("process::PROCESSES"), BTreeMap::new());
+    /// Woken whenever a process becomes a zombie
+    static ref CHILD_EXITED: WaitQueue = WaitQueue::new();
+    /// Woken whenever a thread of a user process ends
+    static ref THREAD_EXITED: WaitQueue = WaitQueue::new();
+}
+
+static NEXT_PID: AtomicUsize = AtomicUsize::new(1);
+
+/// CR3 of the kernel's page table, which kernel threads run on
+static KERNEL_CR3: AtomicUsize = AtomicUsize::new(0);
+
+extern {
+    // the init executable, see init_image.asm
+    static init_image_start: u8;
+    static init_image_end: u8;
+}
+
+/// Create the kernel process, made up of the threads that are already running. `context::init`
+/// must have been called.
+pub fn init() {
+    let cr3 = control_regs::cr3().0 as usize;
+    KERNEL_CR3.store(cr3, Ordering::SeqCst);
+    PROCESSES.lock().insert(KERNEL_PID, Process {
+        pid: KERNEL_PID,
+        parent: KERNEL_PID,
+        children: Vec::new(),
+        threads: Vec::new(),
+        slots: ThreadSlots::new(None),
+        exited_threads: BTreeMap::new(),
+        cr3: cr3,
+        address_space: None,
+        vm: Vm::new(),
+        signals: Signals::new(),
+        status: Status::Alive,
+    });
+}
+
+pub fn kernel_cr3() -> usize {
+    KERNEL_CR3.load(Ordering::SeqCst)
+}
+
+/// The process of the current thread
+pub fn current() -> ProcessId {
+    context::with_thread(context::current_id(), |thread| thread.process).unwrap_or(KERNEL_PID)
+}
+
+/// Create a process with no threads yet, running in `address_space`, as a child of the current
+/// process
+pub fn create(address_space: AddressSpace, slots: ThreadSlots) -> ProcessId {
+    let pid = ProcessId(NEXT_PID.fetch_add(1, Ordering::SeqCst));
+    let parent = current();
+
+    let mut processes = PROCESSES.lock();
+    processes.insert(pid, Process {
+        pid: pid,
+        parent: parent,
+        children: Vec::new(),
+        threads: Vec::new(),
+        slots: slots,
+        exited_threads: BTreeMap::new(),
+        cr3: address_space.cr3(),
+        address_space: Some(address_space),
+        vm: Vm::new(),
+        signals: Signals::new(),
+        status: Status::Alive,
+    });
+    processes.get_mut(&parent).expect("current process missing").children.push(pid);
+    pid
+}
+
+/// Run `f` on the heap and anonymous mappings of the current process
+pub fn with_vm<F, R>(f: F) -> R where F: FnOnce(&mut Vm) -> R {
+    let pid = current();
+    f(&mut PROCESSES.lock().get_mut(&pid).expect("current process missing").vm)
+}
+
+/// How much memory `pid` uses, if it exists
+pub fn memory_usage(pid: ProcessId) -> Option<MemoryUsage> {
+    PROCESSES.lock().get(&pid).map(|process| process.vm.usage())
+}
+
+/// Called on a fault at `address`, in the lower half, that found no page there. Backs the page
+/// if the current process has it lazily mapped and allows `access`, made up of `vm::PROT_*`
+/// bits. Returns whether it did, and the faulting access can be retried.
+pub fn handle_page_fault(address: VirtualAddress, access: usize) -> bool {
+    if address < USER_OFFSET || current() == KERNEL_PID {
+        return false;
+    }
+    with_vm(|vm| vm.populate(address, access))
+}
+
+/// Start a child process of the current one, running the ELF executable `data` with the
+/// arguments `args` and the environment `env`
+pub fn exec(data: &[u8], args: &[&[u8]], env: &[&[u8]]) -> Result<ProcessId, ExecError> {
+    if args::size(args, env) > args::ARG_MAX {
+        return Err(ExecError::ArgumentsTooLarge);
+    }
+
+    let mut address_space = AddressSpace::new().ok_or(ExecError::OutOfMemory)?;
+    let image = elf::load(data, &mut address_space)?;
+    let sp = args::map_stack(&mut address_space, data, &image, args, env)?;
+
+    let tls = image.tls.map(|tls| TlsTemplate {
+        data: data[tls.offset..tls.offset + tls.file_size].to_vec(),
+        size: tls.size,
+    });
+    let pid = create(address_space, ThreadSlots::new(tls));
+    let start = UserStart {
+        ip: image.entry,
+        sp: sp,
+        arg: 0,
+        fs_base: image.thread_pointer.unwrap_or(0),
+    };
+    match spawn_user_thread(pid, start, 0) {
+        Some(_) => Ok(pid),
+        None => {
+            discard(pid);
+            Err(ExecError::OutOfMemory)
+        }
+    }
+}
+
+/// Start init, the first user process, from the executable built into the kernel
+pub fn start_init() -> ProcessId {
+    let image = unsafe {
+        let start = &init_image_start as *const u8;
+        slice::from_raw_parts(start, &init_image_end as *const u8 as usize - start as usize)
+    };
+    match exec(image, &[&b"init"[..]], &[]) {
+        Ok(pid) => pid,
+        Err(error) => panic!("could not start init: {:?}", error),
+    }
+}
+
+/// Remove `pid`, a child of the current process that never got to run a thread
+fn discard(pid: ProcessId) {
+    let address_space = {
+        let mut processes = PROCESSES.lock();
+        let process = processes.remove(&pid).expect("discarded process missing");
+        processes.get_mut(&process.parent).expect("parent process missing")
+                 .children.retain(|&child| child != pid);
+        process.address_space
+    };
+    drop(address_space);
+}
+
+/// Start a thread in `pid` that runs `entry`. Returns `None` if there's no such process, or it's
+/// exiting.
+pub fn spawn_thread(pid: ProcessId, entry: fn()) -> Option<ThreadId> {
+    start_thread(pid, entry, None, None)
+}
+
+/// Start a thread in `pid` that enters user mode where `start` says, running in the thread slot
+/// `slot`, which was taken for it. Returns `None` if there's no such process, or it's exiting.
+pub fn spawn_user_thread(pid: ProcessId, start: UserStart, slot: usize) -> Option<ThreadId> {
+    start_thread(pid, context::enter_user, Some(start), Some(slot))
+}
+
+fn start_thread(pid: ProcessId, entry: fn(), user_start: Option<UserStart>, slot: Option<usize>)
+                -> Option<ThreadId> {
+    let cr3 = match PROCESSES.lock().get(&pid) {
+        Some(process) if process.status == Status::Alive => process.cr3,
+        _ => return None,
+    };
+    let id = match context::create_in(pid, cr3, entry) {
+        Some(id) => id,
+        None => return None,
+    };
+    context::with_thread(id, |thread| {
+        thread.user_start = user_start;
+        thread.fs_base = user_start.map_or(0, |start| start.fs_base);
+    });
+
+    if let Some(process) = PROCESSES.lock().get_mut(&pid) {
+        process.threads.push(id);
+        if let Some(slot) = slot {
+            process.slots.assign(slot, id);
+        }
+    }
+    scheduler::enqueue(id);
+    Some(id)
+}
+
+/// Start a thread in the current process that enters user mode at `ip`, with `arg` in its first
+/// argument register, on a stack and TLS block of its own
+pub fn clone(ip: usize, arg: usize) -> Result<ThreadId, CloneError> {
+    let pid = current();
+    assert!(pid != KERNEL_PID, "the kernel process can't clone user threads");
+
+    let slot = {
+        let mut processes = PROCESSES.lock();
+        let process = processes.get_mut(&pid).expect("current process missing");
+        process.slots.take(&mut process.vm)?
+    };
+    // the thread starts out as if `ip` had just been called, with a null return address. This
+    // backs the top page of the stack, out of the process lock.
+    let sp = slot.stack_top - mem::size_of::<usize>();
+    let spawned = match write_user(sp, &0usize) {
+        Ok(()) => {
+            let start = UserStart {
+                ip: ip,
+                sp: sp,
+                arg: arg,
+                fs_base: slot.thread_pointer,
+            };
+            spawn_user_thread(pid, start, slot.index)
+        }
+        Err(_) => None,
+    };
+    match spawned {
+        Some(id) => Ok(id),
+        None => {
+            if let Some(process) = PROCESSES.lock().get_mut(&pid) {
+                process.slots.release(slot.index);
+            }
+            Err(CloneError::OutOfMemory)
+        }
+    }
+}
+
+/// End the current thread, leaving `value` for `join`. The process exits with status 0 if this
+/// was its last thread.
+pub fn exit_thread(value: usize) -> ! {
+    let pid = current();
+    if pid != KERNEL_PID {
+        if let Some(process) = PROCESSES.lock().get_mut(&pid) {
+            process.exited_threads.insert(context::current_id(), value);
+        }
+        THREAD_EXITED.wake_all();
+    }
+    scheduler::exit();
+}
+
+/// Wait for the thread `id` of the current process to end with `exit_thread`, and collect the
+/// value it left. Returns `None` if there's no such thread, it ended some other way or was joined
+/// already, or the current thread is interrupted in the meantime.
+pub fn join(id: ThreadId) -> Option<usize> {
+    let pid = current();
+    let mut result = None;
+
+    THREAD_EXITED.wait_until(|| {
+        if context::interrupted() {
+            return true;
+        }
+        let mut processes = PROCESSES.lock();
+        let process = processes.get_mut(&pid).expect("current process missing");
+        match process.exited_threads.remove(&id) {
+            Some(value) => {
+                result = Some(value);
+                true
+            }
+            // nothing to wait for
+            None => !process.threads.contains(&id),
+        }
+    });
+
+    result
+}
+
+/// End the current process with `status`, stopping all of its threads
+pub fn exit(status: i32) -> ! {
+    let pid = current();
+    assert!(pid != KERNEL_PID, "the kernel process can't exit");
+    terminate(&mut PROCESSES.lock(), pid, status);
+    scheduler::exit();
+}
+
+/// End the user process `pid` with `status`, unless it has exited already. Its threads stop on
+/// their way back to user mode, and the blocked ones are woken up to get there.
+fn terminate(processes: &mut BTreeMap<ProcessId, Process>, pid: ProcessId, status: i32) {
+    let children = {
+        let process = processes.get_mut(&pid).expect("terminated process missing");
+        // if another thread got here first, its status stands
+        if process.status != Status::Alive {
+            return;
+        }
+        process.status = Status::Exiting(status);
+
+        for &id in &process.threads {
+            context::with_thread(id, |thread| thread.exit_pending = true);
+            scheduler::wake(id);
+        }
+
+        mem::replace(&mut process.children, Vec::new())
+    };
+
+    for child in children {
+        let zombie = {
+            let process = processes.get_mut(&child).expect("child process missing");
+            process.parent = KERNEL_PID;
+            match process.status {
+                Status::Zombie(_) => true,
+                _ => false,
+            }
+        };
+        if zombie {
+            processes.remove(&child);
+        } else {
+            processes.get_mut(&KERNEL_PID).unwrap().children.push(child);
+        }
+    }
+}
+
+/// Wait for a child to exit, or the child `pid` if given, and collect its exit status. Returns
+/// `None` if there's no such child, or the current thread is interrupted in the meantime.
+pub fn wait(pid: Option<ProcessId>) -> Option<(ProcessId, i32)> {
+    let parent = current();
+    let mut result = None;
+
+    CHILD_EXITED.wait_until(|| {
+        // another thread ended the process, or it has signals to act on
+        if context::interrupted() {
+            return true;
+        }
+        let mut processes = PROCESSES.lock();
+        let zombie = {
+            let children = &processes.get(&parent).expect("current process missing").children;
+            let mut candidates = children.iter().cloned()
+                .filter(|&child| pid.map_or(true, |pid| pid == child))
+                .peekable();
+            if candidates.peek().is_none() {
+                // nothing to wait for
+                return true;
+            }
+            candidates.filter_map(|child| match processes[&child].status {
+                Status::Zombie(status) => Some((child, status)),
+                _ => None,
+            }).next()
+        };
+
+        match zombie {
+            Some((child, status)) => {
+                processes.remove(&child);
+                processes.get_mut(&parent).unwrap().children.retain(|&other| other != child);
+                result = Some((child, status));
+                true
+            }
+            None => false,
+        }
+    });
+
+    result
+}
+
+/// Called once an exited thread of `pid` has been freed. Frees the process's address space when it
+/// was the last one.
+pub fn thread_freed(pid: ProcessId, id: ThreadId) {
+    let last = {
+        let mut processes = PROCESSES.lock();
+        match processes.get_mut(&pid) {
+            Some(process) => {
+                process.threads.retain(|&thread| thread != id);
+                process.slots.thread_freed(id);
+                pid != KERNEL_PID && process.threads.is_empty()
+            }
+            None => return,
+        }
+    };
+    if !last {
+        // whoever waits to join the thread finds it gone, if it didn't call `exit_thread`
+        if pid != KERNEL_PID {
+            THREAD_EXITED.wake_all();
+        }
+        return;
+    }
+
+    let address_space = {
+        let mut processes = PROCESSES.lock();
+        let (address_space, parent) = {
+            let process = processes.get_mut(&pid).expect("exiting process missing");
+
+            // a process whose last thread returned without calling `exit` exits with status 0
+            let status = match process.status {
+                Status::Exiting(status) => status,
+                _ => 0,
+            };
+            process.status = Status::Zombie(status);
+            (process.address_space.take(), process.parent)
+        };
+
+        if parent == KERNEL_PID {
+            processes.remove(&pid);
+            processes.get_mut(&KERNEL_PID).unwrap().children.retain(|&child| child != pid);
+        }
+        address_space
+    };
+
+    // tearing down a page table takes a while, and this runs in the middle of a thread switch
+    if let Some(address_space) = address_space {
+        workqueue::schedule(move || drop(address_space));
+    }
+    CHILD_EXITED.wake_all();
+}
@@ -0,0 +1,483 @@
+// Signals: notifications sent to a process, by another process with `kill` or by the kernel when
+// one of its threads faults. Each process has a set of pending signals, a set of blocked ones, and
+// an action for each signal: its default, ignoring it, or running a handler.
+//
+// Default actions that concern the whole process, terminating, stopping and continuing it, are
+// carried out as soon as a signal is sent. Handlers run on whichever thread of the process next
+// returns to user mode: blocking syscalls give up with `EINTR` so a thread gets there, and the
+// timer catches threads that never make syscalls. A handler runs on the thread's stack, below a
+// `SignalFrame` holding the interrupted registers, FPU registers included, and returns into a
+// restorer, provided along with the handler, which calls `sigreturn` to put them back.
+
+use core::{mem, ptr};
+
+use context::{self, fpu};
+use interrupts::SyscallFrame;
+use memory::{VirtualAddress, USER_OFFSET};
+use scheduler;
+use sync::WaitQueue;
+use syscall::{self, copy_from_user, copy_to_user, read_user, write_user};
+use syscall::error::{EPERM, ESRCH};
+use super::{current, Process, ProcessId, Status, KERNEL_PID, PROCESSES};
+
+/// Signals are numbered 1 to `NSIG - 1`
+pub const NSIG: usize = 32;
+
+pub const SIGHUP: usize = 1;
+pub const SIGINT: usize = 2;
+pub const SIGQUIT: usize = 3;
+pub const SIGILL: usize = 4;
+pub const SIGTRAP: usize = 5;
+pub const SIGABRT: usize = 6;
+pub const SIGBUS: usize = 7;
+pub const SIGFPE: usize = 8;
+pub const SIGKILL: usize = 9;
+pub const SIGUSR1: usize = 10;
+pub const SIGSEGV: usize = 11;
+pub const SIGUSR2: usize = 12;
+pub const SIGPIPE: usize = 13;
+pub const SIGALRM: usize = 14;
+pub const SIGTERM: usize = 15;
+pub const SIGCHLD: usize = 17;
+pub const SIGCONT: usize = 18;
+pub const SIGSTOP: usize = 19;
+pub const SIGTSTP: usize = 20;
+pub const SIGTTIN: usize = 21;
+pub const SIGTTOU: usize = 22;
+pub const SIGURG: usize = 23;
+pub const SIGWINCH: usize = 28;
+
+/// Handlers that stand for the default action and for ignoring the signal
+pub const SIG_DFL: usize = 0;
+pub const SIG_IGN: usize = 1;
+
+/// How `sigprocmask` changes the blocked signals
+pub const SIG_BLOCK: usize = 0;
+pub const SIG_UNBLOCK: usize = 1;
+pub const SIG_SETMASK: usize = 2;
+
+/// Signals that can't be caught, ignored or blocked
+const UNBLOCKABLE: u32 = 1 << SIGKILL | 1 << SIGSTOP;
+const STOP_SIGNALS: u32 = 1 << SIGSTOP | 1 << SIGTSTP | 1 << SIGTTIN | 1 << SIGTTOU;
+
+/// Bytes below the interrupted stack pointer that leaf functions may use without moving it
+const RED_ZONE: usize = 128;
+
+/// RFLAGS bits a signal handler may change in the registers it returns to: the arithmetic flags,
+/// the trap flag and the direction flag
+const USER_RFLAGS: usize = 0xDD5;
+const RFLAGS_TF: usize = 1 << 8;
+const RFLAGS_DF: usize = 1 << 10;
+
+/// End of the lower half, where user code runs
+const USER_END: usize = 0x0000_8000_0000_0000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Default,
+    Ignore,
+    /// Run `handler` with `mask` blocked on top of the signal itself, and return into `restorer`
+    Handler { handler: VirtualAddress, restorer: VirtualAddress, mask: u32 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum DefaultAction {
+    Terminate,
+    Ignore,
+    Stop,
+    Continue,
+}
+
+fn default_action(signal: usize) -> DefaultAction {
+    match signal {
+        SIGCHLD | SIGURG | SIGWINCH => DefaultAction::Ignore,
+        SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU => DefaultAction::Stop,
+        SIGCONT => DefaultAction::Continue,
+        _ => DefaultAction::Terminate,
+    }
+}
+
+/// Exit status of a process a signal terminated
+pub fn killed_status(signal: usize) -> i32 {
+    128 + signal as i32
+}
+
+pub fn is_valid(signal: usize) -> bool {
+    signal > 0 && signal < NSIG
+}
+
+/// The signal state of a process
+pub struct Signals {
+    pending: u32,
+    blocked: u32,
+    actions: [Action; NSIG],
+    /// Set by a stop signal until `SIGCONT` arrives. The threads stop on their way to user mode.
+    stopped: bool,
+}
+
+impl Signals {
+    pub fn new() -> Signals {
+        Signals {
+            pending: 0,
+            blocked: 0,
+            actions: [Action::Default; NSIG],
+            stopped: false,
+        }
+    }
+
+    /// Pending signals that a thread should act on
+    fn deliverable(&self) -> u32 {
+        self.pending & !self.blocked
+    }
+
+    /// Take the lowest deliverable signal off the pending set
+    fn take(&mut self) -> Option<usize> {
+        let deliverable = self.deliverable();
+        if deliverable == 0 {
+            return None;
+        }
+        let signal = deliverable.trailing_zeros() as usize;
+        self.pending &= !(1 << signal);
+        Some(signal)
+    }
+
+    fn ignores(&self, signal: usize) -> bool {
+        match self.actions[signal] {
+            Action::Ignore => true,
+            Action::Default => default_action(signal) == DefaultAction::Ignore,
+            Action::Handler { .. } => false,
+        }
+    }
+}
+
+lazy_static! {
+    /// Woken whenever a stopped process continues
+    static ref CONTINUED: WaitQueue = WaitQueue::new();
+}
+
+/// Why `send` couldn't send a signal
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SendError {
+    NoSuchProcess,
+    /// The kernel process doesn't take signals
+    NotPermitted,
+}
+
+impl From<SendError> for syscall::Error {
+    fn from(error: SendError) -> syscall::Error {
+        match error {
+            SendError::NoSuchProcess => syscall::Error::new(ESRCH),
+            SendError::NotPermitted => syscall::Error::new(EPERM),
+        }
+    }
+}
+
+/// Send `signal` to `pid`. A signal of 0 only checks that the process exists.
+pub fn send(pid: ProcessId, signal: usize) -> Result<(), SendError> {
+    if pid == KERNEL_PID {
+        return Err(SendError::NotPermitted);
+    }
+
+    let continued = {
+        let mut processes = PROCESSES.lock();
+        match processes.get(&pid) {
+            Some(process) if process.status == Status::Alive => (),
+            Some(_) => return Ok(()),
+            None => return Err(SendError::NoSuchProcess),
+        }
+        if signal == 0 {
+            return Ok(());
+        }
+
+        let bit = 1 << signal;
+        let (terminate, wake_threads, continued) = {
+            let signals = &mut processes.get_mut(&pid).unwrap().signals;
+            let mut continued = false;
+            if signal == SIGCONT {
+                // continuing can't be blocked or ignored, only what else the signal does
+                signals.pending &= !STOP_SIGNALS;
+                continued = signals.stopped;
+                signals.stopped = false;
+            } else if bit & STOP_SIGNALS != 0 {
+                signals.pending &= !(1 << SIGCONT);
+            }
+
+            let mut terminate = false;
+            let mut wake_threads = false;
+            if signal == SIGKILL {
+                terminate = true;
+            } else if signals.ignores(signal) {
+                // ignored signals are dropped right away, even while blocked
+            } else if signals.blocked & bit != 0 {
+                signals.pending |= bit;
+            } else {
+                match signals.actions[signal] {
+                    Action::Default => match default_action(signal) {
+                        DefaultAction::Terminate => terminate = true,
+                        DefaultAction::Stop => {
+                            signals.stopped = true;
+                            wake_threads = true;
+                        }
+                        DefaultAction::Ignore | DefaultAction::Continue => (),
+                    },
+                    Action::Ignore => (),
+                    Action::Handler { .. } => {
+                        signals.pending |= bit;
+                        wake_threads = true;
+                    }
+                }
+            }
+            (terminate, wake_threads, continued)
+        };
+
+        if terminate {
+            super::terminate(&mut processes, pid, killed_status(signal));
+        } else if wake_threads {
+            notify(&processes[&pid]);
+        }
+        continued
+    };
+
+    if continued {
+        CONTINUED.wake_all();
+    }
+    Ok(())
+}
+
+/// Set the action of `signal` in the current process. Returns the old one.
+pub fn set_action(signal: usize, action: Action) -> Action {
+    let pid = current();
+    let mut processes = PROCESSES.lock();
+    let signals = &mut processes.get_mut(&pid).expect("current process missing").signals;
+    let old = mem::replace(&mut signals.actions[signal], action);
+    if signals.ignores(signal) {
+        signals.pending &= !(1 << signal);
+    }
+    old
+}
+
+/// Whether `signal` can't be caught, ignored or blocked
+pub fn is_unblockable(signal: usize) -> bool {
+    UNBLOCKABLE & 1 << signal != 0
+}
+
+/// Replace the blocked signals of the current process with `f` of them. Returns the old set.
+pub fn update_blocked<F>(f: F) -> u32 where F: FnOnce(u32) -> u32 {
+    let pid = current();
+    let mut processes = PROCESSES.lock();
+    let signals = &mut processes.get_mut(&pid).expect("current process missing").signals;
+    let old = signals.blocked;
+    signals.blocked = f(old) & !UNBLOCKABLE;
+    if signals.deliverable() != 0 {
+        // the caller is on its way back to user mode, and takes care of them
+        context::with_thread(context::current_id(), |thread| thread.signal_pending = true);
+    }
+    old
+}
+
+/// Mark every thread of `process` as having signals to act on, and wake the blocked ones
+fn notify(process: &Process) {
+    for &id in &process.threads {
+        context::with_thread(id, |thread| thread.signal_pending = true);
+        scheduler::wake(id);
+    }
+}
+
+/// Raise `signal` for a fault of the current thread, whose user registers are `frame`. Unless the
+/// process has a handler for it that isn't blocked, the process is terminated, as the thread
+/// can't go on past the fault.
+pub fn force(frame: &mut SyscallFrame, signal: usize) {
+    let pid = current();
+    let handled = {
+        let mut processes = PROCESSES.lock();
+        let signals = &mut processes.get_mut(&pid).expect("current process missing").signals;
+        let action = signals.actions[signal];
+        match action {
+            Action::Handler { .. } if signals.blocked & 1 << signal == 0 => {
+                signals.pending |= 1 << signal;
+                true
+            }
+            _ => false,
+        }
+    };
+    if !handled {
+        println!("process {} killed by signal {} at {:#x}", pid.0, signal, frame.rip);
+        super::exit(killed_status(signal));
+    }
+    context::with_thread(context::current_id(), |thread| thread.signal_pending = true);
+    return_to_user(frame);
+}
+
+/// What a thread returning to user mode has to do about its signals
+enum Next {
+    Stop,
+    Signal(usize, Action, u32),
+}
+
+/// Called with the user registers `frame` of the current thread on its way back to user mode.
+/// Stops the thread if its process has exited or been stopped, and acts on the process's pending
+/// signals, rewriting `frame` to run a handler if there is one.
+pub fn return_to_user(frame: &mut SyscallFrame) {
+    if !context::interrupted() {
+        return;
+    }
+    let pid = current();
+    let id = context::current_id();
+
+    loop {
+        if context::exit_pending() {
+            scheduler::exit();
+        }
+        context::with_thread(id, |thread| thread.signal_pending = false);
+
+        let next = {
+            let mut processes = PROCESSES.lock();
+            let signals = &mut processes.get_mut(&pid).expect("current process missing").signals;
+            if signals.stopped {
+                Next::Stop
+            } else {
+                match signals.take() {
+                    Some(signal) => Next::Signal(signal, signals.actions[signal], signals.blocked),
+                    None => return,
+                }
+            }
+        };
+
+        match next {
+            Next::Stop => CONTINUED.wait_until(|| {
+                context::exit_pending() ||
+                    PROCESSES.lock().get(&pid).map_or(true, |process| !process.signals.stopped)
+            }),
+            Next::Signal(signal, Action::Handler { handler, restorer, mask }, blocked) => {
+                if !run_handler(frame, signal, handler, restorer, blocked) {
+                    println!("process {} killed by signal {}: no room for a signal frame", pid.0,
+                             SIGSEGV);
+                    super::exit(killed_status(SIGSEGV));
+                }
+                let mut processes = PROCESSES.lock();
+                let signals = &mut processes.get_mut(&pid).unwrap().signals;
+                signals.blocked |= (mask | 1 << signal) & !UNBLOCKABLE;
+                if signals.deliverable() != 0 {
+                    // the rest interrupt the handler at the next tick or syscall
+                    context::with_thread(id, |thread| thread.signal_pending = true);
+                }
+                return;
+            }
+            Next::Signal(_, Action::Ignore, _) => (),
+            Next::Signal(signal, Action::Default, _) => match default_action(signal) {
+                DefaultAction::Terminate => super::exit(killed_status(signal)),
+                DefaultAction::Stop => {
+                    let mut processes = PROCESSES.lock();
+                    let process = processes.get_mut(&pid).unwrap();
+                    process.signals.stopped = true;
+                    notify(process);
+                }
+                DefaultAction::Ignore | DefaultAction::Continue => (),
+            },
+        }
+    }
+}
+
+/// What a signal handler finds on its stack
+#[repr(C)]
+struct SignalFrame {
+    /// Where the handler returns to, the restorer that calls `sigreturn`
+    return_address: VirtualAddress,
+    signal: usize,
+    /// The blocked signals to go back to
+    blocked: usize,
+    /// Where the interrupted FPU registers are, right above the frame
+    fpu: VirtualAddress,
+    /// The interrupted registers
+    registers: SyscallFrame,
+}
+
+/// Build a `SignalFrame`, and the FPU registers it points to above it, under the interrupted
+/// stack pointer of `frame`, and point `frame` at `handler`, which gets the signal as its
+/// argument. Returns `false` if they don't fit on the stack.
+fn run_handler(frame: &mut SyscallFrame, signal: usize, handler: VirtualAddress,
+               restorer: VirtualAddress, blocked: u32) -> bool {
+    let state = fpu::save_current();
+    let fpu_address = match frame.rsp.checked_sub(RED_ZONE + state.bytes().len()) {
+        Some(fpu_address) => fpu_address / fpu::AREA_ALIGN * fpu::AREA_ALIGN,
+        None => return false,
+    };
+    let size = mem::size_of::<SignalFrame>();
+    let top = match fpu_address.checked_sub(size) {
+        Some(top) if top >= USER_OFFSET => top,
+        _ => return false,
+    };
+    // the handler sees the stack of a function that was just called
+    let address = (top + 8) / 16 * 16 - 8;
+    let signal_frame = SignalFrame {
+        return_address: restorer,
+        signal: signal,
+        blocked: blocked as usize,
+        fpu: fpu_address,
+        registers: unsafe { ptr::read(frame) },
+    };
+    if copy_to_user(fpu_address, state.bytes()).is_err() ||
+       write_user(address, &signal_frame).is_err() {
+        return false;
+    }
+
+    frame.rip = handler;
+    frame.rsp = address;
+    frame.rdi = signal;
+    // the ABI wants the direction flag clear on function entry
+    frame.rflags &= !(RFLAGS_TF | RFLAGS_DF);
+    true
+}
+
+/// Return from a signal handler: restore the registers and blocked signals saved in the
+/// `SignalFrame` the handler returned from, whose restorer made the syscall with the registers
+/// `frame`. Terminates the process if the frame is gone or garbled.
+pub fn sigreturn(frame: &mut SyscallFrame) {
+    // the handler's `ret` popped the return address
+    let address = frame.rsp.wrapping_sub(mem::size_of::<VirtualAddress>());
+    let saved: SignalFrame = match read_user(address) {
+        Ok(saved) => saved,
+        Err(_) => bad_frame(),
+    };
+    let registers = &saved.registers;
+    if registers.rip >= USER_END || registers.rsp >= USER_END {
+        bad_frame();
+    }
+    // the handler may have changed the FPU registers on its stack, but not into ones that fault
+    let mut bytes = vec![0; fpu::area_size()];
+    if copy_from_user(saved.fpu, &mut bytes).is_err() {
+        bad_frame();
+    }
+    let state = match fpu::FpuState::from_bytes(&bytes) {
+        Some(state) => state,
+        None => bad_frame(),
+    };
+
+    frame.r15 = registers.r15;
+    frame.r14 = registers.r14;
+    frame.r13 = registers.r13;
+    frame.r12 = registers.r12;
+    frame.rbp = registers.rbp;
+    frame.rbx = registers.rbx;
+    frame.r11 = registers.r11;
+    frame.r10 = registers.r10;
+    frame.r9 = registers.r9;
+    frame.r8 = registers.r8;
+    frame.rax = registers.rax;
+    frame.rcx = registers.rcx;
+    frame.rdx = registers.rdx;
+    frame.rsi = registers.rsi;
+    frame.rdi = registers.rdi;
+    frame.rip = registers.rip;
+    frame.rsp = registers.rsp;
+    // the privileged flags stay the kernel's business
+    frame.rflags = frame.rflags & !USER_RFLAGS | registers.rflags & USER_RFLAGS;
+
+    fpu::restore_current(state);
+    update_blocked(|_| saved.blocked as u32);
+}
+
+fn bad_frame() -> ! {
+    println!("process {} killed by signal {}: bad signal frame", current().0, SIGSEGV);
+    super::exit(killed_status(SIGSEGV))
+}
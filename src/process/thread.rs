@@ -0,0 +1,166 @@
+// Stacks and TLS blocks of the user threads of a process. Each thread takes a slot, which says
+// where they go: the stack of slot `n` is the `n`th in the stack area, with an unmapped guard page
+// underneath, and its TLS block the `n`th in the TLS area. The first thread, started by `exec`,
+// has slot 0, which `args::map_stack` and `elf::load` map. Other slots are mapped the first time a
+// thread takes them, and stay mapped after the thread is freed, for the next one: the stack
+// lazily, as an anonymous mapping of the process, and the TLS block right away, to be filled in.
+
+use collections::{BTreeMap, Vec};
+use core::{mem, ptr};
+
+use context::ThreadId;
+use memory::{with_mem_ctrl, VirtualAddress, NO_EXECUTE, USER_ACCESSIBLE, WRITABLE, PAGE_SIZE,
+             USER_STACK_OFFSET, USER_STACK_SIZE, USER_TLS_OFFSET, USER_TMP_OFFSET};
+use super::CloneError;
+use super::vm::{page_align_up, Vm, PROT_READ, PROT_WRITE};
+
+/// Most threads a process can have at once
+pub const MAX_THREADS: usize = 1024;
+
+/// Distance between the stacks of neighbouring slots, leaving room for a guard page
+const STACK_STRIDE: usize = USER_STACK_SIZE + PAGE_SIZE;
+
+/// Initial contents of each thread's TLS block
+pub struct TlsTemplate {
+    /// The initialization image. The rest of the block is zeroed.
+    pub data: Vec<u8>,
+    /// Size of a block, rounded up to its alignment. The thread pointer follows it.
+    pub size: usize,
+}
+
+/// Where a thread that took a slot starts out
+#[derive(Clone, Copy, Debug)]
+pub struct Slot {
+    pub index: usize,
+    /// Top of the slot's stack, 16 byte aligned
+    pub stack_top: VirtualAddress,
+    /// The FS base of the thread, pointing at the end of its TLS block. 0 if the program has no
+    /// TLS.
+    pub thread_pointer: VirtualAddress,
+}
+
+pub struct ThreadSlots {
+    /// Whether each slot is taken. Slots from `taken.len()` on have never been mapped.
+    taken: Vec<bool>,
+    /// The slot of each thread that has one
+    owners: BTreeMap<ThreadId, usize>,
+    /// `None` if the program has no TLS
+    tls: Option<TlsTemplate>,
+}
+
+impl ThreadSlots {
+    /// The slots of a new process, with slot 0 taken for its first thread
+    pub fn new(tls: Option<TlsTemplate>) -> ThreadSlots {
+        ThreadSlots {
+            taken: vec![true],
+            owners: BTreeMap::new(),
+            tls: tls,
+        }
+    }
+
+    /// Take a free slot and get its stack and TLS block ready for a new thread, mapping them if
+    /// the slot has never been used. The process's address space, whose memory is `vm`, must be
+    /// active.
+    pub fn take(&mut self, vm: &mut Vm) -> Result<Slot, CloneError> {
+        let index = match self.taken.iter().position(|&taken| !taken) {
+            Some(index) => index,
+            None => {
+                let index = self.taken.len();
+                if index == MAX_THREADS || !self.tls_fits(index) {
+                    return Err(CloneError::TooManyThreads);
+                }
+                if self.map(index, vm).is_none() {
+                    return Err(CloneError::OutOfMemory);
+                }
+                self.taken.push(false);
+                index
+            }
+        };
+        self.taken[index] = true;
+
+        Ok(Slot {
+            index: index,
+            stack_top: USER_STACK_OFFSET + index * STACK_STRIDE + USER_STACK_SIZE,
+            thread_pointer: self.fill_tls(index),
+        })
+    }
+
+    /// Record that the thread `id` runs in the slot `index`, which was taken for it
+    pub fn assign(&mut self, index: usize, id: ThreadId) {
+        self.owners.insert(id, index);
+    }
+
+    /// Give back the slot `index`, taken for a thread that couldn't be started
+    pub fn release(&mut self, index: usize) {
+        self.taken[index] = false;
+    }
+
+    /// Free the slot of the thread `id`, which nothing runs on anymore
+    pub fn thread_freed(&mut self, id: ThreadId) {
+        if let Some(index) = self.owners.remove(&id) {
+            self.taken[index] = false;
+        }
+    }
+
+    /// Distance between the TLS blocks of neighbouring slots, each followed by its thread
+    /// pointer. 0 if there's no TLS.
+    fn tls_stride(&self) -> usize {
+        self.tls.as_ref().map_or(0, |tls| page_align_up(tls.size + mem::size_of::<usize>()))
+    }
+
+    fn tls_fits(&self, index: usize) -> bool {
+        (index + 1) * self.tls_stride() <= USER_TMP_OFFSET - USER_TLS_OFFSET
+    }
+
+    /// Map the stack and TLS block of the slot `index`. Returns `None`, with nothing left mapped,
+    /// if out of memory.
+    fn map(&self, index: usize, vm: &mut Vm) -> Option<()> {
+        let stride = self.tls_stride();
+        if stride != 0 && map_zeroed(USER_TLS_OFFSET + index * stride, stride).is_none() {
+            return None;
+        }
+        // the stack is backed a page at a time as it's touched, so this is cheap enough to do
+        // under the process lock
+        let stack = USER_STACK_OFFSET + index * STACK_STRIDE;
+        vm.map(Some(stack), USER_STACK_SIZE, PROT_READ | PROT_WRITE)
+          .expect("fixed mappings always fit");
+        Some(())
+    }
+
+    /// Fill in the mapped TLS block of the slot `index` from the template. Returns the thread
+    /// pointer, which points at itself as the x86_64 TLS ABI wants, or 0 if there's no TLS.
+    fn fill_tls(&self, index: usize) -> VirtualAddress {
+        let tls = match self.tls {
+            Some(ref tls) => tls,
+            None => return 0,
+        };
+        let start = USER_TLS_OFFSET + index * self.tls_stride();
+        let thread_pointer = start + tls.size;
+        unsafe {
+            // a reused block holds whatever the last thread left there
+            ptr::copy_nonoverlapping(tls.data.as_ptr(), start as *mut u8, tls.data.len());
+            ptr::write_bytes((start + tls.data.len()) as *mut u8, 0, tls.size - tls.data.len());
+            *(thread_pointer as *mut usize) = thread_pointer;
+        }
+        thread_pointer
+    }
+}
+
+/// Map zeroed pages over the page aligned range `start..start + size` of the active table.
+/// Returns `None`, with nothing left mapped, if out of memory.
+fn map_zeroed(start: VirtualAddress, size: usize) -> Option<()> {
+    let mut page = start;
+    while page < start + size {
+        let mapped = with_mem_ctrl(|m| {
+            m.map_zeroed_page(page, USER_ACCESSIBLE | WRITABLE | NO_EXECUTE)
+        });
+        if mapped.is_none() {
+            if page > start {
+                with_mem_ctrl(|m| m.unmap_user(start, page));
+            }
+            return None;
+        }
+        page += PAGE_SIZE;
+    }
+    Some(())
+}
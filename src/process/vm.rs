@@ -0,0 +1,232 @@
+// The memory a process asks for while it runs: the heap, grown and shrunk with `brk`, and
+// anonymous mappings made with `mmap`, or by `clone` for the stacks of threads outside of the
+// mapping area. Neither is backed by memory up front. The first access to
+// each page faults, and `populate` maps a zeroed frame there.
+
+use collections::{BTreeMap, Vec};
+
+use memory::{with_mem_ctrl, EntryFlags, VirtualAddress, NO_EXECUTE, USER_ACCESSIBLE, WRITABLE,
+             PAGE_SIZE, USER_HEAP_OFFSET, USER_MMAP_OFFSET, USER_GRANT_OFFSET};
+
+pub const PROT_NONE: usize = 0;
+pub const PROT_READ: usize = 1 << 0;
+pub const PROT_WRITE: usize = 1 << 1;
+pub const PROT_EXEC: usize = 1 << 2;
+
+/// End of the heap area. The heap can grow up to where the anonymous mappings start.
+pub const HEAP_END: VirtualAddress = USER_MMAP_OFFSET;
+/// End of the area anonymous mappings are made in
+pub const MMAP_END: VirtualAddress = USER_GRANT_OFFSET;
+
+/// Memory use of a process, in bytes
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MemoryUsage {
+    /// Size of the heap and the anonymous mappings
+    pub virtual_size: usize,
+    /// How much of that is backed by memory
+    pub resident_size: usize,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Mapping {
+    /// Exclusive
+    end: VirtualAddress,
+    prot: usize,
+}
+
+pub struct Vm {
+    /// End of the heap, which starts at `USER_HEAP_OFFSET`
+    brk: VirtualAddress,
+    /// Anonymous mappings by start address, all page aligned
+    mappings: BTreeMap<VirtualAddress, Mapping>,
+    /// Pages of the heap and the mappings that are backed by memory
+    resident_pages: usize,
+}
+
+impl Vm {
+    pub fn new() -> Vm {
+        Vm {
+            brk: USER_HEAP_OFFSET,
+            mappings: BTreeMap::new(),
+            resident_pages: 0,
+        }
+    }
+
+    pub fn usage(&self) -> MemoryUsage {
+        let mapped: usize = self.mappings.iter().map(|(&start, mapping)| mapping.end - start).sum();
+        MemoryUsage {
+            virtual_size: page_align_up(self.brk) - USER_HEAP_OFFSET + mapped,
+            resident_size: self.resident_pages * PAGE_SIZE,
+        }
+    }
+
+    pub fn brk(&self) -> VirtualAddress {
+        self.brk
+    }
+
+    /// Move the end of the heap to `brk`, freeing whatever it gives up. Returns `false` if `brk`
+    /// is outside of the heap area.
+    pub fn set_brk(&mut self, brk: VirtualAddress) -> bool {
+        if brk < USER_HEAP_OFFSET || brk > HEAP_END {
+            return false;
+        }
+        let old_end = page_align_up(self.brk);
+        let new_end = page_align_up(brk);
+        if new_end < old_end {
+            self.release(new_end, old_end);
+        }
+        self.brk = brk;
+        true
+    }
+
+    /// Map `size` bytes, a multiple of the page size, with the protection `prot`. The mapping goes
+    /// at `fixed` if given, replacing whatever is mapped there, or wherever there's room
+    /// otherwise. Returns its start, or `None` if there's no room.
+    pub fn map(&mut self, fixed: Option<VirtualAddress>, size: usize, prot: usize)
+               -> Option<VirtualAddress> {
+        let start = match fixed {
+            Some(start) => {
+                self.unmap(start, size);
+                start
+            }
+            None => match self.find_free(size) {
+                Some(start) => start,
+                None => return None,
+            },
+        };
+        self.mappings.insert(start, Mapping { end: start + size, prot: prot });
+        Some(start)
+    }
+
+    /// Unmap whatever is mapped in `start..start + size`, which must be page aligned
+    pub fn unmap(&mut self, start: VirtualAddress, size: usize) {
+        let end = start + size;
+        self.split_at(start);
+        self.split_at(end);
+        let starts: Vec<_> = self.mappings.range(start..end).map(|(&start, _)| start).collect();
+        for start in starts {
+            self.mappings.remove(&start);
+        }
+        self.release(start, end);
+    }
+
+    /// Change the protection of `start..start + size`, which must be page aligned, to `prot`.
+    /// Returns `false` if not all of it is mapped.
+    pub fn protect(&mut self, start: VirtualAddress, size: usize, prot: usize) -> bool {
+        let end = start + size;
+        let mut address = start;
+        while address < end {
+            match self.mapping_containing(address) {
+                Some((_, mapping)) => address = mapping.end,
+                None => return false,
+            }
+        }
+
+        self.split_at(start);
+        self.split_at(end);
+        for (_, mapping) in self.mappings.range_mut(start..end) {
+            mapping.prot = prot;
+        }
+        with_mem_ctrl(|m| m.set_user_flags(start, end, entry_flags(prot)));
+        true
+    }
+
+    /// Back the page containing `address` with a zeroed frame, if the heap or a mapping covers it
+    /// and allows `access`, made up of `PROT_*` bits. The address space must be active. Returns
+    /// `false` if the access isn't allowed, or there's no memory left.
+    pub fn populate(&mut self, address: VirtualAddress, access: usize) -> bool {
+        let prot = if address >= USER_HEAP_OFFSET && address < page_align_up(self.brk) {
+            PROT_READ | PROT_WRITE
+        } else {
+            match self.mapping_containing(address) {
+                Some((_, mapping)) => mapping.prot,
+                None => return false,
+            }
+        };
+        if !allows(prot, access) {
+            return false;
+        }
+
+        let page = address / PAGE_SIZE * PAGE_SIZE;
+        let mapped = with_mem_ctrl(|m| {
+            if m.translate_address(page).is_some() {
+                // another thread got here first
+                return Some(false);
+            }
+            m.map_zeroed_page(page, entry_flags(prot)).map(|_| true)
+        });
+        match mapped {
+            Some(true) => {
+                self.resident_pages += 1;
+                true
+            }
+            Some(false) => true,
+            None => false,
+        }
+    }
+
+    /// A free range of `size` bytes in the mapping area
+    fn find_free(&self, size: usize) -> Option<VirtualAddress> {
+        let mut start = USER_MMAP_OFFSET;
+        // thread stacks are mapped past the mapping area
+        for (&next, mapping) in self.mappings.range(..MMAP_END) {
+            if next - start >= size {
+                break;
+            }
+            start = mapping.end;
+        }
+        if MMAP_END - start >= size { Some(start) } else { None }
+    }
+
+    fn mapping_containing(&self, address: VirtualAddress) -> Option<(VirtualAddress, Mapping)> {
+        self.mappings.range(..address + 1).next_back()
+            .and_then(|(&start, &mapping)| if address < mapping.end {
+                Some((start, mapping))
+            } else {
+                None
+            })
+    }
+
+    /// Split the mapping containing `address`, if any, so one starts there
+    fn split_at(&mut self, address: VirtualAddress) {
+        if let Some((start, mapping)) = self.mapping_containing(address) {
+            if start < address {
+                self.mappings.get_mut(&start).unwrap().end = address;
+                self.mappings.insert(address, mapping);
+            }
+        }
+    }
+
+    /// Free the memory backing the page aligned range `start..end`
+    fn release(&mut self, start: VirtualAddress, end: VirtualAddress) {
+        if start < end {
+            self.resident_pages -= with_mem_ctrl(|m| m.unmap_user(start, end));
+        }
+    }
+}
+
+/// Whether memory with the protection `prot` may be accessed with `access`. Anything that's
+/// mapped at all can be read.
+fn allows(prot: usize, access: usize) -> bool {
+    let checked = PROT_WRITE | PROT_EXEC;
+    prot != PROT_NONE && prot & access & checked == access & checked
+}
+
+fn entry_flags(prot: usize) -> EntryFlags {
+    // pages nobody may touch stay mapped, but only for the kernel, so their contents survive
+    let mut flags = NO_EXECUTE;
+    if prot != PROT_NONE {
+        flags = flags | USER_ACCESSIBLE;
+    }
+    if prot & PROT_WRITE != 0 {
+        flags = flags | WRITABLE;
+    }
+    if prot & PROT_EXEC != 0 {
+        flags = flags - NO_EXECUTE;
+    }
+    flags
+}
+
+pub fn page_align_up(address: VirtualAddress) -> VirtualAddress {
+    (address + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE
+}
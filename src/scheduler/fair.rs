@@ -0,0 +1,72 @@
+use collections::{BTreeMap, BTreeSet};
+use core::cmp;
+
+use context::ThreadId;
+use super::policy::{Class, Policy, DEFAULT_WEIGHT};
+
+/// Virtual runtime a thread of the default weight gains per tick
+const VRUNTIME_PER_TICK: u64 = 1000;
+
+/// Weighted fair-share scheduling. Each thread accumulates virtual runtime at a rate inversely
+/// proportional to its weight, and the thread that's furthest behind runs next.
+pub struct Fair {
+    /// Runnable threads, ordered by virtual runtime
+    queue: BTreeSet<(u64, ThreadId)>,
+    vruntime: BTreeMap<ThreadId, u64>,
+    /// Virtual runtime of the thread picked last. Never decreases.
+    min_vruntime: u64,
+}
+
+impl Fair {
+    pub fn new() -> Fair {
+        Fair {
+            queue: BTreeSet::new(),
+            vruntime: BTreeMap::new(),
+            min_vruntime: 0,
+        }
+    }
+}
+
+impl Policy for Fair {
+    fn enqueue(&mut self, id: ThreadId, _class: Class) {
+        // new threads start level with the others, and a thread that was blocked for a while
+        // doesn't get to monopolize the CPU catching up
+        let min_vruntime = self.min_vruntime;
+        let vruntime = self.vruntime.entry(id).or_insert(min_vruntime);
+        *vruntime = cmp::max(*vruntime, min_vruntime);
+        self.queue.insert((*vruntime, id));
+    }
+
+    fn pick_next(&mut self) -> Option<ThreadId> {
+        let first = match self.queue.iter().next() {
+            Some(&first) => first,
+            None => return None,
+        };
+        self.queue.remove(&first);
+
+        let (vruntime, id) = first;
+        self.min_vruntime = cmp::max(self.min_vruntime, vruntime);
+        Some(id)
+    }
+
+    fn remove(&mut self, id: ThreadId) -> bool {
+        match self.vruntime.remove(&id) {
+            Some(vruntime) => self.queue.remove(&(vruntime, id)),
+            None => false,
+        }
+    }
+
+    fn tick(&mut self, id: ThreadId, class: Class) {
+        let weight = match class {
+            Class::Fair(weight) => cmp::max(weight, 1),
+            _ => panic!("{:?} charged to the fair-share policy", class),
+        };
+        if let Some(vruntime) = self.vruntime.get_mut(&id) {
+            *vruntime += VRUNTIME_PER_TICK * DEFAULT_WEIGHT as u64 / weight as u64;
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
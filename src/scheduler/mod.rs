@@ -0,0 +1,359 @@
+// Preemptive scheduling on top of `context`. The timer interrupt charges a tick to the running
+// thread and, once its time slice is used up, the thread is switched out on the way out of the
+// interrupt. Each thread belongs to a class whose `Policy` decides the order within the class;
+// classes are served in order of rank. The boot processor's timer also drives the clock that
+// `ticks` counts, and wakes threads that asked for it with `wake_at`.
+
+use alloc::boxed::Box;
+use collections::{BTreeMap, Vec};
+use core::mem;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use irq_lock::IrqMutex;
+use spin::Once;
+use x86_64::instructions::interrupts;
+
+use context::{self, Status, ThreadId};
+use interrupts::without_interrupts;
+use syscall::{Error, Result};
+use syscall::error::EINVAL;
+
+pub use context::ThreadStats;
+pub use self::policy::{Class, Policy, DEFAULT_WEIGHT, MAX_REALTIME_PRIORITY};
+
+mod fair;
+mod policy;
+mod realtime;
+
+/// Rate of the timer interrupt that drives the scheduler
+pub const TICKS_PER_SECOND: u32 = 1000;
+/// Time slice length, in ticks, until `set_time_slice` is called
+pub const DEFAULT_TIME_SLICE: usize = 10;
+
+struct Scheduler {
+    /// One policy per class, indexed by `Class::rank`
+    policies: Vec<Box<Policy>>,
+    /// Runs when nothing else is runnable. Never queued.
+    idle: Option<ThreadId>,
+    /// Ticks left until the current thread is preempted
+    slice_remaining: usize,
+}
+
+impl Scheduler {
+    fn class(&self, id: ThreadId) -> Class {
+        context::with_thread(id, |thread| thread.class).unwrap_or_default()
+    }
+
+    /// Queue the runnable thread `id`, and preempt the current thread if `id` outranks it
+    fn enqueue(&mut self, id: ThreadId) {
+        let class = self.class(id);
+        self.policies[class.rank()].enqueue(id, class);
+        context::with_thread(id, |thread| thread.ready_since = ticks());
+
+        let current = context::current_id();
+        if self.idle == Some(current) || class.preempts(&self.class(current)) {
+            NEED_RESCHED.get().store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn pick_next(&mut self) -> Option<ThreadId> {
+        let next = self.policies.iter_mut().filter_map(|policy| policy.pick_next()).next();
+        if let Some(next) = next {
+            let now = ticks();
+            context::with_thread(next, |thread| {
+                thread.stats.wait_time += (now - thread.ready_since) as u64;
+            });
+        }
+        next
+    }
+
+    /// Whether any thread that may take over from a thread of `class` is waiting
+    fn has_contender(&self, class: Class) -> bool {
+        self.policies[..class.rank() + 1].iter().any(|policy| !policy.is_empty())
+    }
+
+    fn has_runnable(&self) -> bool {
+        self.policies.iter().any(|policy| !policy.is_empty())
+    }
+}
+
+percpu! {
+    /// This CPU's run queues, created on first use
+    static SCHEDULER: Once<IrqMutex<Scheduler>> = Once::new();
+}
+
+percpu! {
+    /// Set when the current thread should be switched out on the way out of an interrupt
+    static NEED_RESCHED: AtomicBool = AtomicBool::new(false);
+}
+
+static TICKS: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    /// Threads to wake by the tick they're to be woken at, see `wake_at`
+    static ref TIMERS: IrqMutex<BTreeMap<usize, Vec<ThreadId>>> =
+        IrqMutex::named(lock_class!("scheduler::TIMERS"), BTreeMap::new());
+}
+static TIME_SLICE: AtomicUsize = AtomicUsize::new(DEFAULT_TIME_SLICE);
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+fn scheduler() -> &'static IrqMutex<Scheduler> {
+    SCHEDULER.get().call_once(|| IrqMutex::named(lock_class!("scheduler::SCHEDULER"), Scheduler {
+        policies: vec![
+            Box::new(realtime::RealTime::new()) as Box<Policy>,
+            Box::new(fair::Fair::new()) as Box<Policy>,
+        ],
+        idle: None,
+        slice_remaining: DEFAULT_TIME_SLICE,
+    }))
+}
+
+/// Start the boot processor's idle thread and start preempting threads. `context::init` must have
+/// been called.
+pub fn init() {
+    let id = context::create(idle).expect("failed to create the idle thread");
+    scheduler().lock().idle = Some(id);
+
+    STARTED.store(true, Ordering::SeqCst);
+    ::interrupts::start_timer(TICKS_PER_SECOND);
+}
+
+/// Turn the current thread of an application processor into its idle thread, and start
+/// scheduling on it. `context::init_cpu` must have been called.
+pub fn run_idle() -> ! {
+    scheduler().lock().idle = Some(context::current_id());
+    ::interrupts::start_timer(TICKS_PER_SECOND);
+
+    idle();
+    unreachable!();
+}
+
+/// Ticks of the boot processor's timer since the scheduler started
+pub fn ticks() -> usize {
+    TICKS.load(Ordering::SeqCst)
+}
+
+/// Set how many ticks a thread may run before it's preempted. Takes effect from the next switch.
+pub fn set_time_slice(ticks: usize) {
+    assert!(ticks > 0, "time slice must be at least one tick");
+    TIME_SLICE.store(ticks, Ordering::SeqCst);
+}
+
+/// Make the new thread `id` runnable
+pub fn enqueue(id: ThreadId) {
+    let mut scheduler = scheduler().lock();
+    context::set_status(id, Status::Runnable);
+    scheduler.enqueue(id);
+}
+
+/// Move `id` to another scheduling class. If it's queued on another CPU, the new class takes effect
+/// the next time it's queued. Fails with `EINVAL` if `class` is out of range, like a real-time
+/// priority above `MAX_REALTIME_PRIORITY`.
+pub fn set_class(id: ThreadId, class: Class) -> Result<()> {
+    if !class.is_valid() {
+        return Err(Error::new(EINVAL));
+    }
+
+    let mut scheduler = scheduler().lock();
+    let old = scheduler.class(id);
+    context::with_thread(id, |thread| thread.class = class);
+
+    // a thread queued on another CPU stays there, under its old policy, until it's picked
+    if scheduler.policies[old.rank()].remove(id) {
+        scheduler.enqueue(id);
+    } else if id == context::current_id() && scheduler.has_contender(class) {
+        // a running thread that was demoted may have to give way
+        NEED_RESCHED.get().store(true, Ordering::SeqCst);
+    }
+    Ok(())
+}
+
+pub fn class(id: ThreadId) -> Class {
+    scheduler().lock().class(id)
+}
+
+/// Scheduling statistics of `id`, or `None` if there's no such thread
+pub fn stats(id: ThreadId) -> Option<ThreadStats> {
+    context::stats(id)
+}
+
+/// Called from the timer interrupt
+pub fn tick() {
+    if !STARTED.load(Ordering::SeqCst) {
+        return;
+    }
+    if ::percpu::cpu_id() == 0 {
+        let now = TICKS.fetch_add(1, Ordering::SeqCst) + 1;
+        wake_expired(now);
+    }
+
+    let mut scheduler = scheduler().lock();
+    let current = context::current_id();
+    context::with_thread(current, |thread| thread.stats.runtime += 1);
+    if scheduler.idle == Some(current) {
+        return;
+    }
+
+    let class = scheduler.class(current);
+    scheduler.policies[class.rank()].tick(current, class);
+
+    if scheduler.slice_remaining > 0 {
+        scheduler.slice_remaining -= 1;
+    }
+    if scheduler.slice_remaining == 0 && scheduler.has_contender(class) {
+        NEED_RESCHED.get().store(true, Ordering::SeqCst);
+    }
+}
+
+/// Switch threads if the scheduler asked for it. Called on the way out of the outermost interrupt
+/// handler.
+pub fn preempt() {
+    if NEED_RESCHED.get().load(Ordering::SeqCst) {
+        yield_now();
+    }
+}
+
+/// Give up the CPU to the next thread the policies pick, which may be the current thread again
+pub fn yield_now() {
+    schedule(Status::Runnable);
+}
+
+/// Stop running the current thread until someone calls `wake` with it. If it was woken since it
+/// last blocked, returns right away instead, so a wakeup that comes in between checking the
+/// condition being waited for and blocking isn't lost. Callers have to expect spurious returns.
+pub fn block() {
+    schedule(Status::Blocked);
+}
+
+/// Make the blocked thread `id` runnable again, or make its next `block` return immediately if it
+/// isn't blocked. Safe to call from interrupt handlers.
+pub fn wake(id: ThreadId) {
+    let mut scheduler = scheduler().lock();
+    // another CPU may be waking the same thread, and only one of them may queue it
+    let won = context::with_thread(id, |thread| match thread.status {
+        Status::Blocked => {
+            thread.status = Status::Runnable;
+            true
+        }
+        Status::Runnable | Status::Running => {
+            thread.wakeup_pending = true;
+            false
+        }
+        _ => false,
+    });
+    if won == Some(true) {
+        scheduler.enqueue(id);
+    }
+}
+
+/// Wake `id` as soon as `ticks` reaches `tick`, as `wake` would. Safe to call from interrupt
+/// handlers.
+pub fn wake_at(id: ThreadId, tick: usize) {
+    TIMERS.lock().entry(tick).or_insert_with(Vec::new).push(id);
+}
+
+/// Take back a `wake_at(id, tick)`. Does nothing if it has fired already, so the thread may still
+/// have a wakeup pending.
+pub fn cancel_wake_at(id: ThreadId, tick: usize) {
+    let mut timers = TIMERS.lock();
+    let empty = match timers.get_mut(&tick) {
+        Some(ids) => {
+            ids.retain(|&other| other != id);
+            ids.is_empty()
+        }
+        None => false,
+    };
+    if empty {
+        timers.remove(&tick);
+    }
+}
+
+/// Wake the threads whose `wake_at` tick has come
+fn wake_expired(now: usize) {
+    let expired = {
+        let mut timers = TIMERS.lock();
+        match timers.keys().next() {
+            Some(&tick) if tick <= now => (),
+            _ => return,
+        }
+        let later = timers.split_off(&(now + 1));
+        mem::replace(&mut *timers, later)
+    };
+    for (_, ids) in expired {
+        for id in ids {
+            wake(id);
+        }
+    }
+}
+
+/// Stop the current thread. Its stack is released once another thread has switched away from it.
+pub fn exit() -> ! {
+    schedule(Status::Exited);
+    unreachable!("exited thread was switched back to");
+}
+
+/// The idle thread, which sleeps whenever nothing else is runnable
+fn idle() {
+    loop {
+        // check with interrupts off, so a thread woken by an interrupt can't slip in between the
+        // check and the `hlt`
+        interrupts::disable();
+        if !scheduler().lock().has_runnable() {
+            unsafe { asm!("sti; hlt" : : : : "intel", "volatile"); }
+        } else {
+            unsafe { interrupts::enable(); }
+            yield_now();
+        }
+    }
+}
+
+/// Leave the current thread in `status` and switch to the next runnable thread, or the idle thread
+/// if there is none
+fn schedule(status: Status) {
+    without_interrupts(|| {
+        let next = {
+            let mut scheduler = scheduler().lock();
+            let current = context::current_id();
+
+            // a `wake` on another CPU either sees the thread blocked, or has left a wakeup pending
+            // by the time it's checked, as both happen under the `THREADS` lock
+            let woken = context::with_thread(current, |thread| {
+                if status == Status::Blocked && thread.wakeup_pending {
+                    thread.wakeup_pending = false;
+                    return true;
+                }
+                thread.status = status;
+                false
+            });
+            if woken == Some(true) {
+                return;
+            }
+
+            if Some(current) != scheduler.idle {
+                match status {
+                    Status::Runnable => scheduler.enqueue(current),
+                    Status::Exited => {
+                        let class = scheduler.class(current);
+                        scheduler.policies[class.rank()].remove(current);
+                    }
+                    _ => (),
+                }
+            }
+
+            let next = scheduler.pick_next()
+                .or(scheduler.idle)
+                .expect("no thread to run");
+            context::set_status(next, Status::Running);
+
+            scheduler.slice_remaining = TIME_SLICE.load(Ordering::SeqCst);
+            NEED_RESCHED.get().store(false, Ordering::SeqCst);
+
+            if next == current {
+                return;
+            }
+            next
+        };
+
+        unsafe { context::switch_to(next); }
+    })
+}
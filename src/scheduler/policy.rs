@@ -0,0 +1,67 @@
+use context::ThreadId;
+
+/// Weight of a fair-share thread unless told otherwise
+pub const DEFAULT_WEIGHT: u32 = 1024;
+/// Highest priority of a real-time thread
+pub const MAX_REALTIME_PRIORITY: u8 = 99;
+
+/// Which policy schedules a thread, and its parameters under that policy
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Class {
+    /// Fixed priority, 0 to `MAX_REALTIME_PRIORITY`. Runs before any fair-share thread and before
+    /// real-time threads of lower priority. Threads of equal priority take turns each time slice.
+    RealTime(u8),
+    /// Shares the CPU with the other fair-share threads, in proportion to its weight
+    Fair(u32),
+}
+
+impl Class {
+    /// Whether the class's parameters are in range
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            Class::RealTime(priority) => priority <= MAX_REALTIME_PRIORITY,
+            Class::Fair(_) => true,
+        }
+    }
+
+    /// Index of the class's policy in the scheduler. Lower indices run first.
+    pub fn rank(&self) -> usize {
+        match *self {
+            Class::RealTime(_) => 0,
+            Class::Fair(_) => 1,
+        }
+    }
+
+    /// Whether a thread of this class should take the CPU from a running thread of `other`
+    pub fn preempts(&self, other: &Class) -> bool {
+        match (*self, *other) {
+            (Class::RealTime(a), Class::RealTime(b)) => a > b,
+            _ => self.rank() < other.rank(),
+        }
+    }
+}
+
+impl Default for Class {
+    fn default() -> Class {
+        Class::Fair(DEFAULT_WEIGHT)
+    }
+}
+
+/// A scheduling policy keeps track of the runnable threads of one class. The running thread is
+/// never queued: it's taken out by `pick_next` and put back with `enqueue` when it stops running.
+pub trait Policy: Send {
+    /// Add the runnable thread `id`
+    fn enqueue(&mut self, id: ThreadId, class: Class);
+
+    /// Take the thread that should run next out of the queue
+    fn pick_next(&mut self) -> Option<ThreadId>;
+
+    /// Forget about `id`, because it exited or moved to another class. Returns whether it was
+    /// queued.
+    fn remove(&mut self, id: ThreadId) -> bool;
+
+    /// Charge a tick to the running thread `id`
+    fn tick(&mut self, id: ThreadId, class: Class);
+
+    fn is_empty(&self) -> bool;
+}
@@ -0,0 +1,59 @@
+use collections::{BTreeMap, Vec, VecDeque};
+
+use context::ThreadId;
+use super::policy::{Class, Policy};
+
+/// Fixed-priority scheduling: always the highest priority thread first, round robin among equals
+pub struct RealTime {
+    queues: BTreeMap<u8, VecDeque<ThreadId>>,
+}
+
+impl RealTime {
+    pub fn new() -> RealTime {
+        RealTime { queues: BTreeMap::new() }
+    }
+}
+
+impl Policy for RealTime {
+    fn enqueue(&mut self, id: ThreadId, class: Class) {
+        let priority = match class {
+            Class::RealTime(priority) => priority,
+            _ => panic!("{:?} queued with the real-time policy", class),
+        };
+        self.queues.entry(priority).or_insert_with(VecDeque::new).push_back(id);
+    }
+
+    fn pick_next(&mut self) -> Option<ThreadId> {
+        let (priority, next) = match self.queues.iter_mut().next_back() {
+            Some((&priority, queue)) => (priority, queue.pop_front()),
+            None => return None,
+        };
+        if self.queues[&priority].is_empty() {
+            self.queues.remove(&priority);
+        }
+        next
+    }
+
+    fn remove(&mut self, id: ThreadId) -> bool {
+        let mut queued = false;
+        for queue in self.queues.values_mut() {
+            let len = queue.len();
+            queue.retain(|&other| other != id);
+            queued |= queue.len() != len;
+        }
+        let empty: Vec<u8> = self.queues.iter()
+            .filter(|&(_, queue)| queue.is_empty())
+            .map(|(&priority, _)| priority)
+            .collect();
+        for priority in empty {
+            self.queues.remove(&priority);
+        }
+        queued
+    }
+
+    fn tick(&mut self, _id: ThreadId, _class: Class) {}
+
+    fn is_empty(&self) -> bool {
+        self.queues.is_empty()
+    }
+}
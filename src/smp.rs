@@ -0,0 +1,191 @@
+// Bringing up the application processors. The boot processor starts each one with the
+// INIT-SIPI-SIPI sequence; it comes up in real mode in the trampoline (trampoline.asm), which
+// takes it to long mode and into `ap_main`.
+
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use x86_64::registers::control_regs;
+
+use acpi::Madt;
+use memory::{self, pcid, WRITABLE};
+
+/// Most CPUs the kernel will run on, limited by the per-CPU bookkeeping in lockdep
+pub const MAX_CPUS: usize = 16;
+
+/// Physical address the trampoline is copied to. Must be page aligned and below 1 MiB, the
+/// startup IPI only carries the page number.
+const TRAMPOLINE: usize = 0x8000;
+
+// offsets of the data at the start of the trampoline
+const AP_READY: usize = 8;
+const AP_CPU_ID: usize = 16;
+const AP_PAGE_TABLE: usize = 24;
+const AP_STACK_TOP: usize = 32;
+const AP_ENTRY: usize = 40;
+
+/// Size of the stack each application processor starts on, and keeps as its idle thread's stack
+const AP_STACK_PAGES: usize = 4;
+
+extern "C" {
+    static trampoline_start: u8;
+    static trampoline_end: u8;
+}
+
+/// Bit mask of the CPUs running the kernel, by CPU ID, the boot processor included. CPU IDs of
+/// processors that didn't come up are never reused, so there may be gaps.
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+/// Bit mask of the application processors that have initialized and are waiting at the barrier
+static CHECKED_IN: AtomicUsize = AtomicUsize::new(0);
+/// Lets the application processors past the barrier, into the scheduler
+static RELEASED: AtomicBool = AtomicBool::new(false);
+
+pub fn cpu_count() -> usize {
+    ONLINE.load(Ordering::SeqCst).count_ones() as usize
+}
+
+/// Whether the CPU with `cpu_id` is running the kernel
+pub fn is_online(cpu_id: usize) -> bool {
+    ONLINE.load(Ordering::SeqCst) & 1 << cpu_id != 0
+}
+
+/// Busy wait for `ms` timer ticks, at least `ms` milliseconds
+fn delay_ms(ms: usize) {
+    let start = ::scheduler::ticks();
+    while ::scheduler::ticks() - start < ms {
+        unsafe { asm!("pause" : : : "memory" : "intel", "volatile"); }
+    }
+}
+
+unsafe fn write_trampoline(offset: usize, value: u64) {
+    ptr::write_volatile((TRAMPOLINE + offset) as *mut u64, value);
+}
+
+unsafe fn read_trampoline(offset: usize) -> u64 {
+    ptr::read_volatile((TRAMPOLINE + offset) as *const u64)
+}
+
+/// Start every processor listed in the MADT and wait until they've all checked in. Needs the
+/// scheduler's timer to be running, for the delays the startup sequence requires.
+pub fn init() {
+    let apics = match Madt::get() {
+        Some(madt) => madt.local_apics(),
+        None => {
+            println!("No MADT, running on the boot processor only");
+            return;
+        }
+    };
+
+    let trampoline_size = unsafe {
+        &trampoline_end as *const u8 as usize - &trampoline_start as *const u8 as usize
+    };
+    memory::with_mem_ctrl(|m| m.identity_map(TRAMPOLINE, WRITABLE))
+        .expect("could not map the trampoline");
+    unsafe {
+        ptr::copy_nonoverlapping(&trampoline_start as *const u8, TRAMPOLINE as *mut u8,
+                                 trampoline_size);
+    }
+
+    let bsp_apic_id = ::interrupts::local_apic().id();
+    let mut next_id = 1;
+    let mut started = 0;
+    for apic in apics.iter().filter(|apic| apic.usable && apic.apic_id != bsp_apic_id) {
+        if next_id == MAX_CPUS {
+            println!("Ignoring CPUs past the first {}", MAX_CPUS);
+            break;
+        }
+
+        // a processor that timed out may still have picked up its ID, so it's never handed out
+        // again
+        let cpu_id = next_id;
+        next_id += 1;
+        if start_ap(cpu_id, apic.apic_id) {
+            started += 1;
+        } else {
+            println!("CPU with APIC ID {} did not start", apic.apic_id);
+        }
+    }
+
+    // the barrier: wait for every started processor to finish initializing
+    let start = ::scheduler::ticks();
+    while (CHECKED_IN.load(Ordering::SeqCst).count_ones() as usize) < started &&
+          ::scheduler::ticks() - start < 1000 {
+        unsafe { asm!("pause" : : : "memory" : "intel", "volatile"); }
+    }
+    // processors checking in later stay out, see `ap_main`
+    ONLINE.fetch_or(CHECKED_IN.load(Ordering::SeqCst), Ordering::SeqCst);
+    RELEASED.store(true, Ordering::SeqCst);
+
+    println!("{} CPUs online", cpu_count());
+}
+
+/// Start the processor with `apic_id` as CPU `cpu_id`. Returns false if it didn't come up, in
+/// which case it's parked with an INIT, and its stack and per-CPU block are left to it.
+fn start_ap(cpu_id: usize, apic_id: u8) -> bool {
+    let stack = match memory::with_mem_ctrl(|m| m.alloc_stack(AP_STACK_PAGES)) {
+        Ok(stack) => stack,
+        Err(_) => return false,
+    };
+    memory::with_mem_ctrl(|m| ::percpu::setup(cpu_id, m));
+
+    unsafe {
+        write_trampoline(AP_READY, 0);
+        write_trampoline(AP_CPU_ID, cpu_id as u64);
+        // PCIDs are only enabled once the processor reaches `ap_main`
+        write_trampoline(AP_PAGE_TABLE, control_regs::cr3().0 & !(pcid::PCID_MASK as u64));
+        write_trampoline(AP_STACK_TOP, stack.top() as u64);
+        write_trampoline(AP_ENTRY, ap_main as usize as u64);
+    }
+
+    let local_apic = ::interrupts::local_apic();
+    let page = (TRAMPOLINE >> 12) as u8;
+    local_apic.send_init(apic_id);
+    delay_ms(10);
+    local_apic.send_startup(apic_id, page);
+    delay_ms(1);
+    // the second startup IPI is only needed if the first one got lost
+    if unsafe { read_trampoline(AP_READY) } == 0 {
+        local_apic.send_startup(apic_id, page);
+    }
+
+    let start = ::scheduler::ticks();
+    while unsafe { read_trampoline(AP_READY) } == 0 {
+        if ::scheduler::ticks() - start > 100 {
+            // back to waiting for a startup IPI, before the trampoline is rewritten for the next
+            // processor. It may have got as far as checking in.
+            local_apic.send_init(apic_id);
+            CHECKED_IN.fetch_and(!(1 << cpu_id), Ordering::SeqCst);
+            return false;
+        }
+        unsafe { asm!("pause" : : : "memory" : "intel", "volatile"); }
+    }
+    true
+}
+
+/// Where application processors enter Rust, on the stack the boot processor gave them
+extern "C" fn ap_main(cpu_id: usize) -> ! {
+    // before anything takes a lock, which looks at per-CPU state
+    ::percpu::load(cpu_id);
+    memory::with_mem_ctrl(|m| {
+        m.init_cpu();
+        ::interrupts::init_cpu(m);
+    });
+    ::context::init_cpu();
+
+    println!("CPU {} (APIC ID {}) up", cpu_id, ::interrupts::local_apic().id());
+
+    CHECKED_IN.fetch_or(1 << cpu_id, Ordering::SeqCst);
+    while !RELEASED.load(Ordering::SeqCst) {
+        unsafe { asm!("pause" : : : "memory" : "intel", "volatile"); }
+    }
+    // too late for the barrier: the other CPUs don't count on this one, e.g. for TLB shootdowns
+    if !is_online(cpu_id) {
+        println!("CPU {} checked in late, halting it", cpu_id);
+        loop {
+            unsafe { asm!("cli; hlt" : : : : "intel", "volatile"); }
+        }
+    }
+
+    unsafe { ::x86_64::instructions::interrupts::enable(); }
+    ::scheduler::run_idle();
+}
@@ -0,0 +1,38 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::WaitQueue;
+
+/// An event that threads can wait for. Stays signalled once completed, until `reset`.
+pub struct Completion {
+    done: AtomicBool,
+    waiters: WaitQueue,
+}
+
+impl Completion {
+    pub fn new() -> Completion {
+        Completion {
+            done: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Sleep until the event is signalled
+    pub fn wait(&self) {
+        self.waiters.wait_until(|| self.done.load(Ordering::SeqCst));
+    }
+
+    /// Signal the event and wake all waiters. Safe to call from interrupt handlers.
+    pub fn complete(&self) {
+        self.done.store(true, Ordering::SeqCst);
+        self.waiters.wake_all();
+    }
+
+    /// Clear the event so it can be waited for again
+    pub fn reset(&self) {
+        self.done.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.done.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,42 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{MutexGuard, WaitQueue};
+
+/// A condition variable, for waiting on a condition over data protected by a `Mutex`
+pub struct Condvar {
+    /// Bumped by every notification, so a waiter can tell whether one happened since it unlocked
+    /// the mutex
+    generation: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub fn new() -> Condvar {
+        Condvar {
+            generation: AtomicUsize::new(0),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Unlock the mutex, sleep until notified and lock it again. Wakeups may be spurious, so the
+    /// condition has to be checked again in a loop.
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex();
+        // read before unlocking, so a notification right after the unlock is seen
+        let generation = self.generation.load(Ordering::SeqCst);
+        drop(guard);
+
+        self.waiters.wait_until(|| self.generation.load(Ordering::SeqCst) != generation);
+        mutex.lock()
+    }
+
+    pub fn notify_one(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.waiters.wake_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.waiters.wake_all();
+    }
+}
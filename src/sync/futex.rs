@@ -0,0 +1,123 @@
+// Futexes: wait queues keyed by the physical address of a word of memory, so threads sharing the
+// memory, in any process, can sleep until another one wakes them. The kernel never changes the
+// word; it only checks that it still holds what the waiter expects before going to sleep, with the
+// futex table locked, so a waker that changes the word and then calls `wake` can't be missed. A
+// futex only exists while threads wait on it.
+
+use alloc::arc::Arc;
+use collections::BTreeMap;
+use core::cmp;
+
+use irq_lock::IrqMutex;
+
+use context;
+use memory::PhysicalAddress;
+use super::WaitQueue;
+
+/// How `wait` ended
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Wait {
+    /// Woken by `wake`
+    Woken,
+    /// The word didn't hold the expected value, so the thread didn't sleep
+    Mismatch,
+    /// The deadline passed first
+    TimedOut,
+    /// The thread's process has exited, or has signals to act on
+    Interrupted,
+}
+
+struct Futex {
+    queue: Arc<WaitQueue>,
+    /// Threads in `wait`, asleep or about to be
+    waiters: usize,
+    /// Wakeups handed out by `wake` that no waiter has taken yet
+    wakeups: usize,
+}
+
+lazy_static! {
+    static ref FUTEXES: IrqMutex<BTreeMap<PhysicalAddress, Futex>> =
+        IrqMutex::named(lock_class!("futex::FUTEXES"), BTreeMap::new());
+}
+
+/// Sleep on the futex `key` until `wake` picks the current thread, unless `matches`, which checks
+/// the word with the futex table locked, returns false. Gives up once `scheduler::ticks` reaches
+/// `deadline`, if given.
+pub fn wait<F>(key: PhysicalAddress, matches: F, deadline: Option<usize>) -> Wait
+    where F: FnOnce() -> bool
+{
+    let queue = {
+        let mut futexes = FUTEXES.lock();
+        if !matches() {
+            return Wait::Mismatch;
+        }
+        let futex = futexes.entry(key).or_insert_with(|| Futex {
+            queue: Arc::new(WaitQueue::new()),
+            waiters: 0,
+            wakeups: 0,
+        });
+        futex.waiters += 1;
+        futex.queue.clone()
+    };
+
+    let mut woken = false;
+    let finished = {
+        let condition = || {
+            let mut futexes = FUTEXES.lock();
+            let futex = futexes.get_mut(&key).expect("futex with waiters missing");
+            if futex.wakeups > 0 {
+                futex.wakeups -= 1;
+                woken = true;
+                return true;
+            }
+            context::interrupted()
+        };
+        match deadline {
+            Some(deadline) => queue.wait_until_deadline(condition, deadline),
+            None => {
+                queue.wait_until(condition);
+                true
+            }
+        }
+    };
+
+    let mut futexes = FUTEXES.lock();
+    let unused = {
+        let futex = futexes.get_mut(&key).expect("futex with waiters missing");
+        futex.waiters -= 1;
+        // a wakeup meant for a waiter that timed out instead goes to the next one, if any
+        futex.wakeups = cmp::min(futex.wakeups, futex.waiters);
+        futex.waiters == 0
+    };
+    if unused {
+        futexes.remove(&key);
+    }
+
+    if woken {
+        Wait::Woken
+    } else if finished {
+        Wait::Interrupted
+    } else {
+        Wait::TimedOut
+    }
+}
+
+/// Wake up to `count` threads waiting on the futex `key`. Returns how many there were.
+pub fn wake(key: PhysicalAddress, count: usize) -> usize {
+    let (queue, count) = {
+        let mut futexes = FUTEXES.lock();
+        let futex = match futexes.get_mut(&key) {
+            Some(futex) => futex,
+            None => return 0,
+        };
+        let count = cmp::min(count, futex.waiters - futex.wakeups);
+        futex.wakeups += count;
+        (futex.queue.clone(), count)
+    };
+
+    // a waiter that isn't queued right now finds its wakeup before it goes back to sleep
+    for _ in 0..count {
+        queue.wake_one();
+    }
+    count
+}
@@ -0,0 +1,17 @@
+// Blocking synchronization. Unlike the spinlocks in `irq_lock`, these put the waiting thread to
+// sleep, so they may only be used from thread context, never from interrupt handlers. The
+// waking side (`WaitQueue::wake_one`, `Completion::complete`, ...) is fine to use anywhere.
+
+pub use self::completion::Completion;
+pub use self::condvar::Condvar;
+pub use self::mutex::{Mutex, MutexGuard};
+pub use self::semaphore::Semaphore;
+pub use self::wait_queue::WaitQueue;
+
+pub mod futex;
+
+mod completion;
+mod condvar;
+mod mutex;
+mod semaphore;
+mod wait_queue;
@@ -0,0 +1,45 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::WaitQueue;
+
+/// A counting semaphore. `acquire` sleeps until the count is positive and decrements it.
+pub struct Semaphore {
+    count: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub fn new(count: usize) -> Semaphore {
+        Semaphore {
+            count: AtomicUsize::new(count),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    pub fn acquire(&self) {
+        self.waiters.wait_until(|| self.try_acquire());
+    }
+
+    /// Decrement the count if it's positive, without sleeping
+    pub fn try_acquire(&self) -> bool {
+        let mut count = self.count.load(Ordering::SeqCst);
+        while count > 0 {
+            let old = self.count.compare_and_swap(count, count - 1, Ordering::SeqCst);
+            if old == count {
+                return true;
+            }
+            count = old;
+        }
+        false
+    }
+
+    /// Increment the count and wake a waiter. Safe to call from interrupt handlers.
+    pub fn release(&self) {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        self.waiters.wake_one();
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,91 @@
+use collections::VecDeque;
+use core::mem;
+
+use irq_lock::IrqMutex;
+
+use context::{self, ThreadId};
+use interrupts;
+use scheduler;
+
+/// Threads sleeping until some condition becomes true
+pub struct WaitQueue {
+    waiters: IrqMutex<VecDeque<ThreadId>>,
+}
+
+impl WaitQueue {
+    pub fn new() -> WaitQueue {
+        WaitQueue { waiters: IrqMutex::new(VecDeque::new()) }
+    }
+
+    /// Sleep until `condition` returns true. The condition is checked with the queue locked, so a
+    /// waker that makes it true before calling `wake_one` or `wake_all` is never missed.
+    pub fn wait_until<F>(&self, mut condition: F) where F: FnMut() -> bool {
+        debug_assert!(!interrupts::in_interrupt(), "sleeping in an interrupt handler");
+
+        let current = context::current_id();
+        loop {
+            {
+                let mut waiters = self.waiters.lock();
+                // still queued if this is a spurious return from `block`
+                waiters.retain(|&id| id != current);
+                if condition() {
+                    return;
+                }
+                waiters.push_back(current);
+            }
+
+            scheduler::block();
+        }
+    }
+
+    /// Like `wait_until`, but gives up once `scheduler::ticks` reaches `deadline`. Returns whether
+    /// the condition became true.
+    pub fn wait_until_deadline<F>(&self, mut condition: F, deadline: usize) -> bool
+        where F: FnMut() -> bool
+    {
+        debug_assert!(!interrupts::in_interrupt(), "sleeping in an interrupt handler");
+
+        let current = context::current_id();
+        scheduler::wake_at(current, deadline);
+        let mut done = false;
+        loop {
+            {
+                let mut waiters = self.waiters.lock();
+                waiters.retain(|&id| id != current);
+                if condition() {
+                    done = true;
+                    break;
+                }
+                if scheduler::ticks() >= deadline {
+                    break;
+                }
+                waiters.push_back(current);
+            }
+
+            scheduler::block();
+        }
+        scheduler::cancel_wake_at(current, deadline);
+        done
+    }
+
+    /// Wake the thread that has been waiting longest. Returns false if there was none.
+    pub fn wake_one(&self) -> bool {
+        match self.waiters.lock().pop_front() {
+            Some(id) => {
+                scheduler::wake(id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wake every waiting thread, returning how many there were. Threads that start waiting
+    /// meanwhile, woken ones going back to sleep included, are left waiting.
+    pub fn wake_all(&self) -> usize {
+        let waiters = mem::replace(&mut *self.waiters.lock(), VecDeque::new());
+        for &id in waiters.iter() {
+            scheduler::wake(id);
+        }
+        waiters.len()
+    }
+}
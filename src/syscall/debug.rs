@@ -0,0 +1,25 @@
+use core::{cmp, str};
+
+use ::memory::PAGE_SIZE;
+
+use super::error::{Error, Result, EINVAL};
+use super::validate::copy_from_user;
+
+/// Print the UTF-8 text of `size` bytes at `address` to the kernel console. Returns the number of
+/// bytes written, which may be less than `size`.
+pub fn debug_write(address: usize, size: usize) -> Result<usize> {
+    // a page at a time, the caller writes the rest again
+    let len = cmp::min(size, PAGE_SIZE);
+    let mut bytes = vec![0; len];
+    copy_from_user(address, &mut bytes)?;
+    let text = match str::from_utf8(&bytes) {
+        Ok(text) => text,
+        // a character cut in two by the limit goes with the next write
+        Err(error) if len < size && error.valid_up_to() > 0 => unsafe {
+            str::from_utf8_unchecked(&bytes[..error.valid_up_to()])
+        },
+        Err(_) => return Err(Error::new(EINVAL)),
+    };
+    print!("{}", text);
+    Ok(text.len())
+}
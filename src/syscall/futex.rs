@@ -0,0 +1,52 @@
+use core::mem;
+
+use ::memory::{with_mem_ctrl, EntryFlags, PhysicalAddress};
+use ::scheduler::{self, TICKS_PER_SECOND};
+use ::sync::futex::{self, Wait};
+
+use super::error::{Error, Result, EAGAIN, EFAULT, EINTR, EINVAL, ETIMEDOUT};
+use super::validate::{read_user, user_accessible};
+
+/// `timeout` argument of `futex_wait` that waits for as long as it takes
+pub const FUTEX_FOREVER: usize = !0;
+
+/// Sleep until `futex_wake` is called on the 32-bit word at `address`, if it still holds
+/// `expected`, or until `timeout` milliseconds have passed. Returns `EAGAIN` if the word holds
+/// something else.
+pub fn futex_wait(address: usize, expected: usize, timeout: usize) -> Result<usize> {
+    let key = futex_key(address)?;
+    let deadline = if timeout == FUTEX_FOREVER {
+        None
+    } else {
+        let ticks = timeout.saturating_mul(TICKS_PER_SECOND as usize) / 1000;
+        Some(scheduler::ticks().saturating_add(ticks))
+    };
+
+    // unmapped after `futex_key` looked it up, the word can't hold anything
+    let matches = || read_user::<u32>(address).ok() == Some(expected as u32);
+    match futex::wait(key, matches, deadline) {
+        Wait::Woken => Ok(0),
+        Wait::Mismatch => Err(Error::new(EAGAIN)),
+        Wait::TimedOut => Err(Error::new(ETIMEDOUT)),
+        Wait::Interrupted => Err(Error::new(EINTR)),
+    }
+}
+
+/// Wake up to `count` threads sleeping on the 32-bit word at `address`. Returns how many were
+/// woken.
+pub fn futex_wake(address: usize, count: usize) -> Result<usize> {
+    let key = futex_key(address)?;
+    Ok(futex::wake(key, count))
+}
+
+/// The physical address of the word at `address`, which is the same in every address space that
+/// shares it
+fn futex_key(address: usize) -> Result<PhysicalAddress> {
+    if address % mem::align_of::<u32>() != 0 {
+        return Err(Error::new(EINVAL));
+    }
+    if !user_accessible(address, mem::size_of::<u32>(), EntryFlags::empty()) {
+        return Err(Error::new(EFAULT));
+    }
+    with_mem_ctrl(|m| m.translate_address(address)).ok_or(Error::new(EFAULT))
+}
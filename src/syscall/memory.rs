@@ -1,12 +1,16 @@
-use ::memory::{with_mem_ctrl, EntryFlags, PhysicalAddress, VirtualAddress};
+use ::memory::{with_mem_ctrl, EntryFlags, PhysicalAddress, VirtualAddress, PAGE_SIZE,
+               USER_MMAP_OFFSET};
+use ::process::{self, vm};
 
-pub fn alloc_vm(size: usize, flags: EntryFlags) -> Option<VirtualAddress> {
+use super::error::{Error, Result, EINVAL, ENOMEM};
+
+pub fn alloc_vm(size: usize, flags: EntryFlags) -> Result<VirtualAddress> {
     with_mem_ctrl(|m| {
         m.alloc_vm(size, flags)
     })
 }
 
-pub fn map_pm(address: PhysicalAddress, size: usize, flags: EntryFlags) -> Option<VirtualAddress> {
+pub fn map_pm(address: PhysicalAddress, size: usize, flags: EntryFlags) -> Result<VirtualAddress> {
     with_mem_ctrl(|m| {
         m.map_pm(address, size, flags)
     })
@@ -21,3 +25,85 @@ pub fn free_vm(start_address: VirtualAddress) {
 pub fn translate_addr(addr: VirtualAddress) -> Option<PhysicalAddress> {
     with_mem_ctrl(|m| { m.translate_address(addr) })
 }
+
+pub const MAP_SHARED: usize = 0x01;
+pub const MAP_PRIVATE: usize = 0x02;
+pub const MAP_FIXED: usize = 0x10;
+pub const MAP_ANONYMOUS: usize = 0x20;
+
+/// Move the end of the caller's heap to `address`, or leave it if `address` is 0 or out of
+/// bounds. Returns where the heap ends.
+pub fn brk(address: VirtualAddress) -> VirtualAddress {
+    process::with_vm(|vm| {
+        if address != 0 {
+            vm.set_brk(address);
+        }
+        vm.brk()
+    })
+}
+
+/// Map `size` bytes of anonymous memory with the protection `prot`. Only `MAP_ANONYMOUS` mappings
+/// are supported, so `fd` and `offset` are ignored. Returns the start of the mapping.
+pub fn mmap(address: VirtualAddress, size: usize, prot: usize, flags: usize, _fd: usize,
+            _offset: usize) -> Result<VirtualAddress> {
+    let size = checked_size(size).ok_or(Error::new(EINVAL))?;
+    if prot & !(vm::PROT_READ | vm::PROT_WRITE | vm::PROT_EXEC) != 0 ||
+       flags & !(MAP_SHARED | MAP_PRIVATE | MAP_FIXED | MAP_ANONYMOUS) != 0 ||
+       flags & MAP_ANONYMOUS == 0 {
+        return Err(Error::new(EINVAL));
+    }
+    // without fork, shared anonymous memory is no different from private
+    if flags & (MAP_SHARED | MAP_PRIVATE) == 0 ||
+       flags & (MAP_SHARED | MAP_PRIVATE) == MAP_SHARED | MAP_PRIVATE {
+        return Err(Error::new(EINVAL));
+    }
+
+    let fixed = if flags & MAP_FIXED != 0 {
+        check_range(address, size)?;
+        Some(address)
+    } else {
+        None
+    };
+    process::with_vm(|vm| vm.map(fixed, size, prot)).ok_or(Error::new(ENOMEM))
+}
+
+/// Unmap whatever is mapped in `address..address + size`
+pub fn munmap(address: VirtualAddress, size: usize) -> Result<usize> {
+    let size = checked_size(size).ok_or(Error::new(EINVAL))?;
+    check_range(address, size)?;
+    process::with_vm(|vm| vm.unmap(address, size));
+    Ok(0)
+}
+
+/// Change the protection of `address..address + size`, all of which must be mapped
+pub fn mprotect(address: VirtualAddress, size: usize, prot: usize) -> Result<usize> {
+    let size = checked_size(size).ok_or(Error::new(EINVAL))?;
+    if prot & !(vm::PROT_READ | vm::PROT_WRITE | vm::PROT_EXEC) != 0 {
+        return Err(Error::new(EINVAL));
+    }
+    check_range(address, size)?;
+    if process::with_vm(|vm| vm.protect(address, size, prot)) {
+        Ok(0)
+    } else {
+        Err(Error::new(ENOMEM))
+    }
+}
+
+/// `size` rounded up to whole pages, unless it's 0 or too large
+fn checked_size(size: usize) -> Option<usize> {
+    match size.checked_add(PAGE_SIZE - 1) {
+        Some(rounded) if size != 0 => Some(rounded / PAGE_SIZE * PAGE_SIZE),
+        _ => None,
+    }
+}
+
+/// Check that `address..address + size` is a page aligned range of the mapping area
+fn check_range(address: VirtualAddress, size: usize) -> Result<()> {
+    if address % PAGE_SIZE != 0 {
+        return Err(Error::new(EINVAL));
+    }
+    match address.checked_add(size) {
+        Some(end) if address >= USER_MMAP_OFFSET && end <= vm::MMAP_END => Ok(()),
+        _ => Err(Error::new(ENOMEM)),
+    }
+}
@@ -1,7 +1,67 @@
+pub use self::debug::debug_write;
 pub use self::error::{Error, Result};
-pub use self::memory::{alloc_vm, free_vm, map_pm};
+pub use self::futex::{futex_wait, futex_wake};
+pub use self::memory::{alloc_vm, brk, free_vm, map_pm, mmap, mprotect, munmap, translate_addr};
+pub use self::process::{clone, exec, exit, exit_thread, getpid, join, wait};
+pub use self::signal::{kill, sigaction, sigprocmask};
+pub use self::validate::{copy_from_user, copy_to_user, read_user, user_accessible, write_user};
+
+use self::error::{EINVAL, EFAULT, ENOSYS, EPERM};
+use self::number::*;
+
+use ::memory::EntryFlags;
+use ::process::{self, KERNEL_PID};
 
 pub mod error;
 pub mod io;
+pub mod number;
 
+mod debug;
+mod futex;
 mod memory;
+mod process;
+mod signal;
+mod validate;
+
+/// Run the syscall `number` with up to six arguments. Returns the value to hand back to the
+/// caller in rax.
+pub fn syscall(number: usize, a: usize, b: usize, c: usize,
+               d: usize, e: usize, f: usize) -> usize {
+    let result = match number {
+        // these hand out kernel memory and physical addresses, with flags of the caller's choosing
+        SYS_ALLOC_VM | SYS_MAP_PM | SYS_FREE_VM | SYS_TRANSLATE_ADDR
+            if process::current() != KERNEL_PID => Err(Error::new(EPERM)),
+        SYS_ALLOC_VM => entry_flags(b).and_then(|flags| alloc_vm(a, flags)),
+        SYS_MAP_PM => entry_flags(c).and_then(|flags| map_pm(a, b, flags)),
+        SYS_FREE_VM => {
+            free_vm(a);
+            Ok(0)
+        }
+        SYS_TRANSLATE_ADDR => translate_addr(a).ok_or(Error::new(EFAULT)),
+        SYS_EXEC => exec(a, b, c, d),
+        SYS_EXIT => exit(a as i32),
+        SYS_WAIT => wait(a, b),
+        SYS_GETPID => Ok(getpid()),
+        SYS_DEBUG_WRITE => debug_write(a, b),
+        SYS_BRK => Ok(brk(a)),
+        SYS_MMAP => mmap(a, b, c, d, e, f),
+        SYS_MUNMAP => munmap(a, b),
+        SYS_MPROTECT => mprotect(a, b, c),
+        SYS_CLONE => clone(a, b),
+        SYS_EXIT_THREAD => exit_thread(a),
+        SYS_JOIN => join(a, b),
+        SYS_FUTEX_WAIT => futex_wait(a, b, c),
+        SYS_FUTEX_WAKE => futex_wake(a, b),
+        SYS_KILL => kill(a, b),
+        SYS_SIGACTION => sigaction(a, b, c, d),
+        SYS_SIGPROCMASK => sigprocmask(a, b),
+        // `SYS_SIGRETURN` rewrites the caller's registers, so `syscall_dispatch` handles it
+        _ => Err(Error::new(ENOSYS)),
+    };
+
+    Error::mux(result)
+}
+
+fn entry_flags(bits: usize) -> Result<EntryFlags> {
+    EntryFlags::from_bits(bits as u64).ok_or(Error::new(EINVAL))
+}
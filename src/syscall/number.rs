@@ -0,0 +1,25 @@
+// Syscall numbers, passed in rax. Arguments go in rdi, rsi, rdx, r10, r8 and r9, and the result
+// comes back in rax, see `Error::mux`.
+
+pub const SYS_ALLOC_VM: usize = 1;
+pub const SYS_MAP_PM: usize = 2;
+pub const SYS_FREE_VM: usize = 3;
+pub const SYS_TRANSLATE_ADDR: usize = 4;
+pub const SYS_EXEC: usize = 5;
+pub const SYS_EXIT: usize = 6;
+pub const SYS_WAIT: usize = 7;
+pub const SYS_GETPID: usize = 8;
+pub const SYS_DEBUG_WRITE: usize = 9;
+pub const SYS_BRK: usize = 10;
+pub const SYS_MMAP: usize = 11;
+pub const SYS_MUNMAP: usize = 12;
+pub const SYS_MPROTECT: usize = 13;
+pub const SYS_CLONE: usize = 14;
+pub const SYS_EXIT_THREAD: usize = 15;
+pub const SYS_JOIN: usize = 16;
+pub const SYS_FUTEX_WAIT: usize = 17;
+pub const SYS_FUTEX_WAKE: usize = 18;
+pub const SYS_KILL: usize = 19;
+pub const SYS_SIGACTION: usize = 20;
+pub const SYS_SIGPROCMASK: usize = 21;
+pub const SYS_SIGRETURN: usize = 22;
@@ -1,23 +1,142 @@
-use ::memory;
-
-pub fn exec() {
-    // TODO create new context
-    with_mem_ctrl(|m| {
-        let mut new_table = m.new_page_table().expect("Out of frames");
-
-        let kernel_memory_p4_entry = m.active_table.p4()[0].pointed_frame().expect("kernel table not mapped");
-        let kernel_memory_flags = m.active_table.p4()[0].flags();
-
-        m.with_inactive_table(&mut new_table, |mapper, allocator| {
-            // Copy kernel mapping
-            //mapper.p4_mut()[510].set(kernel_memory_p4_entry, flags);
-            mapper.p4_mut()[0].set(kernel_memory_p4_entry, flags);
-            // TODO map program image
-            // TODO create and map stack
-            let stack = m.alloc_stack(2048).unwrap(); // 2048 pages = 8MB
-
-            // TODO create and map heap
-            // TODO switch to user mode
-        });
-    });
+use collections::Vec;
+use core::{cmp, mem};
+
+use ::context::{self, ThreadId};
+use ::memory::{PAGE_SIZE, USER_OFFSET, WRITABLE};
+use ::process::{self, ProcessId};
+use ::process::args::ARG_MAX;
+
+use super::error::{Error, Result, E2BIG, ECHILD, EDEADLK, EFAULT, EINTR, EINVAL, ENOEXEC,
+                   ESRCH};
+use super::validate::{copy_from_user, read_user, user_accessible, write_user};
+
+/// `pid` argument of `wait` that waits for any child
+pub const WAIT_ANY: usize = !0;
+
+/// Start a child process running the ELF executable of `size` bytes at `address`, with the
+/// null-terminated arrays of strings `argv` and `envp` as its arguments and environment. Either
+/// array may be 0 if empty. Returns the child's PID, or `ENOEXEC` if the executable is larger than
+/// `process::EXEC_MAX`.
+pub fn exec(address: usize, size: usize, argv: usize, envp: usize) -> Result<usize> {
+    if size > process::EXEC_MAX {
+        return Err(Error::new(ENOEXEC));
+    }
+    // the caller's memory may change under us, so work on copies
+    let mut data = vec![0; size];
+    copy_from_user(address, &mut data)?;
+    let mut budget = ARG_MAX;
+    let args = copy_strings(argv, &mut budget)?;
+    let env = copy_strings(envp, &mut budget)?;
+
+    let args: Vec<&[u8]> = args.iter().map(|arg| &arg[..]).collect();
+    let env: Vec<&[u8]> = env.iter().map(|var| &var[..]).collect();
+    let pid = process::exec(&data, &args, &env)?;
+    Ok(pid.0)
+}
+
+/// Copy the strings of the null-terminated array at `address`, taking what they need out of
+/// `budget`
+fn copy_strings(address: usize, budget: &mut usize) -> Result<Vec<Vec<u8>>> {
+    let mut strings = Vec::new();
+    if address == 0 {
+        return Ok(strings);
+    }
+
+    let mut entry = address;
+    loop {
+        let pointer: usize = read_user(entry)?;
+        if pointer == 0 {
+            return Ok(strings);
+        }
+        strings.push(copy_string(pointer, budget)?);
+        entry += mem::size_of::<usize>();
+    }
+}
+
+/// Copy the null-terminated string at `address`, taking what it needs, its null included, out of
+/// `budget`
+fn copy_string(mut address: usize, budget: &mut usize) -> Result<Vec<u8>> {
+    let mut string = Vec::new();
+    let mut chunk = [0; 64];
+    loop {
+        // copy a chunk at a time, up to the end of the page, the string may end before the next
+        let len = cmp::min(chunk.len(), PAGE_SIZE - address % PAGE_SIZE);
+        copy_from_user(address, &mut chunk[..len])?;
+        for &byte in chunk[..len].iter() {
+            if string.len() + 1 > *budget {
+                return Err(Error::new(E2BIG));
+            }
+            if byte == 0 {
+                *budget -= string.len() + 1;
+                return Ok(string);
+            }
+            string.push(byte);
+        }
+        address += len;
+    }
+}
+
+pub fn exit(status: i32) -> ! {
+    process::exit(status)
+}
+
+/// Wait for the child `pid`, or any child if it's `WAIT_ANY`, to exit. Stores its exit status at
+/// `status_address` unless that's 0, and returns its PID. Returns `EINTR` if a signal comes in
+/// first, and `EFAULT`, with the child reaped all the same, if the status can't be stored.
+pub fn wait(pid: usize, status_address: usize) -> Result<usize> {
+    if status_address != 0 && !user_accessible(status_address, mem::size_of::<i32>(), WRITABLE) {
+        return Err(Error::new(EFAULT));
+    }
+
+    let pid = if pid == WAIT_ANY { None } else { Some(ProcessId(pid)) };
+    let (child, status) = process::wait(pid).ok_or_else(|| interrupted_or(ECHILD))?;
+    // the caller's memory may have changed while it was blocked, so this is checked again
+    if status_address != 0 {
+        write_user(status_address, &status)?;
+    }
+    Ok(child.0)
+}
+
+pub fn getpid() -> usize {
+    process::current().0
+}
+
+/// Start a thread in the calling process at `ip`, with `arg` as its first argument. It gets a stack
+/// and TLS block of its own and shares everything else. Returns its thread ID.
+pub fn clone(ip: usize, arg: usize) -> Result<usize> {
+    // `iretq` to a non-canonical address would fault in the kernel
+    if ip < USER_OFFSET || ip >> 47 != 0 {
+        return Err(Error::new(EINVAL));
+    }
+    let id = process::clone(ip, arg)?;
+    Ok(id.0)
+}
+
+pub fn exit_thread(value: usize) -> ! {
+    process::exit_thread(value)
+}
+
+/// Wait for the thread `id` of the calling process to end with `exit_thread`. Stores the value it
+/// left at `value_address` unless that's 0. Returns `EINTR` if a signal comes in first, and
+/// `EFAULT`, with the thread joined all the same, if the value can't be stored.
+pub fn join(id: usize, value_address: usize) -> Result<usize> {
+    if value_address != 0 && !user_accessible(value_address, mem::size_of::<usize>(), WRITABLE) {
+        return Err(Error::new(EFAULT));
+    }
+    let id = ThreadId(id);
+    if id == context::current_id() {
+        return Err(Error::new(EDEADLK));
+    }
+
+    let value = process::join(id).ok_or_else(|| interrupted_or(ESRCH))?;
+    // the caller's memory may have changed while it was blocked, so this is checked again
+    if value_address != 0 {
+        write_user(value_address, &value)?;
+    }
+    Ok(0)
+}
+
+/// The error of a blocking call that gave up: `EINTR` if it was for a signal, `errno` otherwise
+fn interrupted_or(errno: u32) -> Error {
+    Error::new(if context::interrupted() { EINTR } else { errno })
 }
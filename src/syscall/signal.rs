@@ -0,0 +1,61 @@
+use ::memory::USER_OFFSET;
+use ::process::ProcessId;
+use ::process::signal::{self, Action, SIG_BLOCK, SIG_DFL, SIG_IGN, SIG_SETMASK, SIG_UNBLOCK};
+
+use super::error::{Error, Result, EINVAL};
+
+/// Send `signal` to the process `pid`. A signal of 0 only checks that the process exists.
+pub fn kill(pid: usize, signal: usize) -> Result<usize> {
+    if signal != 0 && !signal::is_valid(signal) {
+        return Err(Error::new(EINVAL));
+    }
+    signal::send(ProcessId(pid), signal)?;
+    Ok(0)
+}
+
+/// Set what the calling process does with `signal`: `SIG_DFL`, `SIG_IGN`, or call the function
+/// at `handler` with `mask` blocked while it runs. The handler returns into `restorer`, which has
+/// to make the `sigreturn` syscall. Returns the old handler.
+pub fn sigaction(signal: usize, handler: usize, mask: usize, restorer: usize) -> Result<usize> {
+    if !signal::is_valid(signal) || signal::is_unblockable(signal) {
+        return Err(Error::new(EINVAL));
+    }
+    let action = match handler {
+        SIG_DFL => Action::Default,
+        SIG_IGN => Action::Ignore,
+        handler => {
+            if !is_user_address(handler) || !is_user_address(restorer) {
+                return Err(Error::new(EINVAL));
+            }
+            Action::Handler { handler: handler, restorer: restorer, mask: mask as u32 }
+        }
+    };
+
+    Ok(match signal::set_action(signal, action) {
+        Action::Default => SIG_DFL,
+        Action::Ignore => SIG_IGN,
+        Action::Handler { handler, .. } => handler,
+    })
+}
+
+/// Change the blocked signals of the calling process: add `set` to them with `SIG_BLOCK`, remove
+/// it with `SIG_UNBLOCK`, or replace them with `SIG_SETMASK`. `SIGKILL` and `SIGSTOP` can't be
+/// blocked. Returns the old set.
+pub fn sigprocmask(how: usize, set: usize) -> Result<usize> {
+    if how != SIG_BLOCK && how != SIG_UNBLOCK && how != SIG_SETMASK {
+        return Err(Error::new(EINVAL));
+    }
+    let set = set as u32;
+    let old = signal::update_blocked(|old| match how {
+        SIG_BLOCK => old | set,
+        SIG_UNBLOCK => old & !set,
+        _ => set,
+    });
+    Ok(old as usize)
+}
+
+/// Whether user code can be at `address`, leaving aside whether anything is mapped there
+fn is_user_address(address: usize) -> bool {
+    // `iretq` to a non-canonical address would fault in the kernel
+    address >= USER_OFFSET && address >> 47 == 0
+}
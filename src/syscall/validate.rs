@@ -0,0 +1,119 @@
+use core::{cmp, mem, ptr, slice};
+
+use ::memory::{with_mem_ctrl, EntryFlags, PAGE_SIZE, WRITABLE};
+use ::process::{self, vm};
+
+use super::error::{Error, Result, EFAULT};
+
+/// Whether the caller may access every byte of `address..address + size` with `flags`, e.g.
+/// `WRITABLE` to write to them. Lazily mapped pages are backed first, so the kernel doesn't fault
+/// on them. Another thread of the caller may unmap them right after, so this is only good for
+/// failing early; the access itself goes through `copy_from_user` and friends.
+pub fn user_accessible(address: usize, size: usize, flags: EntryFlags) -> bool {
+    if size == 0 {
+        return true;
+    }
+    let end = match user_end(address, size) {
+        Some(end) => end,
+        None => return false,
+    };
+
+    let mut page = address / PAGE_SIZE * PAGE_SIZE;
+    while page < end {
+        if !back(page, flags) {
+            return false;
+        }
+        page += PAGE_SIZE;
+    }
+    with_mem_ctrl(|m| m.is_user_accessible(address, size, flags))
+}
+
+/// Copy the bytes at `address` into `buffer`. Fails with `EFAULT` if the caller may not read all
+/// of them.
+pub fn copy_from_user(address: usize, buffer: &mut [u8]) -> Result<()> {
+    let size = buffer.len();
+    with_user_pages(address, size, EntryFlags::empty(), |offset, user, len| unsafe {
+        ptr::copy_nonoverlapping(user as *const u8, buffer[offset..].as_mut_ptr(), len);
+    })
+}
+
+/// Copy `bytes` to `address`. Fails with `EFAULT` if the caller may not write all of them, having
+/// written some of them maybe.
+pub fn copy_to_user(address: usize, bytes: &[u8]) -> Result<()> {
+    with_user_pages(address, bytes.len(), WRITABLE, |offset, user, len| unsafe {
+        ptr::copy_nonoverlapping(bytes[offset..].as_ptr(), user as *mut u8, len);
+    })
+}
+
+/// Read a `T` at `address`, which needn't be aligned. `T` must be plain data, valid whatever its
+/// bytes are, like integers and structs of them. Fails with `EFAULT` like `copy_from_user`.
+pub fn read_user<T>(address: usize) -> Result<T> {
+    let mut value: T = unsafe { mem::uninitialized() };
+    {
+        let buffer = unsafe {
+            slice::from_raw_parts_mut(&mut value as *mut T as *mut u8, mem::size_of::<T>())
+        };
+        copy_from_user(address, buffer)?;
+    }
+    Ok(value)
+}
+
+/// Write `value` to `address`, which needn't be aligned. Fails with `EFAULT` like `copy_to_user`.
+pub fn write_user<T>(address: usize, value: &T) -> Result<()> {
+    let bytes = unsafe {
+        slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>())
+    };
+    copy_to_user(address, bytes)
+}
+
+/// Run `copy(offset, user address, length)` on the part of `address..address + size` on each page
+/// in turn. That the caller may access the part with `flags` is checked under the memory
+/// controller's lock, held until `copy` returns, so another thread of the caller can't unmap or
+/// protect it in between and make the kernel fault.
+fn with_user_pages<F>(address: usize, size: usize, flags: EntryFlags, mut copy: F) -> Result<()>
+    where F: FnMut(usize, usize, usize)
+{
+    if size == 0 {
+        return Ok(());
+    }
+    if user_end(address, size).is_none() {
+        return Err(Error::new(EFAULT));
+    }
+
+    let mut offset = 0;
+    while offset < size {
+        let user = address + offset;
+        let len = cmp::min(size - offset, PAGE_SIZE - user % PAGE_SIZE);
+        if !back(user, flags) {
+            return Err(Error::new(EFAULT));
+        }
+        let copied = with_mem_ctrl(|m| {
+            if !m.is_user_accessible(user, len, flags) {
+                return false;
+            }
+            copy(offset, user, len);
+            true
+        });
+        if !copied {
+            return Err(Error::new(EFAULT));
+        }
+        offset += len;
+    }
+    Ok(())
+}
+
+/// The end of `address..address + size` if it's all in user space, the lower half
+fn user_end(address: usize, size: usize) -> Option<usize> {
+    match address.checked_add(size) {
+        Some(end) if end <= 0x0000_8000_0000_0000 => Some(end),
+        _ => None,
+    }
+}
+
+/// Back the page containing `address` if it's lazily mapped, for an access with `flags`. Returns
+/// false if there's nothing the caller may access there.
+fn back(address: usize, flags: EntryFlags) -> bool {
+    let access = if flags.contains(WRITABLE) { vm::PROT_WRITE } else { vm::PROT_READ };
+    let mapped = with_mem_ctrl(|m| m.translate_address(address).is_some());
+    mapped || process::handle_page_fault(address / PAGE_SIZE * PAGE_SIZE, access)
+}
@@ -1,9 +1,9 @@
 use core::ptr::Unique;
 use core::fmt;
 
-use spin::Mutex;
+use irq_lock::IrqMutex;
 
-pub static WRITER: Mutex<Writer> = Mutex::new(Writer {
+pub static WRITER: IrqMutex<Writer> = IrqMutex::named(lock_class!("vga_buffer::WRITER"), Writer {
     column_position: 0,
     color_code: ColorCode::new(Color::White, Color::Black),
     buffer: unsafe { Unique::new_unchecked(0xb8000 as *mut _) },
@@ -118,6 +118,12 @@ pub fn clear_screen() {
     }
 }
 
+/// Report a lockdep problem. Can't go through `WRITER`, whose lock might be the one involved.
+#[cfg(feature = "lockdep")]
+pub fn print_lockdep(fmt: fmt::Arguments) {
+    unsafe { print_error(fmt) };
+}
+
 pub unsafe fn print_error(fmt: fmt::Arguments) {
     use core::fmt::Write;
 
@@ -0,0 +1,74 @@
+// Deferred work: interrupt handlers queue up anything expensive here, and it runs later on a
+// worker thread with interrupts enabled.
+
+use alloc::boxed::Box;
+use collections::VecDeque;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use irq_lock::IrqMutex;
+
+use context::{self, ThreadId};
+use interrupts::without_interrupts;
+use scheduler;
+
+pub trait Work: Send {
+    fn run(self: Box<Self>);
+}
+
+impl<F> Work for F where F: FnOnce() + Send {
+    fn run(self: Box<F>) {
+        (*self)()
+    }
+}
+
+lazy_static! {
+    static ref QUEUE: IrqMutex<VecDeque<Box<Work>>> =
+        IrqMutex::named(lock_class!("workqueue::QUEUE"), VecDeque::new());
+}
+
+/// Thread id of the worker, 0 until `init` has started it
+static WORKER: AtomicUsize = AtomicUsize::new(0);
+
+/// Start the worker thread
+pub fn init() {
+    let id = context::spawn(worker).expect("failed to spawn the workqueue worker");
+    WORKER.store(id.0, Ordering::SeqCst);
+}
+
+/// Queue `work` to run later. Safe to call from interrupt handlers.
+pub fn schedule<W>(work: W) where W: Work + 'static {
+    QUEUE.lock().push_back(Box::new(work));
+
+    let worker = WORKER.load(Ordering::SeqCst);
+    if worker != 0 {
+        scheduler::wake(ThreadId(worker));
+    }
+}
+
+pub fn is_empty() -> bool {
+    QUEUE.lock().is_empty()
+}
+
+/// Run queued work until the queue is empty
+pub fn run_pending() {
+    loop {
+        let work = match QUEUE.lock().pop_front() {
+            Some(work) => work,
+            None => break,
+        };
+        work.run();
+    }
+}
+
+fn worker() {
+    loop {
+        run_pending();
+
+        // check with interrupts off, so work queued by an interrupt can't slip in between the
+        // check and blocking
+        without_interrupts(|| {
+            if is_empty() {
+                scheduler::block();
+            }
+        });
+    }
+}
@@ -0,0 +1,91 @@
+global start
+
+; syscall numbers, see src/syscall/number.rs
+%define SYS_EXIT 6
+%define SYS_GETPID 8
+%define SYS_DEBUG_WRITE 9
+%define SYS_CLONE 14
+%define SYS_EXIT_THREAD 15
+%define SYS_JOIN 16
+%define SYS_KILL 19
+%define SYS_SIGACTION 20
+%define SYS_SIGRETURN 22
+
+%define SIGUSR1 10
+
+section .text
+bits 64
+; The first user process. Greets the kernel console, has a second thread and a signal handler do
+; the same and exits, which shows that entering user mode, making syscalls from it, starting
+; threads and handling signals work.
+start:
+    mov rax, SYS_DEBUG_WRITE
+    lea rdi, [rel greeting]
+    mov rsi, greeting_len
+    syscall
+
+    mov rax, SYS_CLONE
+    lea rdi, [rel thread]
+    lea rsi, [rel thread_greeting]
+    syscall
+    mov rdi, rax
+    mov rax, SYS_JOIN
+    xor rsi, rsi
+    syscall
+
+    mov rax, SYS_SIGACTION
+    mov rdi, SIGUSR1
+    lea rsi, [rel handler]
+    xor rdx, rdx
+    lea r10, [rel restorer]
+    syscall
+    mov rax, SYS_GETPID
+    syscall
+    mov rdi, rax
+    mov rax, SYS_KILL
+    mov rsi, SIGUSR1
+    syscall
+
+    ; exit with the PID as the status, so it shows up in the kernel's logs
+    mov rax, SYS_GETPID
+    syscall
+    mov rdi, rax
+    mov rax, SYS_EXIT
+    syscall
+
+.unreachable:
+    jmp .unreachable
+
+; Entered with the greeting to write in rdi
+thread:
+    mov rax, SYS_DEBUG_WRITE
+    mov rsi, thread_greeting_len
+    syscall
+
+    mov rax, SYS_EXIT_THREAD
+    xor rdi, rdi
+    syscall
+
+.unreachable:
+    jmp .unreachable
+
+; Signal handler, entered with the signal number in rdi
+handler:
+    mov rax, SYS_DEBUG_WRITE
+    lea rdi, [rel handler_greeting]
+    mov rsi, handler_greeting_len
+    syscall
+    ret
+
+; Where signal handlers return to
+restorer:
+    mov rax, SYS_SIGRETURN
+    syscall
+
+section .rodata
+greeting: db "Hello from user mode!", 10
+greeting_len equ $ - greeting
+thread_greeting: db "Hello from a second thread!", 10
+thread_greeting_len equ $ - thread_greeting
+handler_greeting: db "Hello from a signal handler!", 10
+handler_greeting_len equ $ - handler_greeting